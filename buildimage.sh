@@ -6,7 +6,10 @@ trap 'echo "\"${last_command}\" command filed with exit code $?."' EXIT
 make clean
 make all
 
-rm -rf ./SysControl.hdd
+rm -rf ./SysControl.hdd ./initrd.tar
+
+# Pack the initrd directory, read by the kernel as the "initrd" boot module (see limine.cfg).
+tar --format=ustar -cf initrd.tar -C initrd .
 
 # Create an empty zeroed out 64MiB image file.
 dd if=/dev/zero bs=1M count=0 seek=64 of=SysControl.hdd
@@ -24,8 +27,13 @@ echfs-utils -g -p0 SysControl.hdd quick-format 512
 # Copy config file and kernel file(s) over into the image.
 echfs-utils -g -p0 SysControl.hdd import limine.cfg limine.cfg
 echfs-utils -g -p0 SysControl.hdd import SysControl.elf SysControl.elf
+echfs-utils -g -p0 SysControl.hdd import initrd.tar initrd.tar
+# Uncomment along with the MODULE_PATH lines of limine.cfg to use another console font.
+# echfs-utils -g -p0 SysControl.hdd import font.psf font.psf
 
 # Finally, install Limine onto the image.
 limine-install SysControl.hdd
 
-qemu-system-x86_64 ./SysControl.hdd -no-shutdown -no-reboot -m 500M
\ No newline at end of file
+# Everything the kernel prints, from its first line, is written to the terminal through debugcon.
+# Add "-cpu qemu64,+la57" to boot with five-level paging.
+qemu-system-x86_64 ./SysControl.hdd -no-shutdown -no-reboot -m 500M -debugcon stdio
\ No newline at end of file
@@ -0,0 +1,33 @@
+# Build the kernel with the in-kernel test runner and run it in QEMU.
+# Results are printed over the serial port, the exit code is 0 if every test passed.
+set -e
+
+make kerneltest
+
+rm -rf ./SysControl-test.hdd ./initrd.tar
+tar --format=ustar -cf initrd.tar -C initrd .
+
+# Same image as buildimage.sh, with the test kernel in place of the normal one.
+dd if=/dev/zero bs=1M count=0 seek=64 of=SysControl-test.hdd
+parted -s SysControl-test.hdd mklabel gpt
+parted -s SysControl-test.hdd mkpart primary 2048s 100%
+echfs-utils -g -p0 SysControl-test.hdd quick-format 512
+echfs-utils -g -p0 SysControl-test.hdd import limine.cfg limine.cfg
+echfs-utils -g -p0 SysControl-test.hdd import SysControl-test.elf SysControl.elf
+echfs-utils -g -p0 SysControl-test.hdd import initrd.tar initrd.tar
+limine-install SysControl-test.hdd
+
+# The kernel writes its exit code to the isa-debug-exit device, QEMU then exits with
+# (code << 1) | 1. Success (0x10) gives 33, failure (0x11) gives 35.
+set +e
+qemu-system-x86_64 ./SysControl-test.hdd -no-reboot -m 500M \
+    -device isa-debug-exit,iobase=0xf4,iosize=0x04 \
+    -serial stdio -display none
+status=$?
+set -e
+
+if [ $status -eq 33 ]; then
+    exit 0
+fi
+echo "Kernel tests failed (QEMU exit code $status)."
+exit 1
@@ -0,0 +1,182 @@
+// Fixed ACPI Description Table ("FACP" signature) : where the fixed hardware registers are, which
+// legacy devices the machine has, and how to reset it. Tables shorter than the ACPI 1.0 layout are
+// rejected, fields added by later revisions read as absent when the table is too short.
+
+use crate::acpi::{read_u16, read_u32, read_u64, read_u8};
+
+// Size of the revision 1 table, which every later one extends
+pub const REVISION_1_LENGTH: usize = 116;
+
+// Generic address structure address spaces
+pub const ADDRESS_SPACE_MEMORY: u8 = 0;
+pub const ADDRESS_SPACE_IO: u8 = 1;
+
+// IA-PC boot architecture flags
+const BOOT_ARCH_LEGACY_DEVICES: u16 = 1 << 0;
+const BOOT_ARCH_8042: u16 = 1 << 1;
+const BOOT_ARCH_VGA_NOT_PRESENT: u16 = 1 << 2;
+const BOOT_ARCH_MSI_NOT_SUPPORTED: u16 = 1 << 3;
+
+// Fixed feature flags
+const FLAG_TMR_VAL_EXT: u32 = 1 << 8;
+const FLAG_RESET_REG_SUP: u32 = 1 << 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64
+}
+
+impl GenericAddress {
+    pub fn parse(bytes: &[u8], offset: usize) -> GenericAddress {
+        GenericAddress {
+            address_space: read_u8(bytes, offset),
+            bit_width: read_u8(bytes, offset + 1),
+            bit_offset: read_u8(bytes, offset + 2),
+            access_size: read_u8(bytes, offset + 3),
+            address: read_u64(bytes, offset + 4)
+        }
+    }
+
+    pub fn is_io(&self) -> bool {
+        self.address_space == ADDRESS_SPACE_IO
+    }
+}
+
+// The ACPI power management timer, running at 3.579545MHz
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PmTimer {
+    pub address: GenericAddress,
+    // The counter is 24 bit otherwise
+    pub is_32_bit: bool
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fadt {
+    pub revision: u8,
+    pub dsdt_address: u64,
+    pub sci_interrupt: u16,
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm_timer: Option<PmTimer>,
+    // CMOS RTC register holding the century, 0 when there is none
+    pub century: u8,
+    pub legacy_devices: bool,
+    pub has_8042: bool,
+    pub vga_not_present: bool,
+    pub msi_not_supported: bool,
+    pub reset_register: Option<(GenericAddress, u8)>
+}
+
+impl Fadt {
+    // table has to be validated already (see acpi::validate_table)
+    pub fn parse(table: &[u8]) -> Option<Fadt> {
+        let length = table.len();
+        if length < REVISION_1_LENGTH {
+            return None;
+        }
+        // Fields added by later revisions are only there in long enough tables
+        let has = |end: usize| length >= end;
+        let revision = read_u8(table, 8);
+        let flags = read_u32(table, 112);
+        let boot_architecture = if revision >= 2 { read_u16(table, 109) } else { 0 };
+
+        let dsdt_address = match has(148) {
+            true if read_u64(table, 140) != 0 => read_u64(table, 140),
+            _ => read_u32(table, 40) as u64
+        };
+
+        let pm_timer_length = read_u8(table, 91);
+        let extended_pm_timer = if has(220) { Some(GenericAddress::parse(table, 208)) } else { None };
+        let pm_timer_address = match extended_pm_timer {
+            Some(address) if address.address != 0 => Some(address),
+            _ => match read_u32(table, 76) {
+                0 => None,
+                port => Some(GenericAddress {
+                    address_space: ADDRESS_SPACE_IO,
+                    bit_width: 32,
+                    bit_offset: 0,
+                    access_size: 3,
+                    address: port as u64
+                })
+            }
+        };
+        let pm_timer = match pm_timer_address {
+            Some(address) if pm_timer_length >= 4 || extended_pm_timer.is_some() => Some(PmTimer {
+                address,
+                is_32_bit: flags & FLAG_TMR_VAL_EXT != 0
+            }),
+            _ => None
+        };
+
+        let reset_register = if has(129) && flags & FLAG_RESET_REG_SUP != 0 {
+            Some((GenericAddress::parse(table, 116), read_u8(table, 128)))
+        } else {
+            None
+        };
+
+        Some(Fadt {
+            revision,
+            dsdt_address,
+            sci_interrupt: read_u16(table, 46),
+            smi_command_port: read_u32(table, 48),
+            acpi_enable: read_u8(table, 52),
+            acpi_disable: read_u8(table, 53),
+            pm1a_event_block: read_u32(table, 56),
+            pm1a_control_block: read_u32(table, 64),
+            pm_timer,
+            century: read_u8(table, 108),
+            // Before revision 2 the flags didn't exist and PC legacy hardware was a given
+            legacy_devices: revision < 2 || boot_architecture & BOOT_ARCH_LEGACY_DEVICES != 0,
+            has_8042: revision < 2 || boot_architecture & BOOT_ARCH_8042 != 0,
+            vga_not_present: boot_architecture & BOOT_ARCH_VGA_NOT_PRESENT != 0,
+            msi_not_supported: boot_architecture & BOOT_ARCH_MSI_NOT_SUPPORTED != 0,
+            reset_register
+        })
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use crate::acpi::tests::table;
+    use crate::acpi::{validate_table, SDT_HEADER_SIZE};
+
+    #[test]
+    fn fadt_fields() {
+        // ACPI 1.0 sized table
+        let mut body = vec![0u8; REVISION_1_LENGTH - SDT_HEADER_SIZE];
+        let mut set = |offset: usize, bytes: &[u8]| {
+            body[offset - SDT_HEADER_SIZE..offset - SDT_HEADER_SIZE + bytes.len()].copy_from_slice(bytes)
+        };
+        set(40, &0x7fe0000u32.to_le_bytes());
+        set(46, &9u16.to_le_bytes());
+        set(76, &0x608u32.to_le_bytes());
+        set(91, &[4]);
+        set(108, &[0x32]);
+        set(112, &FLAG_TMR_VAL_EXT.to_le_bytes());
+        let fadt = Fadt::parse(&table(b"FACP", 1, &body)).unwrap();
+
+        assert_eq!(fadt.dsdt_address, 0x7fe0000);
+        assert_eq!(fadt.sci_interrupt, 9);
+        assert_eq!(fadt.century, 0x32);
+        assert!(fadt.legacy_devices && fadt.has_8042);
+        assert!(fadt.reset_register.is_none());
+        let pm_timer = fadt.pm_timer.unwrap();
+        assert!(pm_timer.is_32_bit && pm_timer.address.is_io());
+        assert_eq!(pm_timer.address.address, 0x608);
+    }
+
+    #[test]
+    fn truncated_fadt_is_rejected() {
+        // Ends right after the DSDT address
+        let data = table(b"FACP", 1, &[0; 44 - SDT_HEADER_SIZE]);
+        assert!(Fadt::parse(validate_table(&data, b"FACP").unwrap()).is_none());
+    }
+}
@@ -0,0 +1,69 @@
+// HPET description table : where the high precision event timer's registers are and what the
+// timer block supports.
+
+use crate::acpi::fadt::GenericAddress;
+use crate::acpi::{read_u16, read_u32, read_u8, SDT_HEADER_SIZE};
+
+const TABLE_SIZE: usize = SDT_HEADER_SIZE + 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hpet {
+    pub hardware_revision: u8,
+    pub comparator_count: u8,
+    pub counter_64_bit: bool,
+    // Can replace the PIT and RTC interrupts (IRQ 0 and 8)
+    pub legacy_replacement: bool,
+    pub pci_vendor_id: u16,
+    pub base_address: GenericAddress,
+    pub number: u8,
+    // Smallest tick period usable in periodic mode, in main counter ticks
+    pub minimum_tick: u16
+}
+
+impl Hpet {
+    // table has to be validated already (see acpi::validate_table)
+    pub fn parse(table: &[u8]) -> Option<Hpet> {
+        if table.len() < TABLE_SIZE {
+            return None;
+        }
+        let block_id = read_u32(table, 36);
+        Some(Hpet {
+            hardware_revision: block_id as u8,
+            comparator_count: ((block_id >> 8) & 0x1f) as u8 + 1,
+            counter_64_bit: block_id & (1 << 13) != 0,
+            legacy_replacement: block_id & (1 << 15) != 0,
+            pci_vendor_id: (block_id >> 16) as u16,
+            base_address: GenericAddress::parse(table, 40),
+            number: read_u8(table, 52),
+            minimum_tick: read_u16(table, 53)
+        })
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use crate::acpi::tests::table;
+    use std::vec::Vec;
+
+    #[test]
+    fn hpet_fields() {
+        let mut body = Vec::new();
+        // Revision 1, 3 comparators, 64 bit, legacy replacement, vendor 0x8086
+        body.extend_from_slice(&0x8086_a201u32.to_le_bytes());
+        body.extend_from_slice(&[0, 64, 0, 0]);
+        body.extend_from_slice(&0xfed00000u64.to_le_bytes());
+        body.push(0);
+        body.extend_from_slice(&0x80u16.to_le_bytes());
+        body.push(0);
+        let hpet = Hpet::parse(&table(b"HPET", 1, &body)).unwrap();
+
+        assert_eq!(hpet.comparator_count, 3);
+        assert!(hpet.counter_64_bit && hpet.legacy_replacement);
+        assert_eq!(hpet.pci_vendor_id, 0x8086);
+        assert_eq!(hpet.base_address.address, 0xfed00000);
+        assert!(!hpet.base_address.is_io());
+        assert_eq!(hpet.minimum_tick, 0x80);
+        assert!(Hpet::parse(&table(b"HPET", 1, &body[..10])).is_none());
+    }
+}
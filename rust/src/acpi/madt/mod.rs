@@ -0,0 +1,259 @@
+// Multiple APIC Description Table ("APIC" signature) : the local APIC address, one entry per
+// processor and per IOAPIC, and the ISA interrupts that aren't identity mapped to global system
+// interrupts (GSIs).
+
+use crate::acpi::{read_u16, read_u32, read_u64, read_u8, SDT_HEADER_SIZE};
+use alloc::vec::Vec;
+
+const ENTRIES_OFFSET: usize = SDT_HEADER_SIZE + 8;
+// MADT flags
+const PCAT_COMPAT: u32 = 1 << 0;
+
+const ENTRY_LOCAL_APIC: u8 = 0;
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_INTERRUPT_OVERRIDE: u8 = 2;
+const ENTRY_LOCAL_APIC_NMI: u8 = 4;
+const ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+const ENTRY_LOCAL_X2APIC: u8 = 9;
+const ENTRY_LOCAL_X2APIC_NMI: u8 = 0xa;
+
+const PROCESSOR_ENABLED: u32 = 1 << 0;
+const PROCESSOR_ONLINE_CAPABLE: u32 = 1 << 1;
+
+// Processor UID of NMI entries applying to every processor
+pub const ALL_PROCESSORS: u32 = 0xffff_ffff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    // Whatever the bus uses, active high for ISA
+    BusDefault,
+    ActiveHigh,
+    ActiveLow
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    // Whatever the bus uses, edge for ISA
+    BusDefault,
+    Edge,
+    Level
+}
+
+// MPS INTI flags, used by overrides and NMI entries
+fn inti_flags(flags: u16) -> (Polarity, TriggerMode) {
+    let polarity = match flags & 0b11 {
+        0b01 => Polarity::ActiveHigh,
+        0b11 => Polarity::ActiveLow,
+        _ => Polarity::BusDefault
+    };
+    let trigger = match (flags >> 2) & 0b11 {
+        0b01 => TriggerMode::Edge,
+        0b11 => TriggerMode::Level,
+        _ => TriggerMode::BusDefault
+    };
+    (polarity, trigger)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Processor {
+    pub processor_uid: u32,
+    pub apic_id: u32,
+    pub enabled: bool,
+    // Disabled processors with this set can be brought up later
+    pub online_capable: bool
+}
+
+impl Processor {
+    pub fn is_usable(&self) -> bool {
+        self.enabled || self.online_capable
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u32,
+    // First GSI handled by this IOAPIC
+    pub gsi_base: u32
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    // Always 0 (ISA)
+    pub bus: u8,
+    pub source: u8,
+    pub gsi: u32,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalApicNmi {
+    // ALL_PROCESSORS for every processor
+    pub processor_uid: u32,
+    pub polarity: Polarity,
+    pub trigger: TriggerMode,
+    // LINT0 or LINT1
+    pub lint: u8
+}
+
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: u64,
+    // The legacy 8259 PICs are there too, and have to be masked when using the APICs
+    pub has_8259: bool,
+    pub processors: Vec<Processor>,
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<InterruptOverride>,
+    pub nmis: Vec<LocalApicNmi>
+}
+
+impl Madt {
+    // table has to be validated already (see acpi::validate_table). Unknown and truncated entries
+    // are skipped. None when the table is too short for its fixed fields.
+    pub fn parse(table: &[u8]) -> Option<Madt> {
+        if table.len() < ENTRIES_OFFSET {
+            return None;
+        }
+        let mut madt = Madt {
+            local_apic_address: read_u32(table, SDT_HEADER_SIZE) as u64,
+            has_8259: read_u32(table, SDT_HEADER_SIZE + 4) & PCAT_COMPAT != 0,
+            processors: Vec::new(),
+            io_apics: Vec::new(),
+            overrides: Vec::new(),
+            nmis: Vec::new()
+        };
+
+        let mut offset = ENTRIES_OFFSET;
+        while offset + 2 <= table.len() {
+            let entry_type = read_u8(table, offset);
+            let length = read_u8(table, offset + 1) as usize;
+            if length < 2 || offset + length > table.len() {
+                break;
+            }
+            let entry = &table[offset..offset + length];
+            madt.parse_entry(entry_type, entry);
+            offset += length;
+        }
+        Some(madt)
+    }
+
+    fn parse_entry(&mut self, entry_type: u8, entry: &[u8]) {
+        match (entry_type, entry.len()) {
+            (ENTRY_LOCAL_APIC, 8..=usize::MAX) => {
+                let flags = read_u32(entry, 4);
+                self.processors.push(Processor {
+                    processor_uid: read_u8(entry, 2) as u32,
+                    apic_id: read_u8(entry, 3) as u32,
+                    enabled: flags & PROCESSOR_ENABLED != 0,
+                    online_capable: flags & PROCESSOR_ONLINE_CAPABLE != 0
+                });
+            }
+            (ENTRY_LOCAL_X2APIC, 16..=usize::MAX) => {
+                let flags = read_u32(entry, 8);
+                self.processors.push(Processor {
+                    processor_uid: read_u32(entry, 12),
+                    apic_id: read_u32(entry, 4),
+                    enabled: flags & PROCESSOR_ENABLED != 0,
+                    online_capable: flags & PROCESSOR_ONLINE_CAPABLE != 0
+                });
+            }
+            (ENTRY_IO_APIC, 12..=usize::MAX) => self.io_apics.push(IoApic {
+                id: read_u8(entry, 2),
+                address: read_u32(entry, 4),
+                gsi_base: read_u32(entry, 8)
+            }),
+            (ENTRY_INTERRUPT_OVERRIDE, 10..=usize::MAX) => {
+                let (polarity, trigger) = inti_flags(read_u16(entry, 8));
+                self.overrides.push(InterruptOverride {
+                    bus: read_u8(entry, 2),
+                    source: read_u8(entry, 3),
+                    gsi: read_u32(entry, 4),
+                    polarity,
+                    trigger
+                });
+            }
+            (ENTRY_LOCAL_APIC_NMI, 6..=usize::MAX) => {
+                let (polarity, trigger) = inti_flags(read_u16(entry, 3));
+                let processor_uid = match read_u8(entry, 2) {
+                    0xff => ALL_PROCESSORS,
+                    uid => uid as u32
+                };
+                self.nmis.push(LocalApicNmi { processor_uid, polarity, trigger, lint: read_u8(entry, 5) });
+            }
+            (ENTRY_LOCAL_X2APIC_NMI, 12..=usize::MAX) => {
+                let (polarity, trigger) = inti_flags(read_u16(entry, 2));
+                self.nmis.push(LocalApicNmi {
+                    processor_uid: read_u32(entry, 4),
+                    polarity,
+                    trigger,
+                    lint: read_u8(entry, 8)
+                });
+            }
+            (ENTRY_LOCAL_APIC_ADDRESS_OVERRIDE, 12..=usize::MAX) => {
+                self.local_apic_address = read_u64(entry, 4);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use crate::acpi::tests::table;
+    use crate::acpi::validate_table;
+    use std::vec::Vec;
+
+    fn madt() -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&0xfee00000u32.to_le_bytes());
+        body.extend_from_slice(&PCAT_COMPAT.to_le_bytes());
+        // Two processors, the second one disabled
+        body.extend_from_slice(&[ENTRY_LOCAL_APIC, 8, 0, 0, 1, 0, 0, 0]);
+        body.extend_from_slice(&[ENTRY_LOCAL_APIC, 8, 1, 1, 0, 0, 0, 0]);
+        // IOAPIC 2 at 0xfec00000, GSI base 0
+        body.extend_from_slice(&[ENTRY_IO_APIC, 12, 2, 0, 0x00, 0x00, 0xc0, 0xfe, 0, 0, 0, 0]);
+        // IRQ 0 -> GSI 2, IRQ 9 -> GSI 9 level triggered active low
+        body.extend_from_slice(&[ENTRY_INTERRUPT_OVERRIDE, 10, 0, 0, 2, 0, 0, 0, 0, 0]);
+        body.extend_from_slice(&[ENTRY_INTERRUPT_OVERRIDE, 10, 0, 9, 9, 0, 0, 0, 0x0f, 0]);
+        // NMI on LINT1 of every processor
+        body.extend_from_slice(&[ENTRY_LOCAL_APIC_NMI, 6, 0xff, 0, 0, 1]);
+        // Unknown entry type, skipped
+        body.extend_from_slice(&[0x7f, 4, 0, 0]);
+        body.extend_from_slice(&[ENTRY_LOCAL_X2APIC, 16, 0, 0, 0x00, 0x01, 0, 0, 1, 0, 0, 0, 7, 0, 0, 0]);
+        table(b"APIC", 4, &body)
+    }
+
+    #[test]
+    fn madt_entries_are_parsed() {
+        let data = madt();
+        let madt = Madt::parse(validate_table(&data, b"APIC").unwrap()).unwrap();
+        assert_eq!(madt.local_apic_address, 0xfee00000);
+        assert!(madt.has_8259);
+        assert_eq!(madt.processors.len(), 3);
+        assert!(madt.processors[0].enabled && !madt.processors[1].is_usable());
+        assert_eq!(madt.processors[2], Processor { processor_uid: 7, apic_id: 0x100, enabled: true, online_capable: false });
+        assert_eq!(madt.io_apics, vec![IoApic { id: 2, address: 0xfec00000, gsi_base: 0 }]);
+        assert_eq!(madt.nmis, vec![LocalApicNmi {
+            processor_uid: ALL_PROCESSORS,
+            polarity: Polarity::BusDefault,
+            trigger: TriggerMode::BusDefault,
+            lint: 1
+        }]);
+        assert_eq!(madt.overrides.len(), 2);
+        assert_eq!(madt.overrides[1], InterruptOverride {
+            bus: 0,
+            source: 9,
+            gsi: 9,
+            polarity: Polarity::ActiveLow,
+            trigger: TriggerMode::Level
+        });
+    }
+
+    #[test]
+    fn truncated_madt_is_rejected() {
+        let data = table(b"APIC", 4, &0xfee00000u32.to_le_bytes());
+        assert!(Madt::parse(validate_table(&data, b"APIC").unwrap()).is_none());
+    }
+}
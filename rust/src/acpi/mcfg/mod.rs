@@ -0,0 +1,59 @@
+// PCI express memory mapped configuration space table ("MCFG" signature). Each entry covers the
+// buses of a PCI segment group, function f of device d on bus b having its configuration space at
+// base_address + ((b - start_bus) << 20 | d << 15 | f << 12).
+
+use crate::acpi::{read_u16, read_u64, read_u8, SDT_HEADER_SIZE};
+use alloc::vec::Vec;
+
+const ENTRIES_OFFSET: usize = SDT_HEADER_SIZE + 8;
+const ENTRY_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct McfgEntry {
+    pub base_address: u64,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8
+}
+
+impl McfgEntry {
+    pub fn function_address(&self, bus: u8, device: u8, function: u8) -> Option<u64> {
+        if bus < self.start_bus || bus > self.end_bus || device >= 32 || function >= 8 {
+            return None;
+        }
+        let offset = ((bus - self.start_bus) as u64) << 20 | (device as u64) << 15 | (function as u64) << 12;
+        Some(self.base_address + offset)
+    }
+}
+
+// table has to be validated already (see acpi::validate_table)
+pub fn parse(table: &[u8]) -> Vec<McfgEntry> {
+    if table.len() < ENTRIES_OFFSET {
+        return Vec::new();
+    }
+    table[ENTRIES_OFFSET..].chunks_exact(ENTRY_SIZE).map(|entry| McfgEntry {
+        base_address: read_u64(entry, 0),
+        segment: read_u16(entry, 8),
+        start_bus: read_u8(entry, 10),
+        end_bus: read_u8(entry, 11)
+    }).collect()
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use crate::acpi::tests::table;
+    use std::vec::Vec;
+
+    #[test]
+    fn mcfg_entries() {
+        let mut body = vec![0u8; 8];
+        body.extend_from_slice(&0xb0000000u64.to_le_bytes());
+        body.extend_from_slice(&[0, 0, 0, 0xff, 0, 0, 0, 0]);
+        let entries = parse(&table(b"MCFG", 1, &body));
+
+        assert_eq!(entries, vec![McfgEntry { base_address: 0xb0000000, segment: 0, start_bus: 0, end_bus: 0xff }]);
+        assert_eq!(entries[0].function_address(1, 2, 3), Some(0xb0000000 + (1 << 20) + (2 << 15) + (3 << 12)));
+        assert_eq!(entries[0].function_address(0, 32, 0), None);
+    }
+}
@@ -0,0 +1,410 @@
+// ACPI table discovery.
+//
+// The bootloader passes the physical address of the RSDP, which points to the XSDT (ACPI 2.0+) or
+// the RSDT listing every other table. Each table is mapped read only into the MMIO window (see
+// layout::map_physical) after its checksum is verified. The tables describing the hardware the
+// kernel needs early are parsed into Rust structures :
+// - MADT : processors, IOAPICs and how ISA interrupts are wired to them (madt)
+// - FADT : fixed hardware, power management timer, reset register (fadt)
+// - HPET : the high precision event timer (hpet)
+// - MCFG : PCI express configuration space (mcfg)
+//
+// Parsing works on byte slices, so it doesn't depend on how tables were mapped.
+
+pub mod fadt;
+pub mod hpet;
+pub mod madt;
+pub mod mcfg;
+
+use crate::acpi::fadt::Fadt;
+use crate::acpi::hpet::Hpet;
+use crate::acpi::madt::Madt;
+use crate::acpi::mcfg::McfgEntry;
+use crate::memory::frame_allocator::FRAME_SIZE;
+use crate::memory::layout::map_physical;
+use crate::memory::paging::EntryFlags;
+use crate::stivale2::{RsdpTag, STRUCT_TAG_RSDP_ID};
+use crate::utils::ceil_div_usize;
+use alloc::vec::Vec;
+use spin::Once;
+
+pub const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+pub const SDT_HEADER_SIZE: usize = 36;
+const RSDP_V1_SIZE: usize = 20;
+const RSDP_V2_SIZE: usize = 36;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    NoRsdp,
+    BadSignature,
+    BadChecksum,
+    Truncated
+}
+
+fn read_u8(bytes: &[u8], offset: usize) -> u8 {
+    bytes[offset]
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    let mut value = [0; 4];
+    value.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(value)
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    let mut value = [0; 8];
+    value.copy_from_slice(&bytes[offset..offset + 8]);
+    u64::from_le_bytes(value)
+}
+
+// Every byte of a valid structure adds up to 0
+pub fn checksum_is_valid(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
+
+// Text fields are space padded
+pub fn text(bytes: &[u8]) -> &str {
+    core::str::from_utf8(bytes).unwrap_or("?").trim_end_matches(|c| c == ' ' || c == '\0')
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Rsdp {
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    pub rsdt_address: u32,
+    // ACPI 2.0+
+    pub xsdt_address: Option<u64>
+}
+
+impl Rsdp {
+    pub fn parse(bytes: &[u8]) -> Result<Rsdp, AcpiError> {
+        if bytes.len() < RSDP_V1_SIZE {
+            return Err(AcpiError::Truncated);
+        }
+        if &bytes[..8] != RSDP_SIGNATURE {
+            return Err(AcpiError::BadSignature);
+        }
+        if !checksum_is_valid(&bytes[..RSDP_V1_SIZE]) {
+            return Err(AcpiError::BadChecksum);
+        }
+
+        let revision = read_u8(bytes, 15);
+        let mut oem_id = [0; 6];
+        oem_id.copy_from_slice(&bytes[9..15]);
+        let xsdt_address = if revision >= 2 {
+            if bytes.len() < RSDP_V2_SIZE {
+                return Err(AcpiError::Truncated);
+            }
+            // The extended checksum covers the whole structure
+            if !checksum_is_valid(&bytes[..RSDP_V2_SIZE]) {
+                return Err(AcpiError::BadChecksum);
+            }
+            Some(read_u64(bytes, 24)).filter(|&address| address != 0)
+        } else {
+            None
+        };
+
+        Ok(Rsdp {
+            revision,
+            oem_id,
+            rsdt_address: read_u32(bytes, 16),
+            xsdt_address
+        })
+    }
+}
+
+// Header shared by every table but the RSDP
+#[derive(Debug, Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8]
+}
+
+impl SdtHeader {
+    pub fn parse(bytes: &[u8]) -> Result<SdtHeader, AcpiError> {
+        if bytes.len() < SDT_HEADER_SIZE {
+            return Err(AcpiError::Truncated);
+        }
+        let mut header = SdtHeader {
+            signature: [0; 4],
+            length: read_u32(bytes, 4),
+            revision: read_u8(bytes, 8),
+            oem_id: [0; 6],
+            oem_table_id: [0; 8]
+        };
+        header.signature.copy_from_slice(&bytes[0..4]);
+        header.oem_id.copy_from_slice(&bytes[10..16]);
+        header.oem_table_id.copy_from_slice(&bytes[16..24]);
+        Ok(header)
+    }
+
+    pub fn signature(&self) -> &str {
+        text(&self.signature)
+    }
+}
+
+// Checks a table's signature, length and checksum, returning it cut to its length
+pub fn validate_table<'a>(bytes: &'a [u8], signature: &[u8; 4]) -> Result<&'a [u8], AcpiError> {
+    let header = SdtHeader::parse(bytes)?;
+    if &header.signature != signature {
+        return Err(AcpiError::BadSignature);
+    }
+    let length = header.length as usize;
+    if length < SDT_HEADER_SIZE || length > bytes.len() {
+        return Err(AcpiError::Truncated);
+    }
+    let table = &bytes[..length];
+    if !checksum_is_valid(table) {
+        return Err(AcpiError::BadChecksum);
+    }
+    Ok(table)
+}
+
+// Physical addresses of the tables listed by an RSDT (32 bit entries) or XSDT (64 bit entries)
+pub fn root_table_entries(table: &[u8], extended: bool) -> impl Iterator<Item = u64> + '_ {
+    let entry_size = if extended { 8 } else { 4 };
+    table[SDT_HEADER_SIZE..].chunks_exact(entry_size).map(move |entry| {
+        if extended { read_u64(entry, 0) } else { read_u32(entry, 0) as u64 }
+    })
+}
+
+// Maps a table and checks it. The header is mapped first to find out the table's length.
+unsafe fn map_table(physical_address: u64, signature: Option<&[u8; 4]>) -> Result<&'static [u8], AcpiError> {
+    let physical_address = physical_address as usize;
+    // Map the frames holding the header, then the following ones only if the table goes past them
+    let mapped_end = ceil_div_usize(physical_address + SDT_HEADER_SIZE, FRAME_SIZE) * FRAME_SIZE;
+    let address = map_physical(physical_address, mapped_end - physical_address, EntryFlags::PRESENT);
+    let header = SdtHeader::parse(core::slice::from_raw_parts(address as *const u8, SDT_HEADER_SIZE))?;
+    let length = (header.length as usize).max(SDT_HEADER_SIZE);
+
+    if physical_address + length > mapped_end {
+        let rest = map_physical(mapped_end, physical_address + length - mapped_end, EntryFlags::PRESENT);
+        // The MMIO window is bump allocated, nothing else is mapped in between during boot
+        assert_eq!(rest, address + mapped_end - physical_address, "ACPI table mapping is not contiguous.");
+    }
+    let table = core::slice::from_raw_parts(address as *const u8, length);
+    validate_table(table, signature.unwrap_or(&header.signature))
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TableInfo {
+    pub header: SdtHeader,
+    pub physical_address: u64,
+    pub table: &'static [u8]
+}
+
+pub struct AcpiInfo {
+    pub rsdp: Rsdp,
+    // Every valid table listed by the XSDT / RSDT
+    pub tables: Vec<TableInfo>,
+    pub madt: Option<Madt>,
+    pub fadt: Option<Fadt>,
+    pub hpet: Option<Hpet>,
+    pub mcfg: Vec<McfgEntry>
+}
+
+impl AcpiInfo {
+    pub fn find_table(&self, signature: &[u8; 4]) -> Option<&'static [u8]> {
+        self.tables.iter().find(|info| &info.header.signature == signature).map(|info| info.table)
+    }
+}
+
+static ACPI: Once<Option<AcpiInfo>> = Once::new();
+
+unsafe fn discover() -> Result<AcpiInfo, AcpiError> {
+    let tag = crate::stivale2::read_tag::<RsdpTag>(STRUCT_TAG_RSDP_ID).ok_or(AcpiError::NoRsdp)?;
+    let rsdp_address = map_physical(tag.rsdp as usize, RSDP_V2_SIZE, EntryFlags::PRESENT);
+    let rsdp = Rsdp::parse(core::slice::from_raw_parts(rsdp_address as *const u8, RSDP_V2_SIZE))?;
+
+    let (root_table, extended) = match rsdp.xsdt_address {
+        Some(address) => (map_table(address, Some(b"XSDT"))?, true),
+        None => (map_table(rsdp.rsdt_address as u64, Some(b"RSDT"))?, false)
+    };
+
+    let mut tables = Vec::new();
+    for physical_address in root_table_entries(root_table, extended) {
+        match map_table(physical_address, None) {
+            Ok(table) => tables.push(TableInfo {
+                header: SdtHeader::parse(table)?,
+                physical_address,
+                table
+            }),
+            Err(error) => warn!("Ignoring the ACPI table at 0x{:x} ({:?})", physical_address, error)
+        }
+    }
+
+    let mut info = AcpiInfo {
+        rsdp,
+        tables,
+        madt: None,
+        fadt: None,
+        hpet: None,
+        mcfg: Vec::new()
+    };
+    info.madt = info.find_table(b"APIC").and_then(Madt::parse);
+    info.fadt = info.find_table(b"FACP").and_then(Fadt::parse);
+    info.hpet = info.find_table(b"HPET").and_then(Hpet::parse);
+    info.mcfg = info.find_table(b"MCFG").map(mcfg::parse).unwrap_or_default();
+    Ok(info)
+}
+
+fn print_summary(info: &AcpiInfo) {
+    info!("ACPI revision {} ({}), OEM {}, {} tables",
+          info.rsdp.revision,
+          if info.rsdp.xsdt_address.is_some() { "XSDT" } else { "RSDT" },
+          text(&info.rsdp.oem_id),
+          info.tables.len()
+    );
+    for table in info.tables.iter() {
+        debug!("  {} at 0x{:x}, {} bytes, revision {}, {} {}",
+               table.header.signature(),
+               table.physical_address,
+               table.header.length,
+               table.header.revision,
+               text(&table.header.oem_id),
+               text(&table.header.oem_table_id)
+        );
+    }
+
+    match &info.madt {
+        Some(madt) => {
+            info!("MADT : {} processors ({} usable), {} IOAPICs, {} interrupt overrides{}",
+                  madt.processors.len(),
+                  madt.processors.iter().filter(|processor| processor.is_usable()).count(),
+                  madt.io_apics.len(),
+                  madt.overrides.len(),
+                  if madt.has_8259 { ", legacy PICs present" } else { "" }
+            );
+            for io_apic in madt.io_apics.iter() {
+                debug!("  IOAPIC {} at 0x{:x}, GSI base {}", io_apic.id, io_apic.address, io_apic.gsi_base);
+            }
+            for interrupt_override in madt.overrides.iter() {
+                debug!("  IRQ {} -> GSI {} ({:?}, {:?})",
+                       interrupt_override.source,
+                       interrupt_override.gsi,
+                       interrupt_override.polarity,
+                       interrupt_override.trigger
+                );
+            }
+        }
+        None => warn!("No MADT, interrupt controllers can't be discovered")
+    }
+    if let Some(fadt) = &info.fadt {
+        info!("FADT : SCI on IRQ {}, PM timer {}, century register {}",
+              fadt.sci_interrupt,
+              match fadt.pm_timer { Some(timer) => if timer.is_32_bit { "32 bit" } else { "24 bit" }, None => "absent" },
+              fadt.century
+        );
+    }
+    if let Some(hpet) = &info.hpet {
+        info!("HPET : {} comparators at 0x{:x}, {} bit counter",
+              hpet.comparator_count,
+              hpet.base_address.address,
+              if hpet.counter_64_bit { 64 } else { 32 }
+        );
+    }
+    for entry in info.mcfg.iter() {
+        info!("MCFG : segment {} buses {}-{} at 0x{:x}", entry.segment, entry.start_bus, entry.end_bus, entry.base_address);
+    }
+}
+
+// Finds and parses the tables, then prints a summary. Only usable once the kernel heap allocator
+// is set up.
+pub fn init() -> Option<&'static AcpiInfo> {
+    ACPI.call_once(|| match unsafe { discover() } {
+        Ok(info) => {
+            print_summary(&info);
+            Some(info)
+        }
+        Err(error) => {
+            warn!("ACPI tables unavailable ({:?})", error);
+            None
+        }
+    }).as_ref()
+}
+
+// None before init, or when the firmware has no valid ACPI tables
+pub fn info() -> Option<&'static AcpiInfo> {
+    ACPI.r#try().and_then(|info| info.as_ref())
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+pub mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    // Builds a table with a valid header and checksum around body
+    pub fn table(signature: &[u8; 4], revision: u8, body: &[u8]) -> Vec<u8> {
+        let mut table = Vec::new();
+        table.extend_from_slice(signature);
+        table.extend_from_slice(&((SDT_HEADER_SIZE + body.len()) as u32).to_le_bytes());
+        table.push(revision);
+        table.push(0);
+        table.extend_from_slice(b"SYSCTL");
+        table.extend_from_slice(b"TESTTABL");
+        table.extend_from_slice(&[0; 12]);
+        table.extend_from_slice(body);
+        fix_checksum(&mut table, 9);
+        table
+    }
+
+    pub fn fix_checksum(bytes: &mut [u8], checksum_offset: usize) {
+        bytes[checksum_offset] = 0;
+        let sum = bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        bytes[checksum_offset] = 0u8.wrapping_sub(sum);
+    }
+
+    fn rsdp(revision: u8) -> Vec<u8> {
+        let mut rsdp = Vec::new();
+        rsdp.extend_from_slice(RSDP_SIGNATURE);
+        rsdp.push(0);
+        rsdp.extend_from_slice(b"SYSCTL");
+        rsdp.push(revision);
+        rsdp.extend_from_slice(&0x7fe1000u32.to_le_bytes());
+        rsdp.extend_from_slice(&36u32.to_le_bytes());
+        rsdp.extend_from_slice(&0x7fe2000u64.to_le_bytes());
+        rsdp.extend_from_slice(&[0; 4]);
+        fix_checksum(&mut rsdp[..RSDP_V1_SIZE], 8);
+        fix_checksum(&mut rsdp, 32);
+        rsdp
+    }
+
+    #[test]
+    fn rsdp_versions() {
+        let v1 = Rsdp::parse(&rsdp(0)).unwrap();
+        assert_eq!((v1.revision, v1.rsdt_address, v1.xsdt_address), (0, 0x7fe1000, None));
+        assert_eq!(text(&v1.oem_id), "SYSCTL");
+        let v2 = Rsdp::parse(&rsdp(2)).unwrap();
+        assert_eq!(v2.xsdt_address, Some(0x7fe2000));
+
+        let mut broken = rsdp(2);
+        broken[30] ^= 1;
+        assert_eq!(Rsdp::parse(&broken).err(), Some(AcpiError::BadChecksum));
+        broken[0] = b'X';
+        assert_eq!(Rsdp::parse(&broken).err(), Some(AcpiError::BadSignature));
+    }
+
+    #[test]
+    fn tables_are_validated() {
+        let mut body = Vec::new();
+        body.extend_from_slice(&0x1000u64.to_le_bytes());
+        body.extend_from_slice(&0x2000u64.to_le_bytes());
+        let xsdt = table(b"XSDT", 1, &body);
+        let validated = validate_table(&xsdt, b"XSDT").unwrap();
+        assert_eq!(root_table_entries(validated, true).collect::<Vec<_>>(), vec![0x1000, 0x2000]);
+        assert_eq!(root_table_entries(validated, false).count(), 4);
+        assert_eq!(validate_table(&xsdt, b"RSDT").err(), Some(AcpiError::BadSignature));
+        assert_eq!(validate_table(&xsdt[..40], b"XSDT").err(), Some(AcpiError::Truncated));
+
+        let mut corrupted = xsdt.clone();
+        corrupted[40] ^= 0xff;
+        assert_eq!(validate_table(&corrupted, b"XSDT").err(), Some(AcpiError::BadChecksum));
+    }
+}
@@ -0,0 +1,73 @@
+// High precision event timer, only its main counter is used.
+
+use crate::acpi::hpet::Hpet as HpetTable;
+use crate::memory::frame_allocator::FRAME_SIZE;
+use crate::memory::layout::map_mmio;
+
+const REGISTER_CAPABILITIES: usize = 0x000;
+const REGISTER_CONFIGURATION: usize = 0x010;
+const REGISTER_MAIN_COUNTER: usize = 0x0f0;
+
+const CAPABILITY_COUNTER_64_BIT: u64 = 1 << 13;
+const CONFIGURATION_ENABLE: u64 = 1 << 0;
+
+// The spec caps the period at 100 ns
+const MAX_PERIOD_FEMTOSECONDS: u64 = 100_000_000;
+
+pub struct Hpet {
+    base: usize,
+    // Length of a main counter tick
+    pub period_femtoseconds: u64,
+    pub counter_64_bit: bool
+}
+
+impl Hpet {
+    // Maps the registers described by the ACPI table and starts the main counter. None when the
+    // HPET is unusable.
+    pub unsafe fn init(table: &HpetTable) -> Option<Hpet> {
+        if table.base_address.is_io() || table.base_address.address == 0 {
+            return None;
+        }
+        let mut hpet = Hpet {
+            base: map_mmio(table.base_address.address as usize, FRAME_SIZE),
+            period_femtoseconds: 0,
+            counter_64_bit: false
+        };
+        let capabilities = hpet.read(REGISTER_CAPABILITIES);
+        hpet.period_femtoseconds = capabilities >> 32;
+        hpet.counter_64_bit = capabilities & CAPABILITY_COUNTER_64_BIT != 0;
+        if hpet.period_femtoseconds == 0 || hpet.period_femtoseconds > MAX_PERIOD_FEMTOSECONDS {
+            return None;
+        }
+
+        let configuration = hpet.read(REGISTER_CONFIGURATION);
+        hpet.write(REGISTER_CONFIGURATION, configuration | CONFIGURATION_ENABLE);
+        Some(hpet)
+    }
+
+    unsafe fn read(&self, register: usize) -> u64 {
+        core::ptr::read_volatile((self.base + register) as *const u64)
+    }
+
+    unsafe fn write(&self, register: usize, value: u64) {
+        core::ptr::write_volatile((self.base + register) as *mut u64, value);
+    }
+
+    pub fn frequency(&self) -> u64 {
+        1_000_000_000_000_000 / self.period_femtoseconds
+    }
+
+    pub fn counter(&self) -> u64 {
+        let counter = unsafe { self.read(REGISTER_MAIN_COUNTER) };
+        if self.counter_64_bit { counter } else { counter & 0xffff_ffff }
+    }
+
+    // Ticks between two counter values, handling a 32 bit counter wrapping once
+    pub fn elapsed(&self, start: u64, end: u64) -> u64 {
+        if self.counter_64_bit {
+            end.wrapping_sub(start)
+        } else {
+            (end as u32).wrapping_sub(start as u32) as u64
+        }
+    }
+}
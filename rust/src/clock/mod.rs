@@ -0,0 +1,325 @@
+// Timekeeping.
+//
+// The TSC is the clock : its frequency is measured at boot against the HPET, or against PIT
+// channel 2 without one, and now_ns converts it to nanoseconds since the processor was reset.
+// Interrupts are raised by a tick, the local APIC timer when the APICs are used and PIT channel 0
+// otherwise, either periodically or once (see start_tick).
+
+pub mod hpet;
+pub mod pit;
+
+use crate::clock::hpet::Hpet;
+use crate::interrupts::apic::{self, LocalApic};
+use crate::interrupts::{irq, set_handler, without_interrupts, InterruptStackFrame};
+use crate::utils::cpuid;
+use crate::utils::reg_read::read_tsc;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Once;
+
+// Frequency of the tick started at boot
+pub const TICK_FREQUENCY: u64 = 100;
+// Local APIC timer vector, below the APIC error and spurious vectors
+pub const TIMER_VECTOR: usize = 0xf0;
+
+const CALIBRATION_NS: u64 = 10_000_000;
+// The lowest measurement is kept, SMIs and virtualization only make them longer
+const CALIBRATION_ROUNDS: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CalibrationSource {
+    Hpet,
+    Pit
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TickSource {
+    // Counts per second, after the timer's divider
+    LocalApicTimer(u64),
+    // GSI of IRQ 0
+    Pit(u32)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TickMode {
+    // Ticks per second
+    Periodic(u64),
+    // A single tick after this many nanoseconds
+    OneShot(u64)
+}
+
+pub struct Clock {
+    pub tsc_frequency: u64,
+    pub calibration_source: CalibrationSource,
+    // Without an invariant TSC, frequency changes and deep sleep states make the clock drift
+    pub invariant_tsc: bool,
+    pub tick_source: Option<TickSource>,
+    pub hpet: Option<Hpet>
+}
+
+static CLOCK: Once<Clock> = Once::new();
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static PERIODIC_TICK: AtomicBool = AtomicBool::new(false);
+
+pub fn ticks_to_ns(ticks: u64, frequency: u64) -> u64 {
+    (ticks as u128 * 1_000_000_000 / frequency as u128) as u64
+}
+
+pub fn ns_to_ticks(nanoseconds: u64, frequency: u64) -> u64 {
+    (nanoseconds as u128 * frequency as u128 / 1_000_000_000) as u64
+}
+
+// Ticks per second of something that ticked ticks times in nanoseconds
+pub fn frequency(ticks: u64, nanoseconds: u64) -> u64 {
+    (ticks as u128 * 1_000_000_000 / nanoseconds.max(1) as u128) as u64
+}
+
+fn tsc_frequency_against_hpet(hpet: &Hpet) -> u64 {
+    let target = CALIBRATION_NS * 1_000_000 / hpet.period_femtoseconds;
+    let start = hpet.counter();
+    let tsc_start = unsafe { read_tsc() };
+    while hpet.elapsed(start, hpet.counter()) < target {
+        core::hint::spin_loop();
+    }
+    let tsc_end = unsafe { read_tsc() };
+    let elapsed = hpet.elapsed(start, hpet.counter());
+    frequency(tsc_end - tsc_start, (elapsed as u128 * hpet.period_femtoseconds as u128 / 1_000_000) as u64)
+}
+
+fn tsc_frequency_against_pit() -> u64 {
+    let count = pit::count_for_ns(CALIBRATION_NS);
+    let tsc_start = unsafe {
+        pit::start_channel2(count);
+        read_tsc()
+    };
+    while unsafe { !pit::channel2_finished() } {
+        core::hint::spin_loop();
+    }
+    let tsc_end = unsafe { read_tsc() };
+    frequency(tsc_end - tsc_start, ticks_to_ns(count, pit::FREQUENCY))
+}
+
+// Counts per second of the local APIC timer, measured against the TSC
+fn local_apic_timer_frequency(local_apic: &LocalApic, tsc_frequency: u64) -> u64 {
+    let duration = ns_to_ticks(CALIBRATION_NS, tsc_frequency);
+    local_apic.start_timer(TIMER_VECTOR as u8, u32::MAX, false);
+    let tsc_start = unsafe { read_tsc() };
+    while unsafe { read_tsc() } - tsc_start < duration {
+        core::hint::spin_loop();
+    }
+    let remaining = local_apic.timer_count();
+    let tsc_end = unsafe { read_tsc() };
+    local_apic.stop_timer();
+    frequency((u32::MAX - remaining) as u64, ticks_to_ns(tsc_end - tsc_start, tsc_frequency))
+}
+
+fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+extern "x86-interrupt" fn local_apic_timer_handler(_stack_frame: InterruptStackFrame) {
+    tick();
+    if let Some(apic) = apic::controller() {
+        apic.local_apic.end_of_interrupt();
+    }
+}
+
+fn pit_handler(_gsi: u32) {
+    tick();
+}
+
+// Whole MHz and the remaining kHz, for printing
+fn megahertz(frequency: u64) -> (u64, u64) {
+    (frequency / 1_000_000, frequency / 1000 % 1000)
+}
+
+unsafe fn calibrate() -> Clock {
+    let hpet = crate::acpi::info().and_then(|info| info.hpet.as_ref()).and_then(|table| Hpet::init(table));
+    let (calibration_source, tsc_frequency) = without_interrupts(|| {
+        let measure = || match &hpet {
+            Some(hpet) => tsc_frequency_against_hpet(hpet),
+            None => tsc_frequency_against_pit()
+        };
+        let tsc_frequency = (0..CALIBRATION_ROUNDS).map(|_| measure()).min().unwrap();
+        (if hpet.is_some() { CalibrationSource::Hpet } else { CalibrationSource::Pit }, tsc_frequency)
+    });
+    let invariant_tsc = cpuid::has_invariant_tsc();
+    let (mhz, khz) = megahertz(tsc_frequency);
+    info!("TSC runs at {}.{:03} MHz (calibrated against the {:?} over {} ms){}",
+          mhz, khz,
+          calibration_source,
+          CALIBRATION_NS / 1_000_000,
+          if invariant_tsc { "" } else { ", not invariant, time may drift" }
+    );
+
+    let tick_source = match apic::controller() {
+        Some(apic) => {
+            set_handler(TIMER_VECTOR, local_apic_timer_handler);
+            let timer_frequency = without_interrupts(|| local_apic_timer_frequency(&apic.local_apic, tsc_frequency));
+            Some(TickSource::LocalApicTimer(timer_frequency))
+        }
+        None => match irq::register_isa_handler(pit::IRQ, pit_handler) {
+            Ok(gsi) => Some(TickSource::Pit(gsi)),
+            Err(error) => {
+                warn!("No tick, the PIT interrupt can't be registered ({:?})", error);
+                None
+            }
+        }
+    };
+
+    Clock {
+        tsc_frequency,
+        calibration_source,
+        invariant_tsc,
+        tick_source,
+        hpet
+    }
+}
+
+// Calibrates the TSC, then starts a periodic tick at TICK_FREQUENCY. Needs
+// interrupts::init_controllers.
+pub fn init() {
+    let clock = CLOCK.call_once(|| unsafe { calibrate() });
+    match clock.tick_source {
+        Some(TickSource::LocalApicTimer(frequency)) => {
+            let (mhz, khz) = megahertz(frequency);
+            info!("Tick : local APIC timer ({}.{:03} MHz) at {} Hz", mhz, khz, TICK_FREQUENCY);
+        }
+        Some(TickSource::Pit(gsi)) => info!("Tick : PIT on GSI {} at {} Hz", gsi, TICK_FREQUENCY),
+        None => return
+    }
+    start_tick(TickMode::Periodic(TICK_FREQUENCY));
+}
+
+pub fn clock() -> Option<&'static Clock> {
+    CLOCK.r#try()
+}
+
+pub fn tsc_frequency() -> Option<u64> {
+    clock().map(|clock| clock.tsc_frequency)
+}
+
+// Nanoseconds since the processor was reset, 0 before init
+pub fn now_ns() -> u64 {
+    match tsc_frequency() {
+        Some(frequency) => ticks_to_ns(unsafe { read_tsc() }, frequency),
+        None => 0
+    }
+}
+
+// Replaces the running tick, if any
+pub fn start_tick(mode: TickMode) {
+    let tick_source = match clock().and_then(|clock| clock.tick_source) {
+        Some(tick_source) => tick_source,
+        None => return
+    };
+    PERIODIC_TICK.store(matches!(mode, TickMode::Periodic(_)), Ordering::Relaxed);
+    match tick_source {
+        TickSource::LocalApicTimer(timer_frequency) => {
+            let local_apic = &apic::controller().expect("The local APIC timer is used without APIC.").local_apic;
+            let (count, periodic) = match mode {
+                TickMode::Periodic(frequency) => (timer_frequency / frequency.max(1), true),
+                TickMode::OneShot(nanoseconds) => (ns_to_ticks(nanoseconds, timer_frequency), false)
+            };
+            local_apic.start_timer(TIMER_VECTOR as u8, count.max(1).min(u32::MAX as u64) as u32, periodic);
+        }
+        TickSource::Pit(gsi) => {
+            without_interrupts(|| unsafe {
+                match mode {
+                    TickMode::Periodic(frequency) => pit::start_periodic(frequency),
+                    TickMode::OneShot(nanoseconds) => pit::start_oneshot(pit::count_for_ns(nanoseconds))
+                }
+            });
+            let _ = irq::unmask(gsi);
+        }
+    }
+}
+
+pub fn stop_tick() {
+    PERIODIC_TICK.store(false, Ordering::Relaxed);
+    match clock().and_then(|clock| clock.tick_source) {
+        Some(TickSource::LocalApicTimer(_)) => {
+            if let Some(apic) = apic::controller() {
+                apic.local_apic.stop_timer();
+            }
+        }
+        Some(TickSource::Pit(gsi)) => {
+            let _ = irq::mask(gsi);
+        }
+        None => {}
+    }
+}
+
+// Ticks received since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+// Spins for at least nanoseconds, usable with interrupts disabled
+pub fn busy_wait_ns(nanoseconds: u64) {
+    let frequency = tsc_frequency().expect("The clock isn't initialized.");
+    let start = unsafe { read_tsc() };
+    let duration = ns_to_ticks(nanoseconds, frequency);
+    while unsafe { read_tsc() } - start < duration {
+        core::hint::spin_loop();
+    }
+}
+
+pub fn busy_wait_us(microseconds: u64) {
+    busy_wait_ns(microseconds * 1000);
+}
+
+// Waits for at least nanoseconds, halting until the next tick when a periodic one is running and
+// interrupts are enabled, busy waiting otherwise
+pub fn sleep_ns(nanoseconds: u64) {
+    let frequency = tsc_frequency().expect("The clock isn't initialized.");
+    let start = unsafe { read_tsc() };
+    let duration = ns_to_ticks(nanoseconds, frequency);
+    while unsafe { read_tsc() } - start < duration {
+        if PERIODIC_TICK.load(Ordering::Relaxed) && crate::interrupts::are_enabled() {
+            unsafe { asm!("hlt", options(nomem, nostack)) };
+        } else {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+pub fn sleep_ms(milliseconds: u64) {
+    sleep_ns(milliseconds * 1_000_000);
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conversions() {
+        assert_eq!(ticks_to_ns(3_000_000_000, 3_000_000_000), 1_000_000_000);
+        assert_eq!(ticks_to_ns(u64::MAX, 1_000_000_000), u64::MAX);
+        assert_eq!(ns_to_ticks(10_000_000, 2_500_000_000), 25_000_000);
+        assert_eq!(frequency(25_000_000, 10_000_000), 2_500_000_000);
+        assert_eq!(frequency(1, 0), 1_000_000_000);
+    }
+}
+
+#[cfg(all(test, target_os = "none"))]
+mod kernel_tests {
+    use super::*;
+
+    #[test_case]
+    fn sleep_waits_long_enough() {
+        let start = now_ns();
+        sleep_ms(20);
+        let elapsed = now_ns() - start;
+        assert!(elapsed >= 20_000_000 && elapsed < 1_000_000_000, "slept {} ns", elapsed);
+    }
+
+    #[test_case]
+    fn periodic_tick_advances() {
+        if !PERIODIC_TICK.load(Ordering::Relaxed) {
+            return;
+        }
+        let start = ticks();
+        busy_wait_ns(3 * 1_000_000_000 / TICK_FREQUENCY);
+        assert!(ticks() > start);
+    }
+}
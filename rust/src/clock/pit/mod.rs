@@ -0,0 +1,87 @@
+// 8253/8254 programmable interval timer.
+//
+// Channel 0 is wired to ISA IRQ 0 and used as the tick without APICs. Channel 2 is gated through
+// port 0x61 and its output can be polled there, which makes it usable for calibration without
+// interrupts.
+
+use crate::utils::port::{inb, outb};
+
+pub const FREQUENCY: u64 = 1_193_182;
+// ISA IRQ of channel 0
+pub const IRQ: u8 = 0;
+
+const CHANNEL0_PORT: u16 = 0x40;
+const CHANNEL2_PORT: u16 = 0x42;
+const COMMAND_PORT: u16 = 0x43;
+const CHANNEL2_CONTROL_PORT: u16 = 0x61;
+
+const CONTROL_GATE2: u8 = 1 << 0;
+const CONTROL_SPEAKER: u8 = 1 << 1;
+const CONTROL_OUTPUT2: u8 = 1 << 5;
+
+// Command byte : channel, access mode (low then high byte) and operating mode
+const SELECT_CHANNEL0: u8 = 0b00 << 6;
+const SELECT_CHANNEL2: u8 = 0b10 << 6;
+const ACCESS_LOW_HIGH: u8 = 0b11 << 4;
+const MODE_INTERRUPT_ON_TERMINAL_COUNT: u8 = 0 << 1;
+const MODE_RATE_GENERATOR: u8 = 2 << 1;
+
+// A count of 0 stands for 65536
+pub const MAX_COUNT: u64 = 0x10000;
+
+// Reload value closest to frequency interrupts per second
+pub fn divisor_for_frequency(frequency: u64) -> u64 {
+    ((FREQUENCY + frequency / 2) / frequency.max(1)).max(1).min(MAX_COUNT)
+}
+
+// Count lasting about nanoseconds, at most MAX_COUNT (about 55 ms)
+pub fn count_for_ns(nanoseconds: u64) -> u64 {
+    ((nanoseconds as u128 * FREQUENCY as u128 / 1_000_000_000) as u64).max(1).min(MAX_COUNT)
+}
+
+unsafe fn write_count(port: u16, count: u64) {
+    let count = (count % MAX_COUNT) as u16;
+    outb(port, count as u8);
+    outb(port, (count >> 8) as u8);
+}
+
+// Starts channel 2 counting down from count without touching the speaker. finished tells when
+// it's done.
+pub unsafe fn start_channel2(count: u64) {
+    let control = inb(CHANNEL2_CONTROL_PORT);
+    outb(CHANNEL2_CONTROL_PORT, (control & !CONTROL_SPEAKER) | CONTROL_GATE2);
+    outb(COMMAND_PORT, SELECT_CHANNEL2 | ACCESS_LOW_HIGH | MODE_INTERRUPT_ON_TERMINAL_COUNT);
+    write_count(CHANNEL2_PORT, count);
+}
+
+pub unsafe fn channel2_finished() -> bool {
+    inb(CHANNEL2_CONTROL_PORT) & CONTROL_OUTPUT2 != 0
+}
+
+// Raises IRQ 0 frequency times per second
+pub unsafe fn start_periodic(frequency: u64) {
+    outb(COMMAND_PORT, SELECT_CHANNEL0 | ACCESS_LOW_HIGH | MODE_RATE_GENERATOR);
+    write_count(CHANNEL0_PORT, divisor_for_frequency(frequency));
+}
+
+// Raises IRQ 0 once after count periods
+pub unsafe fn start_oneshot(count: u64) {
+    outb(COMMAND_PORT, SELECT_CHANNEL0 | ACCESS_LOW_HIGH | MODE_INTERRUPT_ON_TERMINAL_COUNT);
+    write_count(CHANNEL0_PORT, count);
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts() {
+        assert_eq!(divisor_for_frequency(100), 11932);
+        assert_eq!(divisor_for_frequency(1000), 1193);
+        assert_eq!(divisor_for_frequency(1), MAX_COUNT);
+        assert_eq!(divisor_for_frequency(10_000_000), 1);
+        assert_eq!(count_for_ns(10_000_000), 11931);
+        assert_eq!(count_for_ns(1), 1);
+        assert_eq!(count_for_ns(1_000_000_000), MAX_COUNT);
+    }
+}
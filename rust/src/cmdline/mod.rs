@@ -0,0 +1,260 @@
+// Kernel command line, passed by the bootloader (KERNEL_CMDLINE in limine.cfg).
+//
+// The command line is made of whitespace separated "key=value" options and "flag" options. Values
+// can be double quoted to contain spaces. Known options are parsed into Options, which every
+// subsystem can read through options(), unknown or invalid ones are warned about and ignored.
+//
+//   loglevel=<level>    records shown on the console : error, warn, info, debug, trace or 0 to 4
+//   console=<where>     where log records go : screen, serial or both (default)
+//   heap_max=<size>     caps the kernel heap, in bytes with an optional K, M or G suffix, at
+//                       least MIN_HEAP_MAX
+//   test=<filter>       only runs the in-kernel tests whose name contains filter
+//   nofbconsole         stays on the VGA text console even when there is a framebuffer
+//   noapic              uses the legacy 8259 PICs even when there are APICs
+
+use crate::log::Level;
+use crate::stivale2::{CmdlineTag, STRUCT_TAG_CMDLINE_ID};
+use spin::Once;
+
+// Smallest heap_max accepted, the kernel can't boot with less
+pub const MIN_HEAP_MAX: usize = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleOutput {
+    Screen,
+    Serial,
+    Both
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Options {
+    pub loglevel: Option<Level>,
+    pub console: ConsoleOutput,
+    pub heap_max: Option<usize>,
+    pub test_filter: Option<&'static str>,
+    pub framebuffer_console: bool,
+    pub apic: bool
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptionError {
+    Unknown,
+    MissingValue,
+    UnexpectedValue,
+    InvalidValue
+}
+
+impl Options {
+    pub const fn new() -> Options {
+        Options {
+            loglevel: None,
+            console: ConsoleOutput::Both,
+            heap_max: None,
+            test_filter: None,
+            framebuffer_console: true,
+            apic: true
+        }
+    }
+
+    // Applies a single option, value being None for flags
+    pub fn apply(&mut self, key: &str, value: Option<&'static str>) -> Result<(), OptionError> {
+        match (key, value) {
+            ("loglevel", Some(value)) => self.loglevel = Some(parse_level(value)?),
+            ("console", Some(value)) => self.console = match value {
+                "screen" => ConsoleOutput::Screen,
+                "serial" => ConsoleOutput::Serial,
+                "both" => ConsoleOutput::Both,
+                _ => return Err(OptionError::InvalidValue)
+            },
+            ("heap_max", Some(value)) => self.heap_max = match parse_size(value)? {
+                size if size >= MIN_HEAP_MAX => Some(size),
+                _ => return Err(OptionError::InvalidValue)
+            },
+            ("test", Some(value)) => self.test_filter = Some(value),
+            ("nofbconsole", None) => self.framebuffer_console = false,
+            ("noapic", None) => self.apic = false,
+            ("loglevel", None) | ("console", None) | ("heap_max", None) | ("test", None) => {
+                return Err(OptionError::MissingValue)
+            }
+            ("nofbconsole", Some(_)) | ("noapic", Some(_)) => return Err(OptionError::UnexpectedValue),
+            _ => return Err(OptionError::Unknown)
+        }
+        Ok(())
+    }
+
+    // Parses a whole command line, warning about the options that can't be applied
+    pub fn parse(cmdline: &'static str) -> Options {
+        let mut options = Options::new();
+        for (key, value) in tokens(cmdline) {
+            if let Err(error) = options.apply(key, value) {
+                match value {
+                    Some(value) => warn!("Ignoring command line option {}={} ({:?})", key, value, error),
+                    None => warn!("Ignoring command line option {} ({:?})", key, error)
+                }
+            }
+        }
+        options
+    }
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options::new()
+    }
+}
+
+fn parse_level(value: &str) -> Result<Level, OptionError> {
+    match value {
+        "error" | "0" => Ok(Level::Error),
+        "warn" | "1" => Ok(Level::Warn),
+        "info" | "2" => Ok(Level::Info),
+        "debug" | "3" => Ok(Level::Debug),
+        "trace" | "4" => Ok(Level::Trace),
+        _ => Err(OptionError::InvalidValue)
+    }
+}
+
+// Bytes, with an optional binary K, M or G suffix
+pub fn parse_size(value: &str) -> Result<usize, OptionError> {
+    let (digits, multiplier) = match value.as_bytes().last() {
+        Some(b'K') | Some(b'k') => (&value[..value.len() - 1], 1 << 10),
+        Some(b'M') | Some(b'm') => (&value[..value.len() - 1], 1 << 20),
+        Some(b'G') | Some(b'g') => (&value[..value.len() - 1], 1 << 30),
+        _ => (value, 1)
+    };
+    digits.parse::<usize>().ok()
+        .and_then(|size| size.checked_mul(multiplier))
+        .ok_or(OptionError::InvalidValue)
+}
+
+// Splits a command line into (key, value) pairs, value being None for flags. Quotes around a
+// value are removed, an unterminated quote runs to the end of the line.
+pub fn tokens(cmdline: &str) -> impl Iterator<Item = (&str, Option<&str>)> {
+    let mut rest = cmdline;
+    core::iter::from_fn(move || {
+        rest = rest.trim_start();
+        if rest.is_empty() {
+            return None;
+        }
+
+        let key_end = rest.find(|c: char| c == '=' || c.is_whitespace()).unwrap_or(rest.len());
+        let key = &rest[..key_end];
+        rest = &rest[key_end..];
+        if !rest.starts_with('=') {
+            return Some((key, None));
+        }
+        rest = &rest[1..];
+
+        let value = if rest.starts_with('"') {
+            let quoted = &rest[1..];
+            let end = quoted.find('"').unwrap_or(quoted.len());
+            rest = &quoted[(end + 1).min(quoted.len())..];
+            &quoted[..end]
+        } else {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            let value = &rest[..end];
+            rest = &rest[end..];
+            value
+        };
+        Some((key, Some(value)))
+    })
+}
+
+static CMDLINE: Once<&'static str> = Once::new();
+static OPTIONS: Once<Options> = Once::new();
+
+// The raw command line, empty when the bootloader didn't give one. It is a nul terminated string
+// in bootloader reclaimable memory, which stays mapped.
+pub fn cmdline() -> &'static str {
+    CMDLINE.call_once(|| {
+        let address = match unsafe { crate::stivale2::read_tag::<CmdlineTag>(STRUCT_TAG_CMDLINE_ID) } {
+            Some(tag) if tag.cmdline != 0 => tag.cmdline as usize,
+            _ => return ""
+        };
+        let bytes = unsafe {
+            let length = (0..).take_while(|&i| *((address + i) as *const u8) != 0).count();
+            core::slice::from_raw_parts(address as *const u8, length)
+        };
+        core::str::from_utf8(bytes).unwrap_or_else(|_| {
+            warn!("The kernel command line is not valid UTF-8, ignoring it");
+            ""
+        })
+    })
+}
+
+// Parses the command line, called once stivale2::init was
+pub fn init() -> &'static Options {
+    OPTIONS.call_once(|| {
+        let cmdline = cmdline();
+        if !cmdline.is_empty() {
+            info!("Command line : {}", cmdline);
+        }
+        Options::parse(cmdline)
+    })
+}
+
+// Defaults until init is called
+pub fn options() -> &'static Options {
+    static DEFAULT: Options = Options::new();
+    OPTIONS.r#try().unwrap_or(&DEFAULT)
+}
+
+// Raw value of an option, for options not in Options
+pub fn get(key: &str) -> Option<Option<&'static str>> {
+    tokens(cmdline()).find(|&(token_key, _)| token_key == key).map(|(_, value)| value)
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    #[test]
+    fn tokens_are_split() {
+        let tokens: Vec<_> = tokens("  loglevel=debug nofbconsole test=\"heap tests\" a= b=c=d ").collect();
+        assert_eq!(tokens, vec![
+            ("loglevel", Some("debug")),
+            ("nofbconsole", None),
+            ("test", Some("heap tests")),
+            ("a", Some("")),
+            ("b", Some("c=d"))
+        ]);
+        assert_eq!(super::tokens("").count(), 0);
+        assert_eq!(super::tokens("test=\"unterminated").collect::<Vec<_>>(), vec![("test", Some("unterminated"))]);
+    }
+
+    #[test]
+    fn options_are_typed() {
+        let mut options = Options::new();
+        options.apply("loglevel", Some("3")).unwrap();
+        options.apply("console", Some("serial")).unwrap();
+        options.apply("heap_max", Some("64M")).unwrap();
+        options.apply("test", Some("heap")).unwrap();
+        options.apply("nofbconsole", None).unwrap();
+        options.apply("noapic", None).unwrap();
+        assert_eq!(options, Options {
+            loglevel: Some(Level::Debug),
+            console: ConsoleOutput::Serial,
+            heap_max: Some(64 * 1024 * 1024),
+            test_filter: Some("heap"),
+            framebuffer_console: false,
+            apic: false
+        });
+    }
+
+    #[test]
+    fn bad_options_are_rejected() {
+        let mut options = Options::new();
+        assert_eq!(options.apply("frobnicate", None), Err(OptionError::Unknown));
+        assert_eq!(options.apply("loglevel", Some("loud")), Err(OptionError::InvalidValue));
+        assert_eq!(options.apply("loglevel", None), Err(OptionError::MissingValue));
+        assert_eq!(options.apply("nofbconsole", Some("1")), Err(OptionError::UnexpectedValue));
+        assert_eq!(options.apply("heap_max", Some("12X")), Err(OptionError::InvalidValue));
+        assert_eq!(options.apply("heap_max", Some("0")), Err(OptionError::InvalidValue));
+        assert_eq!(options.apply("heap_max", Some("4K")), Err(OptionError::InvalidValue));
+        assert_eq!(options, Options::new());
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("2g"), Ok(2 << 30));
+        assert_eq!(parse_size("99999999999999999999G"), Err(OptionError::InvalidValue));
+    }
+}
@@ -0,0 +1,67 @@
+// QEMU / Bochs debug console : every byte written to port 0xe9 is printed by the emulator
+// (qemu -debugcon stdio). It needs no initialisation, so it works from the very first instruction.
+
+use crate::log::LogSink;
+use crate::utils::port::{inb, outb};
+use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+pub const DEBUGCON_PORT: u16 = 0xe9;
+
+const UNKNOWN: u8 = 0;
+const PRESENT: u8 = 1;
+const ABSENT: u8 = 2;
+
+static STATE: AtomicU8 = AtomicU8::new(UNKNOWN);
+
+// Reading the port returns 0xe9 when the debug console is there, so nothing is written to an
+// unrelated device on real hardware
+pub fn is_present() -> bool {
+    match STATE.load(Ordering::Relaxed) {
+        PRESENT => true,
+        ABSENT => false,
+        _ => {
+            let present = unsafe { inb(DEBUGCON_PORT) } == 0xe9;
+            STATE.store(if present { PRESENT } else { ABSENT }, Ordering::Relaxed);
+            present
+        }
+    }
+}
+
+pub fn write_bytes(bytes: &[u8]) {
+    if !is_present() {
+        return;
+    }
+    for &byte in bytes {
+        unsafe { outb(DEBUGCON_PORT, byte) };
+    }
+}
+
+// Stateless, so unlike the VGA writer or the serial port it can't be left locked by a panic
+pub struct DebugCon;
+
+impl fmt::Write for DebugCon {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        write_bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+pub fn print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    DebugCon.write_fmt(args).unwrap();
+}
+
+pub struct DebugConSink;
+
+impl LogSink for DebugConSink {
+    fn name(&self) -> &'static str {
+        "debugcon"
+    }
+
+    fn write_str(&self, s: &str) {
+        write_bytes(s.as_bytes());
+    }
+}
+
+pub static DEBUGCON_SINK: DebugConSink = DebugConSink;
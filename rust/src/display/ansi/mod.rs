@@ -0,0 +1,335 @@
+// ANSI escape sequence parsing for the text consoles.
+//
+// Bytes go through AnsiParser, which hands back characters to print and complete CSI sequences
+// (ESC [ params final). Consoles then apply the sequences they support : SGR colours through
+// TextAttributes, cursor movement and erasing through the CsiSequence accessors.
+
+use crate::display::vga::{Color, DEFAULT_BACKGROUND, DEFAULT_FOREGROUND};
+
+pub const ESCAPE: u8 = 0x1b;
+pub const MAX_PARAMS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    Csi
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CsiSequence {
+    params: [u16; MAX_PARAMS],
+    param_count: usize,
+    // Sequences starting with '?', like the DEC private modes
+    pub private: bool,
+    pub final_byte: u8
+}
+
+impl CsiSequence {
+    const fn new() -> CsiSequence {
+        CsiSequence {
+            params: [0; MAX_PARAMS],
+            param_count: 0,
+            private: false,
+            final_byte: 0
+        }
+    }
+
+    pub fn params(&self) -> &[u16] {
+        &self.params[..self.param_count]
+    }
+
+    // Missing and zero parameters both take the default value, as cursor movements expect
+    pub fn param_or(&self, index: usize, default: u16) -> u16 {
+        match self.params().get(index) {
+            Some(&value) if value != 0 => value,
+            _ => default
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnsiAction {
+    // Printable characters and control characters other than ESC
+    Print(u8),
+    Csi(CsiSequence)
+}
+
+pub struct AnsiParser {
+    state: State,
+    sequence: CsiSequence,
+    // Whether a digit was read for the current parameter, so "1;;2" gives an empty middle one
+    in_param: bool,
+    // Set once a parameter past MAX_PARAMS starts, the remaining ones are dropped
+    params_overflowed: bool
+}
+
+impl AnsiParser {
+    pub const fn new() -> AnsiParser {
+        AnsiParser {
+            state: State::Ground,
+            sequence: CsiSequence::new(),
+            in_param: false,
+            params_overflowed: false
+        }
+    }
+
+    pub fn feed(&mut self, byte: u8) -> Option<AnsiAction> {
+        match self.state {
+            State::Ground => {
+                if byte == ESCAPE {
+                    self.state = State::Escape;
+                    None
+                }
+                else {
+                    Some(AnsiAction::Print(byte))
+                }
+            }
+            State::Escape => {
+                if byte == b'[' {
+                    self.sequence = CsiSequence::new();
+                    self.in_param = false;
+                    self.params_overflowed = false;
+                    self.state = State::Csi;
+                }
+                else {
+                    // Other escapes aren't supported and are dropped
+                    self.state = State::Ground;
+                }
+                None
+            }
+            State::Csi => self.feed_csi(byte)
+        }
+    }
+
+    fn start_param(&mut self) {
+        if self.sequence.param_count < MAX_PARAMS {
+            self.sequence.param_count += 1;
+        }
+        else {
+            self.params_overflowed = true;
+        }
+    }
+
+    fn feed_csi(&mut self, byte: u8) -> Option<AnsiAction> {
+        match byte {
+            b'0'..=b'9' => {
+                if !self.in_param {
+                    self.start_param();
+                    self.in_param = true;
+                }
+                if !self.params_overflowed {
+                    let sequence = &mut self.sequence;
+                    let param = &mut sequence.params[sequence.param_count - 1];
+                    *param = param.saturating_mul(10).saturating_add((byte - b'0') as u16);
+                }
+                None
+            }
+            b';' => {
+                if !self.in_param {
+                    self.start_param();
+                }
+                self.in_param = false;
+                None
+            }
+            b'?' => {
+                self.sequence.private = true;
+                None
+            }
+            // Final bytes
+            0x40..=0x7e => {
+                self.sequence.final_byte = byte;
+                self.state = State::Ground;
+                Some(AnsiAction::Csi(self.sequence))
+            }
+            // Intermediate bytes are ignored
+            0x20..=0x3f => None,
+            // Anything else aborts the sequence
+            _ => {
+                self.state = State::Ground;
+                None
+            }
+        }
+    }
+}
+
+impl Default for AnsiParser {
+    fn default() -> AnsiParser {
+        AnsiParser::new()
+    }
+}
+
+// The 8 ANSI colours in VGA order, and their bright variants
+const ANSI_COLORS: [Color; 8] = [
+    Color::Black, Color::Red, Color::Green, Color::Brown,
+    Color::Blue, Color::Magenta, Color::Cyan, Color::LightGray
+];
+const BRIGHT_ANSI_COLORS: [Color; 8] = [
+    Color::DarkGray, Color::LightRed, Color::LightGreen, Color::Yellow,
+    Color::LightBlue, Color::Pink, Color::LightCyan, Color::White
+];
+
+pub fn ansi_color(index: u16, bright: bool) -> Color {
+    if bright {
+        BRIGHT_ANSI_COLORS[index as usize % 8]
+    }
+    else {
+        ANSI_COLORS[index as usize % 8]
+    }
+}
+
+fn brighten(color: Color) -> Color {
+    match ANSI_COLORS.iter().position(|&ansi| ansi == color) {
+        Some(index) => BRIGHT_ANSI_COLORS[index],
+        None => color
+    }
+}
+
+fn dim(color: Color) -> Color {
+    match BRIGHT_ANSI_COLORS.iter().position(|&bright| bright == color) {
+        Some(index) => ANSI_COLORS[index],
+        None => color
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TextAttributes {
+    pub foreground: Color,
+    pub background: Color,
+    // The 16 colour palette has no bold font, bold brightens the foreground instead
+    pub bold: bool,
+    // Whether the foreground was brightened by bold, and so goes back to normal without it
+    brightened: bool
+}
+
+impl TextAttributes {
+    pub const fn new(foreground: Color, background: Color) -> TextAttributes {
+        TextAttributes { foreground, background, bold: false, brightened: false }
+    }
+
+    pub fn apply_sgr(&mut self, sequence: &CsiSequence) {
+        // ESC[m is the same as ESC[0m
+        if sequence.params().is_empty() {
+            *self = TextAttributes::new(DEFAULT_FOREGROUND, DEFAULT_BACKGROUND);
+            return;
+        }
+
+        for &param in sequence.params() {
+            match param {
+                0 => *self = TextAttributes::new(DEFAULT_FOREGROUND, DEFAULT_BACKGROUND),
+                1 if !self.bold => {
+                    self.bold = true;
+                    let foreground = brighten(self.foreground);
+                    self.brightened = foreground != self.foreground;
+                    self.foreground = foreground;
+                }
+                22 => {
+                    if self.brightened {
+                        self.foreground = dim(self.foreground);
+                    }
+                    self.bold = false;
+                    self.brightened = false;
+                }
+                30..=37 => {
+                    self.foreground = ansi_color(param - 30, self.bold);
+                    self.brightened = self.bold;
+                }
+                39 => {
+                    self.foreground = DEFAULT_FOREGROUND;
+                    self.brightened = false;
+                }
+                40..=47 => self.background = ansi_color(param - 40, false),
+                49 => self.background = DEFAULT_BACKGROUND,
+                90..=97 => {
+                    self.foreground = ansi_color(param - 90, true);
+                    self.brightened = false;
+                }
+                100..=107 => self.background = ansi_color(param - 100, true),
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    fn feed(parser: &mut AnsiParser, bytes: &[u8]) -> Vec<AnsiAction> {
+        bytes.iter().filter_map(|&byte| parser.feed(byte)).collect()
+    }
+
+    fn csi(bytes: &[u8]) -> CsiSequence {
+        match feed(&mut AnsiParser::new(), bytes).as_slice() {
+            [AnsiAction::Csi(sequence)] => *sequence,
+            actions => panic!("Expected a single CSI sequence, got {:?}", actions)
+        }
+    }
+
+    #[test]
+    fn text_around_sequences_is_printed() {
+        let actions = feed(&mut AnsiParser::new(), b"a\x1b[31mb\n");
+        assert_eq!(actions.len(), 4);
+        assert_eq!(actions[0], AnsiAction::Print(b'a'));
+        assert!(matches!(actions[1], AnsiAction::Csi(CsiSequence { final_byte: b'm', .. })));
+        assert_eq!(actions[2], AnsiAction::Print(b'b'));
+        assert_eq!(actions[3], AnsiAction::Print(b'\n'));
+    }
+
+    #[test]
+    fn parameters_are_parsed() {
+        let sequence = csi(b"\x1b[12;;40H");
+        assert_eq!(sequence.params(), &[12, 0, 40]);
+        assert_eq!(sequence.param_or(1, 1), 1);
+        assert_eq!(sequence.final_byte, b'H');
+
+        let sequence = csi(b"\x1b[?25l");
+        assert!(sequence.private);
+        assert_eq!(sequence.params(), &[25]);
+    }
+
+    #[test]
+    fn extra_parameters_are_dropped() {
+        let sequence = csi(b"\x1b[1;2;3;4;5;6;7;8;9;10m");
+        assert_eq!(sequence.params(), &[1, 2, 3, 4, 5, 6, 7, 8]);
+
+        let sequence = csi(b"\x1b[1;2;3;4;5;6;7;;;9m");
+        assert_eq!(sequence.params(), &[1, 2, 3, 4, 5, 6, 7, 0]);
+
+        // The next sequence gets all its parameters again
+        let mut parser = AnsiParser::new();
+        feed(&mut parser, b"\x1b[1;2;3;4;5;6;7;8;9m");
+        match feed(&mut parser, b"\x1b[4;5H").as_slice() {
+            [AnsiAction::Csi(sequence)] => assert_eq!(sequence.params(), &[4, 5]),
+            actions => panic!("Expected a single CSI sequence, got {:?}", actions)
+        }
+    }
+
+    #[test]
+    fn sgr_sets_colors() {
+        let mut attributes = TextAttributes::new(DEFAULT_FOREGROUND, DEFAULT_BACKGROUND);
+        attributes.apply_sgr(&csi(b"\x1b[1;31;44m"));
+        assert_eq!(attributes.foreground, Color::LightRed);
+        assert_eq!(attributes.background, Color::Blue);
+
+        attributes.apply_sgr(&csi(b"\x1b[0m"));
+        assert_eq!(attributes, TextAttributes::new(DEFAULT_FOREGROUND, DEFAULT_BACKGROUND));
+
+        attributes.apply_sgr(&csi(b"\x1b[1;31m"));
+        assert_eq!(attributes.foreground, Color::LightRed);
+        attributes.apply_sgr(&csi(b"\x1b[22m"));
+        assert_eq!(attributes.foreground, Color::Red);
+        assert!(!attributes.bold);
+
+        // Colours that were bright on their own stay so
+        attributes.apply_sgr(&csi(b"\x1b[0;1;22m"));
+        assert_eq!(attributes.foreground, DEFAULT_FOREGROUND);
+        attributes.apply_sgr(&csi(b"\x1b[1;91;22m"));
+        assert_eq!(attributes.foreground, Color::LightRed);
+
+        attributes.apply_sgr(&csi(b"\x1b[93;100m"));
+        assert_eq!(attributes.foreground, Color::Yellow);
+        assert_eq!(attributes.background, Color::DarkGray);
+    }
+}
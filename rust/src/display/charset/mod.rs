@@ -0,0 +1,140 @@
+// Character set handling for the VGA text console : UTF-8 decoding, and translation of code points
+// to the Code Page 437 glyphs of the VGA font.
+
+// Glyph drawn for characters CP437 has no glyph for (a small square)
+pub const REPLACEMENT_GLYPH: u8 = 0xfe;
+
+// Glyphs 0x01 to 0x1f, which are only reachable through their Unicode code points since these
+// bytes are control characters
+const CP437_LOW: [char; 31] = [
+    '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼',
+    '►', '◄', '↕', '‼', '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼'
+];
+
+// Glyphs 0x80 to 0xff
+const CP437_HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}'
+];
+
+// Returns the CP437 glyph for a printable character
+pub fn to_cp437(character: char) -> Option<u8> {
+    match character {
+        ' '..='~' => Some(character as u8),
+        '⌂' => Some(0x7f),
+        // Look-alikes sharing a glyph
+        'β' => Some(0xe1),
+        'μ' => Some(0xe6),
+        '∑' => Some(0xe4),
+        '∈' => Some(0xee),
+        _ => CP437_LOW.iter().position(|&glyph| glyph == character).map(|index| index as u8 + 0x01)
+            .or_else(|| CP437_HIGH.iter().position(|&glyph| glyph == character).map(|index| index as u8 + 0x80))
+    }
+}
+
+// Decodes UTF-8 one byte at a time. Invalid sequences give U+FFFD.
+pub struct Utf8Decoder {
+    code_point: u32,
+    remaining: u8,
+    // Smallest code point for the current sequence length, anything below is an overlong encoding
+    minimum: u32
+}
+
+impl Utf8Decoder {
+    pub const fn new() -> Utf8Decoder {
+        Utf8Decoder {
+            code_point: 0,
+            remaining: 0,
+            minimum: 0
+        }
+    }
+
+    // A byte can end an invalid sequence and start a new character at once, hence the two results
+    pub fn feed(&mut self, byte: u8) -> [Option<char>; 2] {
+        if self.remaining > 0 {
+            if byte & 0xc0 == 0x80 {
+                self.code_point = (self.code_point << 6) | (byte & 0x3f) as u32;
+                self.remaining -= 1;
+                if self.remaining > 0 {
+                    return [None, None];
+                }
+                let decoded = if self.code_point < self.minimum {
+                    None
+                }
+                else {
+                    core::char::from_u32(self.code_point)
+                };
+                return [Some(decoded.unwrap_or(char::REPLACEMENT_CHARACTER)), None];
+            }
+
+            // Truncated sequence, the byte starts something new
+            self.remaining = 0;
+            return [Some(char::REPLACEMENT_CHARACTER), self.start(byte)];
+        }
+
+        [self.start(byte), None]
+    }
+
+    fn start(&mut self, byte: u8) -> Option<char> {
+        let (code_point, remaining, minimum) = match byte {
+            0x00..=0x7f => return Some(byte as char),
+            0xc0..=0xdf => (byte & 0x1f, 1, 0x80),
+            0xe0..=0xef => (byte & 0x0f, 2, 0x800),
+            0xf0..=0xf7 => (byte & 0x07, 3, 0x10000),
+            // Stray continuation bytes and invalid lead bytes
+            _ => return Some(char::REPLACEMENT_CHARACTER)
+        };
+        self.code_point = code_point as u32;
+        self.remaining = remaining;
+        self.minimum = minimum;
+        None
+    }
+}
+
+impl Default for Utf8Decoder {
+    fn default() -> Utf8Decoder {
+        Utf8Decoder::new()
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use std::string::String;
+
+    fn decode(bytes: &[u8]) -> String {
+        let mut decoder = Utf8Decoder::new();
+        bytes.iter().flat_map(|&byte| decoder.feed(byte)).flatten().collect()
+    }
+
+    #[test]
+    fn valid_utf8_is_decoded() {
+        let text = "a é ─ € 🦀";
+        assert_eq!(decode(text.as_bytes()), text);
+    }
+
+    #[test]
+    fn invalid_utf8_is_replaced() {
+        // Stray continuation byte, truncated sequence followed by ASCII, overlong '/', surrogate
+        assert_eq!(decode(b"\x80a"), "\u{fffd}a");
+        assert_eq!(decode(b"\xe2\x94b"), "\u{fffd}b");
+        assert_eq!(decode(b"\xc0\xaf"), "\u{fffd}");
+        assert_eq!(decode(b"\xed\xa0\x80"), "\u{fffd}");
+    }
+
+    #[test]
+    fn characters_are_translated_to_cp437() {
+        assert_eq!(to_cp437('A'), Some(b'A'));
+        assert_eq!(to_cp437('é'), Some(0x82));
+        assert_eq!(to_cp437('╔'), Some(0xc9));
+        assert_eq!(to_cp437('Σ'), Some(0xe4));
+        assert_eq!(to_cp437('☺'), Some(0x01));
+        assert_eq!(to_cp437('€'), None);
+    }
+}
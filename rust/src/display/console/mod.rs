@@ -0,0 +1,140 @@
+// Text console drawn on the linear framebuffer, behaving like the VGA text mode writer : the
+// cursor and escape sequences are handled by display::text_grid, characters are drawn with a PSF
+// font (display::font), through its Unicode table when it has one.
+
+use crate::display::ansi::TextAttributes;
+use crate::display::font::PsfFont;
+use crate::display::framebuffer::{Framebuffer, Rgb};
+use crate::display::text_grid::{CellBackend, TextGrid};
+use crate::display::vga::Color;
+use core::fmt;
+
+// Character cells of the font's size laid out from the top left corner of the framebuffer
+pub struct GlyphCells {
+    framebuffer: Framebuffer,
+    font: PsfFont<'static>,
+    columns: usize,
+    rows: usize
+}
+
+impl CellBackend for GlyphCells {
+    fn rows(&self) -> usize {
+        self.rows
+    }
+
+    fn columns(&self) -> usize {
+        self.columns
+    }
+
+    fn put_cell(&mut self, row: usize, col: usize, character: char, attributes: TextAttributes) {
+        let foreground = Rgb::from(attributes.foreground);
+        let background = Rgb::from(attributes.background);
+        let (width, height) = (self.font.width, self.font.height);
+        let x = col * width;
+        let y = row * height;
+        let glyph = self.font.glyph(self.font.lookup_or_replacement(character));
+        for glyph_row in 0..height {
+            for glyph_col in 0..width {
+                let set = self.font.pixel(glyph, glyph_col, glyph_row);
+                self.framebuffer.put_pixel(x + glyph_col, y + glyph_row, if set { foreground } else { background });
+            }
+        }
+    }
+
+    fn clear_cells(&mut self, row: usize, start_col: usize, end_col: usize, attributes: TextAttributes) {
+        let (width, height) = (self.font.width, self.font.height);
+        self.framebuffer.fill_rect(
+            start_col * width,
+            row * height,
+            (end_col - start_col) * width,
+            height,
+            Rgb::from(attributes.background)
+        );
+    }
+
+    fn scroll(&mut self, attributes: TextAttributes) {
+        let height = self.font.height;
+        self.framebuffer.scroll_up(height, Rgb::from(attributes.background));
+    }
+
+    // Includes the margins the cells don't cover
+    fn clear(&mut self, attributes: TextAttributes) {
+        let (width, height) = (self.framebuffer.width, self.framebuffer.height);
+        self.framebuffer.fill_rect(0, 0, width, height, Rgb::from(attributes.background));
+    }
+}
+
+pub struct FramebufferConsole {
+    grid: TextGrid<GlyphCells>
+}
+
+impl FramebufferConsole {
+    // The font has to fit on the framebuffer, see fits
+    pub fn new(framebuffer: Framebuffer, font: PsfFont<'static>) -> FramebufferConsole {
+        assert!(FramebufferConsole::fits(&framebuffer, &font), "The console font is larger than the framebuffer.");
+        let cells = GlyphCells {
+            columns: framebuffer.width / font.width,
+            rows: framebuffer.height / font.height,
+            framebuffer,
+            font
+        };
+        let mut console = FramebufferConsole {
+            grid: TextGrid::new(cells, 0)
+        };
+        console.clear_screen();
+        console.flush();
+        console
+    }
+
+    // Whether at least one character fits on the framebuffer
+    pub fn fits(framebuffer: &Framebuffer, font: &PsfFont) -> bool {
+        font.width <= framebuffer.width && font.height <= framebuffer.height
+    }
+
+    pub fn columns(&self) -> usize {
+        self.grid.backend().columns
+    }
+
+    pub fn rows(&self) -> usize {
+        self.grid.backend().rows
+    }
+
+    // For drawing around the text, e.g. status panels
+    pub fn framebuffer(&mut self) -> &mut Framebuffer {
+        &mut self.grid.backend_mut().framebuffer
+    }
+
+    // Shows what was written since the last flush, when the framebuffer has a back buffer
+    pub fn flush(&mut self) {
+        self.framebuffer().flush();
+    }
+
+    pub fn write_str(&mut self, s: &str) {
+        for byte in s.bytes() {
+            self.write_byte(byte)
+        }
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        self.grid.write_byte(byte);
+    }
+
+    pub fn clear_screen(&mut self) {
+        self.grid.clear_screen();
+    }
+
+    pub fn set_position(&mut self, row: usize, col: usize) {
+        self.grid.set_position(row, col);
+    }
+
+    pub fn set_color(&mut self, foreground: Color, background: Color) {
+        self.grid.set_color(foreground, background);
+    }
+}
+
+impl fmt::Write for FramebufferConsole {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        FramebufferConsole::write_str(self, s);
+        Ok(())
+    }
+}
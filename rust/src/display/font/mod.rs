@@ -0,0 +1,335 @@
+// PC Screen Font (PSF) parsing, for the framebuffer console. Both versions are supported :
+// - PSF1 : 8 pixel wide glyphs, 256 or 512 of them, optional UCS-2 Unicode table
+// - PSF2 : any glyph size, optional UTF-8 Unicode table
+// Glyph rows are padded to whole bytes with the leftmost pixel in the top bit.
+//
+// The default font is built in, in Code Page 437 order like the VGA text mode font. Another one
+// can be passed as a boot module with the "font" string, see load_boot_font.
+
+use crate::display::charset::to_cp437;
+use alloc::collections::BTreeMap;
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_MODE_512: u8 = 0x01;
+const PSF1_MODE_HAS_TABLE: u8 = 0x02;
+const PSF1_MODE_HAS_SEQUENCES: u8 = 0x04;
+const PSF1_HEADER_SIZE: usize = 4;
+const PSF1_SEPARATOR: u16 = 0xffff;
+const PSF1_SEQUENCE_START: u16 = 0xfffe;
+
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xb5, 0x4a, 0x86];
+const PSF2_HAS_UNICODE_TABLE: u32 = 0x01;
+const PSF2_HEADER_SIZE: usize = 32;
+const PSF2_SEPARATOR: u8 = 0xff;
+const PSF2_SEQUENCE_START: u8 = 0xfe;
+
+// Module string a replacement font is passed with
+pub const FONT_MODULE_STRING: &str = "font";
+
+static DEFAULT_FONT: &[u8] = include_bytes!("default8x16.psf");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FontError {
+    BadMagic,
+    Truncated,
+    UnsupportedVersion(u32),
+    EmptyGlyphs
+}
+
+pub struct PsfFont<'a> {
+    glyphs: &'a [u8],
+    pub width: usize,
+    pub height: usize,
+    pub glyph_count: usize,
+    bytes_per_row: usize,
+    bytes_per_glyph: usize,
+    // Code point to glyph index, None when the font has no table in which case glyphs are
+    // assumed to be in CP437 order
+    unicode: Option<BTreeMap<char, usize>>
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+impl<'a> PsfFont<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<PsfFont<'a>, FontError> {
+        if data.starts_with(&PSF2_MAGIC) {
+            PsfFont::parse_psf2(data)
+        } else if data.starts_with(&PSF1_MAGIC) {
+            PsfFont::parse_psf1(data)
+        } else {
+            Err(FontError::BadMagic)
+        }
+    }
+
+    fn parse_psf1(data: &'a [u8]) -> Result<PsfFont<'a>, FontError> {
+        if data.len() < PSF1_HEADER_SIZE {
+            return Err(FontError::Truncated);
+        }
+        let mode = data[2];
+        let height = data[3] as usize;
+        let glyph_count = if mode & PSF1_MODE_512 != 0 { 512 } else { 256 };
+        let glyphs_end = PSF1_HEADER_SIZE + glyph_count * height;
+        if height == 0 {
+            return Err(FontError::EmptyGlyphs);
+        }
+        if data.len() < glyphs_end {
+            return Err(FontError::Truncated);
+        }
+
+        let unicode = if mode & (PSF1_MODE_HAS_TABLE | PSF1_MODE_HAS_SEQUENCES) != 0 {
+            Some(parse_psf1_table(&data[glyphs_end..], glyph_count))
+        } else {
+            None
+        };
+
+        Ok(PsfFont {
+            glyphs: &data[PSF1_HEADER_SIZE..glyphs_end],
+            width: 8,
+            height,
+            glyph_count,
+            bytes_per_row: 1,
+            bytes_per_glyph: height,
+            unicode
+        })
+    }
+
+    fn parse_psf2(data: &'a [u8]) -> Result<PsfFont<'a>, FontError> {
+        if data.len() < PSF2_HEADER_SIZE {
+            return Err(FontError::Truncated);
+        }
+        let version = read_u32(data, 4);
+        if version != 0 {
+            return Err(FontError::UnsupportedVersion(version));
+        }
+        let header_size = read_u32(data, 8) as usize;
+        let flags = read_u32(data, 12);
+        let glyph_count = read_u32(data, 16) as usize;
+        let bytes_per_glyph = read_u32(data, 20) as usize;
+        let height = read_u32(data, 24) as usize;
+        let width = read_u32(data, 28) as usize;
+
+        let bytes_per_row = (width + 7) / 8;
+        if glyph_count == 0 || width == 0 || height == 0 {
+            return Err(FontError::EmptyGlyphs);
+        }
+        if bytes_per_glyph < bytes_per_row * height {
+            return Err(FontError::Truncated);
+        }
+        let glyphs_end = glyph_count.checked_mul(bytes_per_glyph)
+            .and_then(|size| size.checked_add(header_size))
+            .ok_or(FontError::Truncated)?;
+        if header_size < PSF2_HEADER_SIZE || data.len() < glyphs_end {
+            return Err(FontError::Truncated);
+        }
+
+        let unicode = if flags & PSF2_HAS_UNICODE_TABLE != 0 {
+            Some(parse_psf2_table(&data[glyphs_end..], glyph_count))
+        } else {
+            None
+        };
+
+        Ok(PsfFont {
+            glyphs: &data[header_size..glyphs_end],
+            width,
+            height,
+            glyph_count,
+            bytes_per_row,
+            bytes_per_glyph,
+            unicode
+        })
+    }
+
+    pub fn has_unicode_table(&self) -> bool {
+        self.unicode.is_some()
+    }
+
+    // Glyph index for a character, if the font can show it
+    pub fn lookup(&self, character: char) -> Option<usize> {
+        let index = match &self.unicode {
+            Some(table) => table.get(&character).cloned(),
+            None => to_cp437(character).map(|cp437| cp437 as usize)
+        };
+        index.filter(|&index| index < self.glyph_count)
+    }
+
+    // Glyph index for a character, falling back on the replacement character then '?'
+    pub fn lookup_or_replacement(&self, character: char) -> usize {
+        self.lookup(character)
+            .or_else(|| self.lookup(char::REPLACEMENT_CHARACTER))
+            .or_else(|| self.lookup('?'))
+            .unwrap_or(0)
+    }
+
+    pub fn glyph(&self, index: usize) -> &'a [u8] {
+        let start = index * self.bytes_per_glyph;
+        &self.glyphs[start..start + self.bytes_per_glyph]
+    }
+
+    // Whether the pixel at (x, y) of a glyph returned by glyph() is set
+    pub fn pixel(&self, glyph: &[u8], x: usize, y: usize) -> bool {
+        glyph[y * self.bytes_per_row + x / 8] & (0x80 >> (x % 8)) != 0
+    }
+}
+
+// One entry per glyph, each a list of UCS-2 code points ended by 0xffff. Sequences (several code
+// points drawn with a single glyph) start with 0xfffe and are skipped.
+fn parse_psf1_table(table: &[u8], glyph_count: usize) -> BTreeMap<char, usize> {
+    let mut map = BTreeMap::new();
+    let mut glyph = 0;
+    let mut in_sequence = false;
+    for chunk in table.chunks_exact(2) {
+        if glyph >= glyph_count {
+            break;
+        }
+        match u16::from_le_bytes([chunk[0], chunk[1]]) {
+            PSF1_SEPARATOR => {
+                glyph += 1;
+                in_sequence = false;
+            }
+            PSF1_SEQUENCE_START => in_sequence = true,
+            value if !in_sequence => {
+                if let Some(character) = core::char::from_u32(value as u32) {
+                    map.entry(character).or_insert(glyph);
+                }
+            }
+            _ => {}
+        }
+    }
+    map
+}
+
+// Same layout as the PSF1 table with UTF-8 code points, ended by 0xff, sequences starting
+// with 0xfe
+fn parse_psf2_table(table: &[u8], glyph_count: usize) -> BTreeMap<char, usize> {
+    let mut map = BTreeMap::new();
+    let mut glyph = 0;
+    let mut entry = table;
+    while glyph < glyph_count && !entry.is_empty() {
+        let end = entry.iter().position(|&byte| byte == PSF2_SEPARATOR).unwrap_or(entry.len());
+        let characters = &entry[..end];
+        let characters = match characters.iter().position(|&byte| byte == PSF2_SEQUENCE_START) {
+            Some(sequence_start) => &characters[..sequence_start],
+            None => characters
+        };
+        // Invalid UTF-8 loses the whole entry rather than guessing
+        if let Ok(characters) = core::str::from_utf8(characters) {
+            for character in characters.chars() {
+                map.entry(character).or_insert(glyph);
+            }
+        }
+        glyph += 1;
+        entry = &entry[(end + 1).min(entry.len())..];
+    }
+    map
+}
+
+pub fn default_font() -> PsfFont<'static> {
+    PsfFont::parse(DEFAULT_FONT).expect("The built-in font is invalid.")
+}
+
+// The font passed as a boot module, if there is one and it parses. Needs the kernel layout (the
+// module is read through the direct map) and the heap (for the Unicode table).
+pub fn load_boot_font() -> Option<Result<PsfFont<'static>, FontError>> {
+    crate::initrd::boot_module(FONT_MODULE_STRING).map(|module| PsfFont::parse(module.data))
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    fn psf1(mode: u8, table: &[u16]) -> Vec<u8> {
+        let mut data = vec![0x36, 0x04, mode, 8];
+        for glyph in 0..256 {
+            data.extend_from_slice(&[glyph as u8; 8]);
+        }
+        for value in table {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data
+    }
+
+    fn psf2(width: u32, height: u32, glyph_count: u32, table: Option<&[u8]>) -> Vec<u8> {
+        let bytes_per_glyph = (width + 7) / 8 * height;
+        let flags = if table.is_some() { PSF2_HAS_UNICODE_TABLE } else { 0 };
+        let mut data = Vec::new();
+        for value in &[0x864ab572, 0, 32, flags, glyph_count, bytes_per_glyph, height, width] {
+            data.extend_from_slice(&u32::to_le_bytes(*value));
+        }
+        for glyph in 0..glyph_count {
+            data.extend(core::iter::repeat(glyph as u8).take(bytes_per_glyph as usize));
+        }
+        data.extend_from_slice(table.unwrap_or(&[]));
+        data
+    }
+
+    #[test]
+    fn default_font_parses() {
+        let font = default_font();
+        assert_eq!((font.width, font.height, font.glyph_count), (8, 16, 256));
+        assert!(font.has_unicode_table());
+        for character in " ~é─█Σ".chars() {
+            assert_eq!(font.lookup(character), to_cp437(character).map(|cp437| cp437 as usize));
+        }
+        assert_eq!(font.lookup_or_replacement('€'), 0xfe);
+    }
+
+    #[test]
+    fn psf1_without_table_uses_cp437() {
+        let data = psf1(0, &[]);
+        let font = PsfFont::parse(&data).unwrap();
+        assert_eq!((font.width, font.height, font.glyph_count), (8, 8, 256));
+        assert_eq!(font.lookup('A'), Some(0x41));
+        assert_eq!(font.lookup('░'), Some(0xb0));
+        assert_eq!(font.glyph(0x41), &[0x41; 8]);
+    }
+
+    #[test]
+    fn psf1_table_skips_sequences() {
+        // Glyph 0 : 'a' and 'b', glyph 1 : 'c' then the sequence "e" + combining acute
+        let table = [0x61, 0x62, 0xffff, 0x63, 0xfffe, 0x65, 0x301, 0xffff];
+        let data = psf1(PSF1_MODE_HAS_TABLE, &table);
+        let font = PsfFont::parse(&data).unwrap();
+        assert_eq!(font.lookup('a'), Some(0));
+        assert_eq!(font.lookup('b'), Some(0));
+        assert_eq!(font.lookup('c'), Some(1));
+        assert_eq!(font.lookup('e'), None);
+        assert_eq!(font.lookup('A'), None);
+    }
+
+    #[test]
+    fn psf2_wide_glyphs_and_table() {
+        let mut table = Vec::new();
+        table.extend_from_slice("a€".as_bytes());
+        table.push(0xff);
+        table.extend_from_slice("b".as_bytes());
+        table.push(0xfe);
+        table.extend_from_slice("x".as_bytes());
+        table.push(0xff);
+        table.push(0xff);
+        let data = psf2(12, 24, 3, Some(&table));
+        let font = PsfFont::parse(&data).unwrap();
+        assert_eq!((font.width, font.height, font.glyph_count), (12, 24, 3));
+        assert_eq!(font.lookup('€'), Some(0));
+        assert_eq!(font.lookup('b'), Some(1));
+        assert_eq!(font.lookup('x'), None);
+        assert_eq!(font.glyph(2).len(), 48);
+        // Glyph 1 is all 0x01 bytes : the last pixel of each byte is set
+        assert!(font.pixel(font.glyph(1), 7, 23));
+        assert!(!font.pixel(font.glyph(1), 8, 0));
+        assert!(font.pixel(font.glyph(1), 15, 0));
+    }
+
+    #[test]
+    fn invalid_fonts_are_rejected() {
+        assert_eq!(PsfFont::parse(&[0u8; 64]).err(), Some(FontError::BadMagic));
+        let data = psf2(8, 16, 256, None);
+        assert_eq!(PsfFont::parse(&data[..1000]).err(), Some(FontError::Truncated));
+        let mut data = psf2(8, 16, 1, None);
+        data[4] = 1;
+        assert_eq!(PsfFont::parse(&data).err(), Some(FontError::UnsupportedVersion(1)));
+        assert_eq!(PsfFont::parse(&psf1(0, &[])[..100]).err(), Some(FontError::Truncated));
+    }
+}
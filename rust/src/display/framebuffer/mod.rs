@@ -0,0 +1,631 @@
+// Linear framebuffer given by the bootloader (stivale2 framebuffer tag), with 2D drawing
+// primitives.
+//
+// Once enable_back_buffer is called everything is drawn to a copy in the kernel heap and only
+// reaches the screen on flush, which copies the rectangles that changed since the last flush.
+// This avoids flicker (a panel being cleared then redrawn shows up as a single update) and
+// reading back from video memory, which is very slow to read. The copy is kept in the
+// framebuffer's own pixel format and layout, so flushing is a plain copy of each row.
+
+use crate::display::vga::Color;
+use crate::stivale2::{FramebufferTag, FRAMEBUFFER_MEMORY_MODEL_RGB};
+use alloc::vec;
+use alloc::vec::Vec;
+
+// Dirty rectangles kept before they are merged into their bounding box
+const MAX_DIRTY_RECTS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8
+}
+
+impl Rgb {
+    pub const fn new(r: u8, g: u8, b: u8) -> Rgb {
+        Rgb { r, g, b }
+    }
+}
+
+// The usual VGA palette, so both consoles show the same colours
+impl From<Color> for Rgb {
+    fn from(color: Color) -> Rgb {
+        match color {
+            Color::Black      => Rgb::new(0x00, 0x00, 0x00),
+            Color::Blue       => Rgb::new(0x00, 0x00, 0xaa),
+            Color::Green      => Rgb::new(0x00, 0xaa, 0x00),
+            Color::Cyan       => Rgb::new(0x00, 0xaa, 0xaa),
+            Color::Red        => Rgb::new(0xaa, 0x00, 0x00),
+            Color::Magenta    => Rgb::new(0xaa, 0x00, 0xaa),
+            Color::Brown      => Rgb::new(0xaa, 0x55, 0x00),
+            Color::LightGray  => Rgb::new(0xaa, 0xaa, 0xaa),
+            Color::DarkGray   => Rgb::new(0x55, 0x55, 0x55),
+            Color::LightBlue  => Rgb::new(0x55, 0x55, 0xff),
+            Color::LightGreen => Rgb::new(0x55, 0xff, 0x55),
+            Color::LightCyan  => Rgb::new(0x55, 0xff, 0xff),
+            Color::LightRed   => Rgb::new(0xff, 0x55, 0x55),
+            Color::Pink       => Rgb::new(0xff, 0x55, 0xff),
+            Color::Yellow     => Rgb::new(0xff, 0xff, 0x55),
+            Color::White      => Rgb::new(0xff, 0xff, 0xff),
+        }
+    }
+}
+
+// Colour with an alpha channel, 0 being fully transparent and 255 fully opaque
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rgba {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+    pub a: u8
+}
+
+impl Rgba {
+    pub const fn new(r: u8, g: u8, b: u8, a: u8) -> Rgba {
+        Rgba { r, g, b, a }
+    }
+
+    // Draws this colour over background
+    pub fn blend(&self, background: Rgb) -> Rgb {
+        fn mix(front: u8, back: u8, alpha: u8) -> u8 {
+            let alpha = alpha as u32;
+            ((front as u32 * alpha + back as u32 * (255 - alpha) + 127) / 255) as u8
+        }
+        Rgb::new(
+            mix(self.r, background.r, self.a),
+            mix(self.g, background.g, self.a),
+            mix(self.b, background.b, self.a)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize
+}
+
+impl Rect {
+    pub const fn new(x: usize, y: usize, width: usize, height: usize) -> Rect {
+        Rect { x, y, width, height }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    pub fn intersection(&self, other: &Rect) -> Rect {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let x_end = (self.x + self.width).min(other.x + other.width);
+        let y_end = (self.y + self.height).min(other.y + other.height);
+        Rect::new(x, y, x_end.saturating_sub(x), y_end.saturating_sub(y))
+    }
+
+    // Smallest rectangle containing both
+    pub fn union(&self, other: &Rect) -> Rect {
+        if self.is_empty() {
+            return *other;
+        }
+        if other.is_empty() {
+            return *self;
+        }
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let x_end = (self.x + self.width).max(other.x + other.width);
+        let y_end = (self.y + self.height).max(other.y + other.height);
+        Rect::new(x, y, x_end - x, y_end - y)
+    }
+
+    pub fn contains_rect(&self, other: &Rect) -> bool {
+        other.x >= self.x && other.y >= self.y
+            && other.x + other.width <= self.x + self.width
+            && other.y + other.height <= self.y + self.height
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PixelFormat {
+    pub bytes_per_pixel: usize,
+    pub red_size: u8,
+    pub red_shift: u8,
+    pub green_size: u8,
+    pub green_shift: u8,
+    pub blue_size: u8,
+    pub blue_shift: u8
+}
+
+impl PixelFormat {
+    // Keeps the top bits of each 8 bit component
+    pub fn encode(&self, color: Rgb) -> u32 {
+        fn component(value: u8, size: u8, shift: u8) -> u32 {
+            ((value as u32) >> (8 - size.min(8))) << shift
+        }
+        component(color.r, self.red_size, self.red_shift)
+            | component(color.g, self.green_size, self.green_shift)
+            | component(color.b, self.blue_size, self.blue_shift)
+    }
+
+    // Scales each component back to 8 bits
+    pub fn decode(&self, value: u32) -> Rgb {
+        fn component(value: u32, size: u8, shift: u8) -> u8 {
+            let size = size.min(8);
+            if size == 0 {
+                return 0;
+            }
+            let max = (1u32 << size) - 1;
+            (((value >> shift) & max) * 255 / max) as u8
+        }
+        Rgb::new(
+            component(value, self.red_size, self.red_shift),
+            component(value, self.green_size, self.green_shift),
+            component(value, self.blue_size, self.blue_shift)
+        )
+    }
+}
+
+pub struct Framebuffer {
+    // Virtual address of the first pixel
+    address: usize,
+    pub width: usize,
+    pub height: usize,
+    // Bytes between the start of two rows
+    pub pitch: usize,
+    pub format: PixelFormat,
+    // pitch * height bytes laid out like the screen, see enable_back_buffer
+    back_buffer: Option<Vec<u8>>,
+    // Parts of the back buffer not flushed yet
+    dirty: Vec<Rect>
+}
+
+impl Framebuffer {
+    // The framebuffer has to be mapped at address already, for pitch * height bytes
+    pub unsafe fn new(address: usize, width: usize, height: usize, pitch: usize, format: PixelFormat) -> Framebuffer {
+        Framebuffer {
+            address,
+            width,
+            height,
+            pitch,
+            format,
+            back_buffer: None,
+            dirty: Vec::new()
+        }
+    }
+
+    // The framebuffer has to be mapped at virtual_address already. Only RGB framebuffers are
+    // supported.
+    pub unsafe fn from_tag(tag: &FramebufferTag, virtual_address: usize) -> Option<Framebuffer> {
+        let bpp = tag.framebuffer_bpp;
+        if tag.memory_model != FRAMEBUFFER_MEMORY_MODEL_RGB || !(bpp == 16 || bpp == 24 || bpp == 32) {
+            return None;
+        }
+
+        Some(Framebuffer::new(
+            virtual_address,
+            tag.framebuffer_width as usize,
+            tag.framebuffer_height as usize,
+            tag.framebuffer_pitch as usize,
+            PixelFormat {
+                bytes_per_pixel: bpp as usize / 8,
+                red_size: tag.red_mask_size,
+                red_shift: tag.red_mask_shift,
+                green_size: tag.green_mask_size,
+                green_shift: tag.green_mask_shift,
+                blue_size: tag.blue_mask_size,
+                blue_shift: tag.blue_mask_shift
+            }
+        ))
+    }
+
+    pub fn size_in_bytes(&self) -> usize {
+        self.pitch * self.height
+    }
+
+    pub fn bounds(&self) -> Rect {
+        Rect::new(0, 0, self.width, self.height)
+    }
+
+    // Starts drawing to a copy in the heap. The copy starts out black, which the screen is cleared
+    // to on the next flush.
+    pub fn enable_back_buffer(&mut self) {
+        if self.back_buffer.is_some() {
+            return;
+        }
+        self.back_buffer = Some(vec![0; self.size_in_bytes()]);
+        let bounds = self.bounds();
+        self.mark_dirty(bounds);
+    }
+
+    pub fn has_back_buffer(&self) -> bool {
+        self.back_buffer.is_some()
+    }
+
+    // Offset of a pixel from the start of the screen, or of the back buffer
+    fn pixel_offset(&self, x: usize, y: usize) -> usize {
+        y * self.pitch + x * self.format.bytes_per_pixel
+    }
+
+    fn pixel_address(&self, x: usize, y: usize) -> usize {
+        self.address + self.pixel_offset(x, y)
+    }
+
+    fn write_encoded(&mut self, x: usize, y: usize, value: u32) {
+        let pixel = self.pixel_address(x, y);
+        unsafe {
+            match self.format.bytes_per_pixel {
+                4 => core::ptr::write_volatile(pixel as *mut u32, value),
+                3 => {
+                    core::ptr::write_volatile(pixel as *mut u16, value as u16);
+                    core::ptr::write_volatile((pixel + 2) as *mut u8, (value >> 16) as u8);
+                }
+                _ => core::ptr::write_volatile(pixel as *mut u16, value as u16)
+            }
+        }
+    }
+
+    fn read_encoded(&self, x: usize, y: usize) -> u32 {
+        let pixel = self.pixel_address(x, y);
+        unsafe {
+            match self.format.bytes_per_pixel {
+                4 => core::ptr::read_volatile(pixel as *const u32),
+                3 => core::ptr::read_volatile(pixel as *const u16) as u32
+                    | (core::ptr::read_volatile((pixel + 2) as *const u8) as u32) << 16,
+                _ => core::ptr::read_volatile(pixel as *const u16) as u32
+            }
+        }
+    }
+
+    // Coordinates have to be on the screen, and the area marked dirty by the caller
+    fn set_encoded(&mut self, x: usize, y: usize, value: u32) {
+        let offset = self.pixel_offset(x, y);
+        let bytes_per_pixel = self.format.bytes_per_pixel;
+        match self.back_buffer.as_mut() {
+            Some(back_buffer) => back_buffer[offset..offset + bytes_per_pixel]
+                .copy_from_slice(&value.to_le_bytes()[..bytes_per_pixel]),
+            None => self.write_encoded(x, y, value)
+        }
+    }
+
+    fn set(&mut self, x: usize, y: usize, color: Rgb) {
+        let value = self.format.encode(color);
+        self.set_encoded(x, y, value);
+    }
+
+    fn get(&self, x: usize, y: usize) -> Rgb {
+        let value = match self.back_buffer.as_ref() {
+            Some(back_buffer) => {
+                let offset = self.pixel_offset(x, y);
+                let mut bytes = [0; 4];
+                bytes[..self.format.bytes_per_pixel]
+                    .copy_from_slice(&back_buffer[offset..offset + self.format.bytes_per_pixel]);
+                u32::from_le_bytes(bytes)
+            }
+            None => self.read_encoded(x, y)
+        };
+        self.format.decode(value)
+    }
+
+    fn mark_dirty(&mut self, rect: Rect) {
+        if self.back_buffer.is_none() {
+            return;
+        }
+        let rect = rect.intersection(&self.bounds());
+        if rect.is_empty() || self.dirty.iter().any(|dirty| dirty.contains_rect(&rect)) {
+            return;
+        }
+        self.dirty.retain(|dirty| !rect.contains_rect(dirty));
+        if self.dirty.len() == MAX_DIRTY_RECTS {
+            let bounding_box = self.dirty.iter().fold(rect, |bounding_box, dirty| bounding_box.union(dirty));
+            self.dirty.clear();
+            self.dirty.push(bounding_box);
+        } else {
+            self.dirty.push(rect);
+        }
+    }
+
+    pub fn dirty_rects(&self) -> &[Rect] {
+        &self.dirty
+    }
+
+    // Copies what changed in the back buffer to the screen
+    pub fn flush(&mut self) {
+        let dirty = core::mem::replace(&mut self.dirty, Vec::new());
+        for rect in dirty.iter() {
+            self.flush_rect(rect);
+        }
+        // Keeps the allocation around for the next frame
+        self.dirty = dirty;
+        self.dirty.clear();
+    }
+
+    // Copies part of the back buffer to the screen, dirty or not
+    pub fn flush_rect(&mut self, rect: &Rect) {
+        let rect = rect.intersection(&self.bounds());
+        let back_buffer = match self.back_buffer.as_ref() {
+            Some(back_buffer) => back_buffer,
+            None => return
+        };
+        let span = rect.width * self.format.bytes_per_pixel;
+        for y in rect.y..rect.y + rect.height {
+            let offset = self.pixel_offset(rect.x, y);
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    back_buffer[offset..offset + span].as_ptr(),
+                    (self.address + offset) as *mut u8,
+                    span
+                );
+            }
+        }
+    }
+
+    // Pixels outside of the screen are ignored
+    pub fn put_pixel(&mut self, x: usize, y: usize, color: Rgb) {
+        if x < self.width && y < self.height {
+            self.set(x, y, color);
+            self.mark_dirty(Rect::new(x, y, 1, 1));
+        }
+    }
+
+    pub fn get_pixel(&self, x: usize, y: usize) -> Option<Rgb> {
+        if x < self.width && y < self.height {
+            Some(self.get(x, y))
+        } else {
+            None
+        }
+    }
+
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: Rgb) {
+        let rect = Rect::new(x, y, width, height).intersection(&self.bounds());
+        let value = self.format.encode(color);
+        for y in rect.y..rect.y + rect.height {
+            for x in rect.x..rect.x + rect.width {
+                self.set_encoded(x, y, value);
+            }
+        }
+        self.mark_dirty(rect);
+    }
+
+    // One pixel wide outline
+    pub fn draw_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: Rgb) {
+        if width == 0 || height == 0 {
+            return;
+        }
+        self.fill_rect(x, y, width, 1, color);
+        self.fill_rect(x, y + height - 1, width, 1, color);
+        self.fill_rect(x, y, 1, height, color);
+        self.fill_rect(x + width - 1, y, 1, height, color);
+    }
+
+    // Bresenham's line, both ends included. Points outside of the screen are clipped.
+    pub fn draw_line(&mut self, x0: usize, y0: usize, x1: usize, y1: usize, color: Rgb) {
+        let (mut x, mut y) = (x0 as isize, y0 as isize);
+        let (x1, y1) = (x1 as isize, y1 as isize);
+        let dx = (x1 - x).abs();
+        let dy = -(y1 - y).abs();
+        let step_x = if x < x1 { 1 } else { -1 };
+        let step_y = if y < y1 { 1 } else { -1 };
+        let mut error = dx + dy;
+
+        loop {
+            if (x as usize) < self.width && (y as usize) < self.height {
+                self.set(x as usize, y as usize, color);
+            }
+            if x == x1 && y == y1 {
+                break;
+            }
+            let doubled_error = 2 * error;
+            if doubled_error >= dy {
+                error += dy;
+                x += step_x;
+            }
+            if doubled_error <= dx {
+                error += dx;
+                y += step_y;
+            }
+        }
+
+        let rect = Rect::new(x0.min(x1 as usize), y0.min(y1 as usize), dx as usize + 1, (-dy) as usize + 1);
+        self.mark_dirty(rect);
+    }
+
+    // Copies a width * height image (row by row) to (x, y), clipped to the screen
+    pub fn blit(&mut self, x: usize, y: usize, width: usize, height: usize, pixels: &[Rgb]) {
+        assert!(pixels.len() >= width * height, "Blitting {} pixels out of a {}x{} image.", pixels.len(), width, height);
+        let rect = Rect::new(x, y, width, height).intersection(&self.bounds());
+        for screen_y in rect.y..rect.y + rect.height {
+            for screen_x in rect.x..rect.x + rect.width {
+                let pixel = pixels[(screen_y - y) * width + screen_x - x];
+                self.set(screen_x, screen_y, pixel);
+            }
+        }
+        self.mark_dirty(rect);
+    }
+
+    // Same as blit, blending the image over what is already drawn
+    pub fn blend_bitmap(&mut self, x: usize, y: usize, width: usize, height: usize, pixels: &[Rgba]) {
+        assert!(pixels.len() >= width * height, "Blending {} pixels out of a {}x{} image.", pixels.len(), width, height);
+        let rect = Rect::new(x, y, width, height).intersection(&self.bounds());
+        for screen_y in rect.y..rect.y + rect.height {
+            for screen_x in rect.x..rect.x + rect.width {
+                let pixel = pixels[(screen_y - y) * width + screen_x - x];
+                match pixel.a {
+                    0 => {}
+                    255 => self.set(screen_x, screen_y, Rgb::new(pixel.r, pixel.g, pixel.b)),
+                    _ => {
+                        let background = self.get(screen_x, screen_y);
+                        self.set(screen_x, screen_y, pixel.blend(background));
+                    }
+                }
+            }
+        }
+        self.mark_dirty(rect);
+    }
+
+    // Moves everything up by lines pixel rows, filling the bottom with color
+    pub fn scroll_up(&mut self, lines: usize, color: Rgb) {
+        let lines = lines.min(self.height);
+        match self.back_buffer.as_mut() {
+            Some(back_buffer) => {
+                back_buffer.copy_within(lines * self.pitch.., 0);
+                let bounds = self.bounds();
+                self.mark_dirty(bounds);
+            }
+            None => unsafe {
+                core::ptr::copy(
+                    (self.address + lines * self.pitch) as *const u8,
+                    self.address as *mut u8,
+                    (self.height - lines) * self.pitch
+                );
+            }
+        }
+        let width = self.width;
+        self.fill_rect(0, self.height - lines, width, lines, color);
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    const XRGB: PixelFormat = PixelFormat {
+        bytes_per_pixel: 4,
+        red_size: 8,
+        red_shift: 16,
+        green_size: 8,
+        green_shift: 8,
+        blue_size: 8,
+        blue_shift: 0
+    };
+
+    const RGB565: PixelFormat = PixelFormat {
+        bytes_per_pixel: 2,
+        red_size: 5,
+        red_shift: 11,
+        green_size: 6,
+        green_shift: 5,
+        blue_size: 5,
+        blue_shift: 0
+    };
+
+    const WHITE: Rgb = Rgb::new(0xff, 0xff, 0xff);
+
+    // Framebuffer drawing to memory, with 2 bytes of padding per row
+    fn framebuffer(memory: &mut Vec<u32>, width: usize, height: usize) -> Framebuffer {
+        memory.resize((width + 2) * height, 0);
+        unsafe { Framebuffer::new(memory.as_mut_ptr() as usize, width, height, (width + 2) * 4, XRGB) }
+    }
+
+    fn screen(memory: &[u32], width: usize, height: usize) -> Vec<Vec<bool>> {
+        (0..height).map(|y| (0..width).map(|x| memory[y * (width + 2) + x] != 0).collect()).collect()
+    }
+
+    #[test]
+    fn pixel_formats_round_trip() {
+        let color = Rgb::new(0x12, 0x80, 0xff);
+        assert_eq!(XRGB.encode(color), 0x1280ff);
+        assert_eq!(XRGB.decode(0x1280ff), color);
+        assert_eq!(RGB565.encode(WHITE), 0xffff);
+        assert_eq!(RGB565.decode(0xffff), WHITE);
+        assert_eq!(RGB565.decode(RGB565.encode(Rgb::new(0, 0, 0))), Rgb::new(0, 0, 0));
+    }
+
+    #[test]
+    fn alpha_blending() {
+        let background = Rgb::new(0, 100, 200);
+        assert_eq!(Rgba::new(255, 0, 0, 255).blend(background), Rgb::new(255, 0, 0));
+        assert_eq!(Rgba::new(255, 0, 0, 0).blend(background), background);
+        assert_eq!(Rgba::new(255, 0, 0, 128).blend(background), Rgb::new(128, 50, 100));
+    }
+
+    #[test]
+    fn lines_include_both_ends() {
+        let mut memory = Vec::new();
+        let mut framebuffer = framebuffer(&mut memory, 5, 4);
+        framebuffer.draw_line(4, 3, 0, 0, WHITE);
+        let drawn = screen(&memory, 5, 4);
+        assert!(drawn[0][0] && drawn[3][4]);
+        assert_eq!(drawn.iter().flatten().filter(|&&set| set).count(), 5);
+        // Each row of a shallow line has at least one pixel
+        assert!(drawn.iter().all(|row| row.contains(&true)));
+    }
+
+    #[test]
+    fn drawing_is_clipped_to_the_screen() {
+        let mut memory = Vec::new();
+        let mut framebuffer = framebuffer(&mut memory, 4, 4);
+        framebuffer.fill_rect(2, 2, 10, 10, WHITE);
+        framebuffer.put_pixel(4, 0, WHITE);
+        framebuffer.blit(3, 0, 2, 1, &[WHITE, WHITE]);
+        assert!(memory.chunks(6).all(|row| row[4] == 0 && row[5] == 0));
+        assert_eq!(memory.iter().filter(|&&pixel| pixel != 0).count(), 5);
+    }
+
+    #[test]
+    fn back_buffer_only_reaches_the_screen_on_flush() {
+        let mut memory = Vec::new();
+        let mut framebuffer = framebuffer(&mut memory, 8, 8);
+        framebuffer.enable_back_buffer();
+        framebuffer.flush();
+        framebuffer.fill_rect(1, 1, 2, 2, WHITE);
+        framebuffer.put_pixel(2, 2, WHITE);
+        framebuffer.put_pixel(6, 6, WHITE);
+        assert_eq!(framebuffer.dirty_rects(), &[Rect::new(1, 1, 2, 2), Rect::new(6, 6, 1, 1)]);
+        assert_eq!(framebuffer.get_pixel(6, 6), Some(WHITE));
+        assert!(memory.iter().all(|&pixel| pixel == 0));
+
+        framebuffer.flush();
+        assert!(framebuffer.dirty_rects().is_empty());
+        assert_eq!(memory.iter().filter(|&&pixel| pixel == 0xffffff).count(), 5);
+    }
+
+    #[test]
+    fn back_buffer_starts_cleared() {
+        let mut memory = vec![0x123456; 6 * 4];
+        let mut framebuffer = framebuffer(&mut memory, 4, 4);
+        framebuffer.enable_back_buffer();
+        assert_eq!(framebuffer.get_pixel(1, 1), Some(Rgb::new(0, 0, 0)));
+        framebuffer.flush();
+        assert!(screen(&memory, 4, 4).iter().flatten().all(|&set| !set));
+        // Padding at the end of the rows is left alone
+        assert!(memory.chunks(6).all(|row| row[4] == 0x123456 && row[5] == 0x123456));
+    }
+
+    #[test]
+    fn back_buffer_in_a_16_bit_format() {
+        let mut memory = vec![0u16; 4 * 2];
+        let mut framebuffer = unsafe { Framebuffer::new(memory.as_mut_ptr() as usize, 3, 2, 8, RGB565) };
+        framebuffer.enable_back_buffer();
+        framebuffer.flush();
+        framebuffer.put_pixel(2, 1, WHITE);
+        assert_eq!(framebuffer.get_pixel(2, 1), Some(WHITE));
+        framebuffer.flush();
+        assert_eq!(memory, vec![0, 0, 0, 0, 0, 0, 0xffff, 0]);
+    }
+
+    #[test]
+    fn dirty_rects_are_merged_when_there_are_too_many() {
+        let mut memory = Vec::new();
+        let mut framebuffer = framebuffer(&mut memory, 32, 4);
+        framebuffer.enable_back_buffer();
+        framebuffer.flush();
+        for x in 0..=MAX_DIRTY_RECTS {
+            framebuffer.put_pixel(x * 2, x % 4, WHITE);
+        }
+        assert_eq!(framebuffer.dirty_rects(), &[Rect::new(0, 0, MAX_DIRTY_RECTS * 2 + 1, 4)]);
+    }
+
+    #[test]
+    fn scrolling_the_back_buffer() {
+        let mut memory = Vec::new();
+        let mut framebuffer = framebuffer(&mut memory, 4, 4);
+        framebuffer.enable_back_buffer();
+        framebuffer.fill_rect(0, 2, 4, 1, WHITE);
+        framebuffer.scroll_up(2, Rgb::new(0, 0, 0));
+        framebuffer.flush();
+        let drawn = screen(&memory, 4, 4);
+        assert_eq!(drawn.iter().map(|row| row[0]).collect::<Vec<_>>(), vec![true, false, false, false]);
+    }
+}
@@ -1,2 +1,126 @@
 #[macro_use]
-pub mod vga;
\ No newline at end of file
+pub mod vga;
+pub mod ansi;
+pub mod charset;
+pub mod console;
+pub mod font;
+pub mod framebuffer;
+pub mod text_grid;
+
+use crate::display::console::FramebufferConsole;
+use crate::display::font::PsfFont;
+use crate::display::framebuffer::Framebuffer;
+use crate::memory::paging::EntryFlags;
+use core::fmt;
+use core::fmt::Write;
+use spin::Mutex;
+
+// Replaces the VGA text mode writer once set, see init_framebuffer_console
+pub static CONSOLE: Mutex<Option<FramebufferConsole>> = Mutex::new(None);
+
+// print! output goes to the debug console as well, which works before anything is set up and
+// even when the VGA writer is left locked
+pub fn print(args: fmt::Arguments) {
+    crate::debugcon::print(args);
+    match CONSOLE.lock().as_mut() {
+        Some(console) => {
+            console.write_fmt(args).unwrap();
+            console.flush();
+        }
+        None => vga::print(args)
+    }
+}
+
+// Writes to the framebuffer console, or to the VGA text buffer when there is none
+pub fn write_str(s: &str) {
+    match CONSOLE.lock().as_mut() {
+        Some(console) => {
+            console.write_str(s);
+            console.flush();
+        }
+        None => vga::WRITER.lock().write_str(s)
+    }
+}
+
+// Switches to a console on the bootloader's framebuffer, if it gave one. Needs the heap to map the
+// framebuffer. Uses the font passed as a "font" boot module when there is a valid one.
+pub unsafe fn init_framebuffer_console() -> bool {
+    let tag = match crate::stivale2::framebuffer() {
+        Some(tag) => tag,
+        None => return false
+    };
+    let size = tag.framebuffer_pitch as usize * tag.framebuffer_height as usize;
+    // Write-combining lets the CPU send whole bursts of pixels instead of one write at a time
+    let virtual_address = crate::memory::layout::map_physical(
+        tag.framebuffer_addr as usize,
+        size,
+        EntryFlags::PRESENT | EntryFlags::WRITABLE | crate::memory::pat::write_combining()
+    );
+
+    match Framebuffer::from_tag(&tag, virtual_address) {
+        Some(mut framebuffer) => {
+            // Loading the font may log, which goes through CONSOLE
+            let font = console_font();
+            if !FramebufferConsole::fits(&framebuffer, &font) {
+                warn!("The {}x{} console font doesn't fit on the {}x{} framebuffer",
+                      font.width, font.height, framebuffer.width, framebuffer.height);
+                return false;
+            }
+            framebuffer.enable_back_buffer();
+            *CONSOLE.lock() = Some(FramebufferConsole::new(framebuffer, font));
+            true
+        }
+        None => false
+    }
+}
+
+// For panic handlers, the panicking code may have been printing
+pub unsafe fn force_unlock() {
+    CONSOLE.force_unlock();
+    vga::WRITER.force_unlock();
+}
+
+// Clears the screen for the panic report, which is written with write_str. The panicking code may
+// have been holding the consoles' locks, they are released first.
+pub unsafe fn begin_panic_screen(foreground: vga::Color, background: vga::Color) {
+    force_unlock();
+    match CONSOLE.lock().as_mut() {
+        Some(console) => {
+            console.set_color(foreground, background);
+            console.clear_screen();
+            console.flush();
+        }
+        None => {
+            let mut writer = vga::WRITER.lock();
+            writer.set_color(foreground, background);
+            writer.clear_screen();
+        }
+    }
+}
+
+// Runs f on the framebuffer console's framebuffer then flushes it, to draw splash screens or
+// status panels. Returns false when there is no framebuffer console.
+pub fn with_framebuffer<F: FnOnce(&mut Framebuffer)>(f: F) -> bool {
+    match CONSOLE.lock().as_mut() {
+        Some(console) => {
+            f(console.framebuffer());
+            console.flush();
+            true
+        }
+        None => false
+    }
+}
+
+fn console_font() -> PsfFont<'static> {
+    match font::load_boot_font() {
+        Some(Ok(font)) => {
+            info!("Loaded {}x{} console font from boot module ({} glyphs)", font.width, font.height, font.glyph_count);
+            font
+        }
+        Some(Err(error)) => {
+            warn!("Invalid console font boot module ({:?}), using the built-in font", error);
+            font::default_font()
+        }
+        None => font::default_font()
+    }
+}
@@ -0,0 +1,294 @@
+// Cursor, escape sequences and erasing shared by the text consoles.
+//
+// TextGrid interprets what is written (display::ansi and display::charset) and keeps track of the
+// cursor and attributes. Storing and showing the cells is left to a CellBackend : VGA text memory,
+// or glyphs drawn on the framebuffer.
+
+use crate::display::ansi::{AnsiAction, AnsiParser, CsiSequence, TextAttributes};
+use crate::display::charset::Utf8Decoder;
+use crate::display::vga::{Color, DEFAULT_BACKGROUND, DEFAULT_FOREGROUND};
+
+pub const TAB_WIDTH: usize = 8;
+
+pub trait CellBackend {
+    // At least one row and one column
+    fn rows(&self) -> usize;
+    fn columns(&self) -> usize;
+    fn put_cell(&mut self, row: usize, col: usize, character: char, attributes: TextAttributes);
+    // Blanks columns start_col..end_col of row with the attributes' background
+    fn clear_cells(&mut self, row: usize, start_col: usize, end_col: usize, attributes: TextAttributes);
+    // Moves every row up by one and blanks the last one
+    fn scroll(&mut self, attributes: TextAttributes);
+
+    // Blanks the whole screen
+    fn clear(&mut self, attributes: TextAttributes) {
+        let columns = self.columns();
+        for row in 0..self.rows() {
+            self.clear_cells(row, 0, columns, attributes);
+        }
+    }
+
+    // ESC[?25h and ESC[?25l
+    fn set_cursor_visible(&mut self, _visible: bool) {}
+}
+
+pub struct TextGrid<B> {
+    backend: B,
+    row_position: usize,
+    // columns() when a full row is waiting to wrap
+    column_position: usize,
+    attributes: TextAttributes,
+    ansi: AnsiParser,
+    utf8: Utf8Decoder
+}
+
+impl<B> TextGrid<B> {
+    pub const fn new(backend: B, row_position: usize) -> TextGrid<B> {
+        TextGrid {
+            backend,
+            row_position,
+            column_position: 0,
+            attributes: TextAttributes::new(DEFAULT_FOREGROUND, DEFAULT_BACKGROUND),
+            ansi: AnsiParser::new(),
+            utf8: Utf8Decoder::new()
+        }
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
+    pub fn position(&self) -> (usize, usize) {
+        (self.row_position, self.column_position)
+    }
+
+    pub fn color(&self) -> (Color, Color) {
+        (self.attributes.foreground, self.attributes.background)
+    }
+
+    pub fn set_color(&mut self, foreground: Color, background: Color) {
+        self.attributes.foreground = foreground;
+        self.attributes.background = background;
+    }
+
+    pub fn reset_color(&mut self) {
+        self.attributes = TextAttributes::new(DEFAULT_FOREGROUND, DEFAULT_BACKGROUND);
+    }
+}
+
+impl<B: CellBackend> TextGrid<B> {
+    // Bytes are UTF-8 text with escape sequences
+    pub fn write_byte(&mut self, byte: u8) {
+        match self.ansi.feed(byte) {
+            Some(AnsiAction::Print(byte)) => {
+                for &character in self.utf8.feed(byte).iter().flatten() {
+                    self.put_char(character);
+                }
+            }
+            Some(AnsiAction::Csi(sequence)) => self.apply_csi(&sequence),
+            None => {}
+        }
+    }
+
+    fn put_char(&mut self, character: char) {
+        match character {
+            '\n' => self.new_line(),
+            '\r' => self.column_position = 0,
+            '\t' => {
+                // Always at least one space, up to the next tab stop
+                self.put_glyph(' ');
+                while self.column_position % TAB_WIDTH != 0 && self.column_position < self.backend.columns() {
+                    self.put_glyph(' ');
+                }
+            }
+            // Backspace moves back without erasing, "\x08 \x08" erases
+            '\x08' => self.column_position = self.column_position.saturating_sub(1),
+            character if character.is_control() => {}
+            character => self.put_glyph(character)
+        }
+    }
+
+    fn put_glyph(&mut self, character: char) {
+        if self.column_position >= self.backend.columns() {
+            self.new_line();
+        }
+        self.backend.put_cell(self.row_position, self.column_position, character, self.attributes);
+        self.column_position += 1;
+    }
+
+    fn new_line(&mut self) {
+        self.column_position = 0;
+        if self.row_position < self.backend.rows() - 1 {
+            self.row_position += 1;
+        } else {
+            self.backend.scroll(self.attributes);
+        }
+    }
+
+    fn clear_row(&mut self, row: usize) {
+        let columns = self.backend.columns();
+        self.backend.clear_cells(row, 0, columns, self.attributes);
+    }
+
+    fn apply_csi(&mut self, sequence: &CsiSequence) {
+        let (rows, columns) = (self.backend.rows(), self.backend.columns());
+        let row = self.row_position;
+        let col = self.column_position.min(columns - 1);
+        let count = sequence.param_or(0, 1) as usize;
+        let mode = sequence.params().first().cloned().unwrap_or(0);
+        let attributes = self.attributes;
+
+        match (sequence.private, sequence.final_byte) {
+            // Cursor up, down, forward, back
+            (false, b'A') => self.row_position = row.saturating_sub(count),
+            (false, b'B') => self.row_position = (row + count).min(rows - 1),
+            (false, b'C') => self.column_position = (col + count).min(columns - 1),
+            (false, b'D') => self.column_position = col.saturating_sub(count),
+            // Column, then row and column, 1 based
+            (false, b'G') => self.column_position = (count - 1).min(columns - 1),
+            (false, b'H') | (false, b'f') => {
+                self.row_position = (sequence.param_or(0, 1) as usize - 1).min(rows - 1);
+                self.column_position = (sequence.param_or(1, 1) as usize - 1).min(columns - 1);
+            }
+            // Erase display : after the cursor, before it, or everything
+            (false, b'J') => match mode {
+                0 => {
+                    self.backend.clear_cells(row, col, columns, attributes);
+                    for row in row + 1..rows {
+                        self.clear_row(row);
+                    }
+                }
+                1 => {
+                    for row in 0..row {
+                        self.clear_row(row);
+                    }
+                    self.backend.clear_cells(row, 0, col + 1, attributes);
+                }
+                _ => {
+                    for row in 0..rows {
+                        self.clear_row(row);
+                    }
+                }
+            },
+            // Erase line, same modes
+            (false, b'K') => match mode {
+                0 => self.backend.clear_cells(row, col, columns, attributes),
+                1 => self.backend.clear_cells(row, 0, col + 1, attributes),
+                _ => self.clear_row(row)
+            },
+            (false, b'm') => self.attributes.apply_sgr(sequence),
+            // Show and hide the cursor
+            (true, b'h') if mode == 25 => self.backend.set_cursor_visible(true),
+            (true, b'l') if mode == 25 => self.backend.set_cursor_visible(false),
+            _ => {}
+        }
+    }
+
+    // Blanks the screen with the current background and moves to the top left corner
+    pub fn clear_screen(&mut self) {
+        self.backend.clear(self.attributes);
+        self.row_position = 0;
+        self.column_position = 0;
+    }
+
+    pub fn set_position(&mut self, row: usize, col: usize) {
+        assert!(row < self.backend.rows() && col < self.backend.columns(), "Position ({}, {}) is outside of the screen.", row, col);
+        self.row_position = row;
+        self.column_position = col;
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    // Rows of characters, '.' being blank
+    struct Cells(Vec<Vec<char>>);
+
+    impl CellBackend for Cells {
+        fn rows(&self) -> usize {
+            self.0.len()
+        }
+
+        fn columns(&self) -> usize {
+            self.0[0].len()
+        }
+
+        fn put_cell(&mut self, row: usize, col: usize, character: char, _attributes: TextAttributes) {
+            self.0[row][col] = character;
+        }
+
+        fn clear_cells(&mut self, row: usize, start_col: usize, end_col: usize, _attributes: TextAttributes) {
+            for col in start_col..end_col {
+                self.0[row][col] = '.';
+            }
+        }
+
+        fn scroll(&mut self, _attributes: TextAttributes) {
+            self.0.remove(0);
+            self.0.push(vec!['.'; 4]);
+        }
+    }
+
+    fn grid() -> TextGrid<Cells> {
+        TextGrid::new(Cells(vec![vec!['.'; 4]; 3]), 0)
+    }
+
+    fn write(grid: &mut TextGrid<Cells>, s: &str) {
+        for byte in s.bytes() {
+            grid.write_byte(byte);
+        }
+    }
+
+    fn screen(grid: &TextGrid<Cells>) -> Vec<String> {
+        grid.backend().0.iter().map(|row| row.iter().collect()).collect()
+    }
+
+    #[test]
+    fn wrapping_and_scrolling() {
+        let mut grid = grid();
+        write(&mut grid, "abcd");
+        // A full row only wraps once the next character comes
+        assert_eq!(grid.position(), (0, 4));
+        write(&mut grid, "e\nf\ng");
+        assert_eq!(screen(&grid), ["e...", "f...", "g..."]);
+        assert_eq!(grid.position(), (2, 1));
+    }
+
+    #[test]
+    fn cursor_movement_stays_on_the_screen() {
+        let mut grid = grid();
+        write(&mut grid, "\x1b[10;10H");
+        assert_eq!(grid.position(), (2, 3));
+        write(&mut grid, "\x1b[5A\x1b[2D");
+        assert_eq!(grid.position(), (0, 1));
+        write(&mut grid, "\x1b[3G\x1b[B");
+        assert_eq!(grid.position(), (1, 2));
+    }
+
+    #[test]
+    fn erasing() {
+        let mut grid = grid();
+        write(&mut grid, "abcd\r\nefgh\r\nijkl\x1b[2;2H\x1b[K");
+        assert_eq!(screen(&grid), ["abcd", "e...", "ijkl"]);
+        write(&mut grid, "\x1b[1J");
+        assert_eq!(screen(&grid), ["....", "....", "ijkl"]);
+        write(&mut grid, "\x1b[1;3Hx\x1b[J");
+        assert_eq!(screen(&grid), ["..x.", "....", "...."]);
+    }
+
+    #[test]
+    fn colours_and_tabs() {
+        let mut grid = grid();
+        write(&mut grid, "\x1b[31;44m\tx");
+        assert_eq!(grid.color(), (Color::Red, Color::Blue));
+        assert_eq!(screen(&grid)[1], "x...");
+        write(&mut grid, "\x1b[0m");
+        assert_eq!(grid.color(), (DEFAULT_FOREGROUND, DEFAULT_BACKGROUND));
+    }
+}
@@ -1,10 +1,28 @@
+use crate::display::ansi::TextAttributes;
+use crate::display::charset::{to_cp437, REPLACEMENT_GLYPH};
+use crate::display::text_grid::{CellBackend, TextGrid};
+use crate::utils::port::{inb, outb};
 use core::ptr::Unique;
 use core::fmt;
 use volatile::Volatile;
 use spin::Mutex;
+use self::scrollback::{Row, Scrollback};
+
+pub mod scrollback;
+
+// CRTC registers, selected through the index port and accessed through the data port
+const CRTC_INDEX_PORT: u16 = 0x3d4;
+const CRTC_DATA_PORT: u16 = 0x3d5;
+const CRTC_CURSOR_START: u8 = 0x0a;
+const CRTC_CURSOR_END: u8 = 0x0b;
+const CRTC_CURSOR_LOCATION_HIGH: u8 = 0x0e;
+const CRTC_CURSOR_LOCATION_LOW: u8 = 0x0f;
+const CURSOR_DISABLE: u8 = 1 << 5;
+// Scanlines of a character cell, the cursor shape is given as a range of them
+pub const CHAR_HEIGHT: u8 = 16;
 
 #[allow(dead_code)]
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum Color {
     Black      = 0,
@@ -25,6 +43,34 @@ pub enum Color {
     White      = 15,
 }
 
+impl Color {
+    pub fn from_u8(value: u8) -> Color {
+        match value & 0xf {
+            0  => Color::Black,
+            1  => Color::Blue,
+            2  => Color::Green,
+            3  => Color::Cyan,
+            4  => Color::Red,
+            5  => Color::Magenta,
+            6  => Color::Brown,
+            7  => Color::LightGray,
+            8  => Color::DarkGray,
+            9  => Color::LightBlue,
+            10 => Color::LightGreen,
+            11 => Color::LightCyan,
+            12 => Color::LightRed,
+            13 => Color::Pink,
+            14 => Color::Yellow,
+            _  => Color::White,
+        }
+    }
+}
+
+pub const DEFAULT_FOREGROUND: Color = Color::LightGreen;
+pub const DEFAULT_BACKGROUND: Color = Color::Black;
+
+// Only read by the VGA hardware
+#[allow(dead_code)]
 #[derive(Debug, Clone, Copy)]
 struct ColorCode(u8);
 
@@ -36,57 +82,78 @@ impl ColorCode {
 
 #[derive(Debug, Clone, Copy)]
 #[repr(C)]
-struct ScreenChar {
+pub struct ScreenChar {
     ascii_character: u8,
     color_code: ColorCode,
 }
 
-const BUFFER_HEIGHT: usize = 25;
-const BUFFER_WIDTH: usize = 80;
+pub const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_WIDTH: usize = 80;
 
 struct Buffer {
     chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
-pub struct Writer {
-    column_position: usize,
-    color_code: ColorCode,
+// VGA text memory, keeping the lines scrolled off the top in the scrollback
+pub struct VgaText {
+    // Needs the heap, see Writer::enable_scrollback
+    scrollback: Option<Scrollback>,
     buffer: Unique<Buffer>,
 }
 
-impl Writer {
-    pub fn write_str(&mut self, s: &str) {
-        for byte in s.bytes() {
-            self.write_byte(byte)
+impl VgaText {
+    fn buffer(&mut self) -> &mut Buffer {
+        unsafe{ self.buffer.as_mut() }
+    }
+
+    fn read_row(&mut self, row: usize) -> Row {
+        let buffer = self.buffer();
+        let mut characters = [buffer.chars[row][0].read(); BUFFER_WIDTH];
+        for col in 1..BUFFER_WIDTH {
+            characters[col] = buffer.chars[row][col].read();
         }
+        characters
     }
 
-    pub fn write_byte(&mut self, byte: u8) {
-        match byte {
-            b'\n' => self.new_line(),
-            byte => {
-                if self.column_position >= BUFFER_WIDTH {
-                    self.new_line();
-                }
-
-                let row = BUFFER_HEIGHT - 1;
-                let col = self.column_position;
-
-                let color_code = self.color_code;
-                self.buffer().chars[row][col].write(ScreenChar {
-                    ascii_character: byte,
-                    color_code: color_code,
-                });
-                self.column_position += 1;
-            }
+    fn write_row(&mut self, row: usize, characters: &Row) {
+        for col in 0..BUFFER_WIDTH {
+            self.buffer().chars[row][col].write(characters[col]);
         }
     }
+}
 
-    fn buffer(&mut self) -> &mut Buffer {
-        unsafe{ self.buffer.as_mut() }
+impl CellBackend for VgaText {
+    fn rows(&self) -> usize {
+        BUFFER_HEIGHT
+    }
+
+    fn columns(&self) -> usize {
+        BUFFER_WIDTH
+    }
+
+    fn put_cell(&mut self, row: usize, col: usize, character: char, attributes: TextAttributes) {
+        self.buffer().chars[row][col].write(ScreenChar {
+            ascii_character: to_cp437(character).unwrap_or(REPLACEMENT_GLYPH),
+            color_code: ColorCode::new(attributes.foreground, attributes.background),
+        });
     }
 
-    fn new_line(&mut self) {
+    fn clear_cells(&mut self, row: usize, start_col: usize, end_col: usize, attributes: TextAttributes) {
+        let blank = ScreenChar {
+            ascii_character: b' ',
+            color_code: ColorCode::new(attributes.foreground, attributes.background),
+        };
+        for col in start_col..end_col {
+            self.buffer().chars[row][col].write(blank);
+        }
+    }
+
+    fn scroll(&mut self, attributes: TextAttributes) {
+        let top = self.read_row(0);
+        if let Some(scrollback) = self.scrollback.as_mut() {
+            scrollback.push(top);
+        }
+
         for row in 1..BUFFER_HEIGHT {
             for col in 0..BUFFER_WIDTH {
                 let buffer = self.buffer();
@@ -94,34 +161,193 @@ impl Writer {
                 buffer.chars[row - 1][col].write(character);
             }
         }
-        self.clear_row(BUFFER_HEIGHT-1);
-        self.column_position = 0;
+        self.clear_cells(BUFFER_HEIGHT - 1, 0, BUFFER_WIDTH, attributes);
+    }
+
+    fn set_cursor_visible(&mut self, visible: bool) {
+        unsafe { set_cursor_visible(visible) };
     }
+}
+
+pub struct Writer {
+    grid: TextGrid<VgaText>,
+}
 
-    fn clear_row(&mut self, row: usize) {
+impl Writer {
+    pub fn write_str(&mut self, s: &str) {
+        for byte in s.bytes() {
+            self.write_byte(byte)
+        }
+        self.update_cursor();
+    }
+
+    // Bytes are UTF-8 text with escape sequences, see display::text_grid
+    pub fn write_byte(&mut self, byte: u8) {
+        self.show_live();
+        self.grid.write_byte(byte);
+    }
+
+    // Keeps up to lines lines scrolled off the screen, 0 disables the scrollback
+    pub fn enable_scrollback(&mut self, lines: usize) {
+        self.show_live();
+        let (foreground, background) = self.color();
         let blank = ScreenChar {
             ascii_character: b' ',
-            color_code: self.color_code,
+            color_code: ColorCode::new(foreground, background),
         };
-        for col in 0..BUFFER_WIDTH {
-            self.buffer().chars[row][col].write(blank);
+        self.grid.backend_mut().scrollback = if lines > 0 { Some(Scrollback::new(lines, blank)) } else { None };
+    }
+
+    pub fn scroll_up(&mut self, lines: usize) {
+        let text = self.grid.backend_mut();
+        let (offset, scrolled) = match text.scrollback.as_ref() {
+            Some(scrollback) => (scrollback.offset(), scrollback.is_scrolled()),
+            None => return
+        };
+        if !scrolled {
+            for row in 0..BUFFER_HEIGHT {
+                let characters = text.read_row(row);
+                text.scrollback.as_mut().unwrap().save_live_row(row, characters);
+            }
         }
+        self.set_scroll_offset(offset + lines);
+    }
+
+    pub fn scroll_down(&mut self, lines: usize) {
+        let offset = match self.grid.backend().scrollback.as_ref() {
+            Some(scrollback) => scrollback.offset(),
+            None => return
+        };
+        self.set_scroll_offset(offset.saturating_sub(lines));
     }
+
+    // Shift+PageUp / Shift+PageDown, keeping one line of context
+    pub fn page_up(&mut self) {
+        self.scroll_up(BUFFER_HEIGHT - 1);
+    }
+
+    pub fn page_down(&mut self) {
+        self.scroll_down(BUFFER_HEIGHT - 1);
+    }
+
+    // Goes back to the live screen if scrolled back, writes always do this first
+    pub fn show_live(&mut self) {
+        if self.grid.backend().scrollback.as_ref().map_or(false, |scrollback| scrollback.is_scrolled()) {
+            self.set_scroll_offset(0);
+        }
+    }
+
+    fn set_scroll_offset(&mut self, offset: usize) {
+        let text = self.grid.backend_mut();
+        let mut scrollback = match text.scrollback.take() {
+            Some(scrollback) => scrollback,
+            None => return
+        };
+        if scrollback.set_offset(offset) {
+            for row in 0..BUFFER_HEIGHT {
+                text.write_row(row, scrollback.view_row(row));
+            }
+        }
+        text.scrollback = Some(scrollback);
+    }
+
+    // Blanks the screen with the current background and moves to the top left corner
+    pub fn clear_screen(&mut self) {
+        self.show_live();
+        self.grid.clear_screen();
+        self.update_cursor();
+    }
+
+    pub fn position(&self) -> (usize, usize) {
+        self.grid.position()
+    }
+
+    pub fn set_position(&mut self, row: usize, col: usize) {
+        self.grid.set_position(row, col);
+        self.update_cursor();
+    }
+
+    pub fn color(&self) -> (Color, Color) {
+        self.grid.color()
+    }
+
+    pub fn set_color(&mut self, foreground: Color, background: Color) {
+        self.grid.set_color(foreground, background);
+    }
+
+    pub fn set_foreground(&mut self, foreground: Color) {
+        let (_, background) = self.color();
+        self.set_color(foreground, background);
+    }
+
+    pub fn set_background(&mut self, background: Color) {
+        let (foreground, _) = self.color();
+        self.set_color(foreground, background);
+    }
+
+    pub fn reset_color(&mut self) {
+        self.grid.reset_color();
+    }
+
+    // Moves the blinking hardware cursor to where the next character will be written
+    pub fn update_cursor(&self) {
+        let (row, col) = self.position();
+        // A full last row leaves the column at BUFFER_WIDTH until the next character wraps
+        let location = (row * BUFFER_WIDTH + col.min(BUFFER_WIDTH - 1)) as u16;
+        unsafe {
+            write_crtc(CRTC_CURSOR_LOCATION_HIGH, (location >> 8) as u8);
+            write_crtc(CRTC_CURSOR_LOCATION_LOW, location as u8);
+        }
+    }
+
+    // The cursor covers scanlines start..=end of the character cell, e.g. 14..=15 for an
+    // underline or 0..=15 for a block
+    pub fn set_cursor_shape(&mut self, start: u8, end: u8) {
+        assert!(start <= end && end < CHAR_HEIGHT, "Invalid cursor shape {}..={}.", start, end);
+        unsafe {
+            // The upper bits of both registers are kept, they hold unrelated settings
+            write_crtc(CRTC_CURSOR_START, (read_crtc(CRTC_CURSOR_START) & 0xc0) | start);
+            write_crtc(CRTC_CURSOR_END, (read_crtc(CRTC_CURSOR_END) & 0xe0) | end);
+        }
+    }
+
+    pub fn enable_cursor(&mut self) {
+        unsafe { set_cursor_visible(true) };
+    }
+
+    pub fn disable_cursor(&mut self) {
+        unsafe { set_cursor_visible(false) };
+    }
+}
+
+unsafe fn set_cursor_visible(visible: bool) {
+    let start = read_crtc(CRTC_CURSOR_START);
+    write_crtc(CRTC_CURSOR_START, if visible { start & !CURSOR_DISABLE } else { start | CURSOR_DISABLE });
+}
+
+unsafe fn write_crtc(register: u8, value: u8) {
+    outb(CRTC_INDEX_PORT, register);
+    outb(CRTC_DATA_PORT, value);
+}
+
+unsafe fn read_crtc(register: u8) -> u8 {
+    outb(CRTC_INDEX_PORT, register);
+    inb(CRTC_DATA_PORT)
 }
 
 impl fmt::Write for Writer {
     fn write_str(&mut self, s: &str) -> fmt::Result {
-        for byte in s.bytes() {
-            self.write_byte(byte)
-        }
+        Writer::write_str(self, s);
         Ok(())
     }
 }
 
+// Output starts on the last row, below what the bootloader left on screen
 pub static WRITER: Mutex<Writer> = Mutex::new(Writer {
-    column_position: 0,
-    color_code: ColorCode::new(Color::LightGreen, Color::Black),
-    buffer: unsafe { Unique::new_unchecked(0xb8000 as *mut _) },
+    grid: TextGrid::new(VgaText {
+        scrollback: None,
+        buffer: unsafe { Unique::new_unchecked(0xb8000 as *mut _) },
+    }, BUFFER_HEIGHT - 1),
 });
 
 pub fn print(args: fmt::Arguments) {
@@ -129,9 +355,44 @@ pub fn print(args: fmt::Arguments) {
     WRITER.lock().write_fmt(args).unwrap();
 }
 
+// Prints with the given colours, then goes back to the previous ones
+pub fn print_colored(foreground: Color, background: Color, args: fmt::Arguments) {
+    use core::fmt::Write;
+    let mut writer = WRITER.lock();
+    let previous = writer.color();
+    writer.set_color(foreground, background);
+    writer.write_fmt(args).unwrap();
+    writer.set_color(previous.0, previous.1);
+}
+
+pub fn clear_screen() {
+    WRITER.lock().clear_screen();
+}
+
+// Called once the heap exists
+pub fn enable_scrollback(lines: usize) {
+    WRITER.lock().enable_scrollback(lines);
+}
+
+pub fn scroll_up(lines: usize) {
+    WRITER.lock().scroll_up(lines);
+}
+
+pub fn scroll_down(lines: usize) {
+    WRITER.lock().scroll_down(lines);
+}
+
+pub fn page_up() {
+    WRITER.lock().page_up();
+}
+
+pub fn page_down() {
+    WRITER.lock().page_down();
+}
+
 macro_rules! print {
     ($($arg:tt)*) => ({
-        $crate::display::vga::print(format_args!($($arg)*));
+        $crate::display::print(format_args!($($arg)*));
     });
 }
 
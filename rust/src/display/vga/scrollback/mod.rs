@@ -0,0 +1,153 @@
+// Lines scrolled off the top of the VGA screen, kept on the heap so they can be viewed again.
+//
+// While scrolled back the screen shows the history, and the live screen is kept aside until the
+// view goes back to the bottom. Everything is allocated upfront, since lines are pushed with the
+// writer locked, possibly while logging from the allocator itself.
+
+use super::{ScreenChar, BUFFER_HEIGHT, BUFFER_WIDTH};
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+
+pub const DEFAULT_SCROLLBACK_LINES: usize = 1000;
+
+pub type Row = [ScreenChar; BUFFER_WIDTH];
+
+pub struct Scrollback {
+    lines: VecDeque<Row>,
+    capacity: usize,
+    // Number of lines the view is scrolled up by, 0 showing the live screen
+    offset: usize,
+    live: Vec<Row>
+}
+
+impl Scrollback {
+    pub fn new(capacity: usize, blank: ScreenChar) -> Scrollback {
+        let mut live = Vec::with_capacity(BUFFER_HEIGHT);
+        live.resize(BUFFER_HEIGHT, [blank; BUFFER_WIDTH]);
+        Scrollback {
+            lines: VecDeque::with_capacity(capacity),
+            capacity,
+            offset: 0,
+            live
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn is_scrolled(&self) -> bool {
+        self.offset > 0
+    }
+
+    pub fn push(&mut self, row: Row) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.lines.len() == self.capacity {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(row);
+    }
+
+    // The live screen is saved when scrolling away from it
+    pub fn save_live_row(&mut self, index: usize, row: Row) {
+        self.live[index] = row;
+    }
+
+    pub fn live(&self) -> &[Row] {
+        &self.live
+    }
+
+    // Returns whether the offset changed
+    pub fn set_offset(&mut self, offset: usize) -> bool {
+        let offset = offset.min(self.lines.len());
+        let changed = offset != self.offset;
+        self.offset = offset;
+        changed
+    }
+
+    // Row of the screen at the current offset, history lines followed by the live screen
+    pub fn view_row(&self, row: usize) -> &Row {
+        let index = self.lines.len() - self.offset + row;
+        if index < self.lines.len() {
+            &self.lines[index]
+        }
+        else {
+            &self.live[index - self.lines.len()]
+        }
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use crate::display::vga::{Color, ColorCode};
+
+    fn row(character: u8) -> Row {
+        [ScreenChar { ascii_character: character, color_code: ColorCode::new(Color::White, Color::Black) }; BUFFER_WIDTH]
+    }
+
+    fn character(row: &Row) -> u8 {
+        row[0].ascii_character
+    }
+
+    #[test]
+    fn oldest_lines_are_evicted() {
+        let mut scrollback = Scrollback::new(3, row(b' ')[0]);
+        for line in b'a'..=b'e' {
+            scrollback.push(row(line));
+        }
+        assert_eq!(scrollback.len(), 3);
+        scrollback.set_offset(3);
+        let history: Vec<u8> = (0..3).map(|index| character(scrollback.view_row(index))).collect();
+        assert_eq!(history, b"cde");
+    }
+
+    #[test]
+    fn zero_capacity_keeps_nothing() {
+        let mut scrollback = Scrollback::new(0, row(b' ')[0]);
+        scrollback.push(row(b'a'));
+        assert!(scrollback.is_empty());
+        assert!(!scrollback.set_offset(1));
+        assert!(!scrollback.is_scrolled());
+    }
+
+    #[test]
+    fn offset_is_clamped_to_the_history() {
+        let mut scrollback = Scrollback::new(10, row(b' ')[0]);
+        scrollback.push(row(b'a'));
+        scrollback.push(row(b'b'));
+
+        assert!(scrollback.set_offset(5));
+        assert_eq!(scrollback.offset(), 2);
+        assert!(!scrollback.set_offset(2));
+        assert!(scrollback.set_offset(0));
+        assert!(!scrollback.is_scrolled());
+    }
+
+    #[test]
+    fn view_mixes_history_and_live_rows() {
+        let mut scrollback = Scrollback::new(10, row(b' ')[0]);
+        for line in b'a'..=b'c' {
+            scrollback.push(row(line));
+        }
+        for index in 0..BUFFER_HEIGHT {
+            scrollback.save_live_row(index, row(b'0' + index as u8));
+        }
+
+        scrollback.set_offset(2);
+        assert_eq!(character(scrollback.view_row(0)), b'b');
+        assert_eq!(character(scrollback.view_row(1)), b'c');
+        assert_eq!(character(scrollback.view_row(2)), b'0');
+        assert_eq!(character(scrollback.view_row(BUFFER_HEIGHT - 1)), b'0' + (BUFFER_HEIGHT - 3) as u8);
+    }
+}
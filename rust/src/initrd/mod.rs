@@ -0,0 +1,105 @@
+// Boot modules and the initial ramdisk.
+//
+// Limine loads the files listed as MODULE_PATH in limine.cfg next to the kernel and passes their
+// physical ranges in the stivale2 modules tag. Their frames are marked as used in the frame
+// allocator (protect_modules) and their contents read through the direct map, which covers all of
+// physical memory. The module whose string is "initrd" is a USTAR archive (see tar), built by
+// buildimage.sh out of the initrd directory.
+
+pub mod tar;
+
+use crate::initrd::tar::{TarArchive, TarEntry};
+use crate::memory::frame_allocator::BitMapFrameAllocator;
+use crate::memory::layout::layout;
+use crate::stivale2::{modules, Module};
+use spin::Once;
+
+pub const INITRD_MODULE_STRING: &str = "initrd";
+
+#[derive(Clone, Copy)]
+pub struct BootModule {
+    pub module: Module,
+    pub data: &'static [u8]
+}
+
+impl BootModule {
+    pub fn string(&self) -> &str {
+        self.module.string()
+    }
+
+    pub fn physical_start(&self) -> usize {
+        self.module.begin as usize
+    }
+}
+
+// Keeps the frame allocator from handing out the modules' frames. Bootloaders put them in memory
+// marked as used by the kernel, this makes sure of it whatever the memory map says.
+pub fn protect_modules(frame_allocator: &mut BitMapFrameAllocator) {
+    for module in modules() {
+        frame_allocator.mark_region(module.begin as usize, module.end as usize, true);
+    }
+}
+
+// Needs the kernel layout, modules are read through the direct map
+pub fn boot_modules() -> impl Iterator<Item = BootModule> {
+    modules().map(|module| {
+        let data = unsafe {
+            core::slice::from_raw_parts(
+                layout().physical_to_direct_map(module.begin as usize) as *const u8,
+                module.size()
+            )
+        };
+        BootModule { module, data }
+    })
+}
+
+// First module with the given string
+pub fn boot_module(string: &str) -> Option<BootModule> {
+    boot_modules().find(|module| module.string() == string)
+}
+
+static INITRD: Once<Option<TarArchive<'static>>> = Once::new();
+
+// Looks for the initrd module and checks the archive, logging what was found
+pub fn init() {
+    INITRD.call_once(|| {
+        for module in boot_modules() {
+            info!("Boot module \"{}\" : {} bytes at 0x{:x}", module.string(), module.data.len(), module.physical_start());
+        }
+
+        let module = boot_module(INITRD_MODULE_STRING)?;
+        let archive = TarArchive::new(module.data);
+        match archive.validate() {
+            Ok(count) => {
+                info!("Initrd holds {} entries", count);
+                for entry in archive.entries().flatten() {
+                    debug!("  {:?} {}{}{} ({} bytes)",
+                           entry.kind,
+                           entry.prefix,
+                           if entry.prefix.is_empty() { "" } else { "/" },
+                           entry.name,
+                           entry.data.len()
+                    );
+                }
+                Some(archive)
+            }
+            Err(error) => {
+                warn!("Ignoring the initrd, it is not a valid tar archive ({:?})", error);
+                None
+            }
+        }
+    });
+}
+
+pub fn initrd() -> Option<&'static TarArchive<'static>> {
+    INITRD.r#try().and_then(|initrd| initrd.as_ref())
+}
+
+pub fn find(path: &str) -> Option<TarEntry<'static>> {
+    initrd().and_then(|initrd| initrd.find(path))
+}
+
+// Contents of a regular file of the initrd
+pub fn read_file(path: &str) -> Option<&'static [u8]> {
+    initrd().and_then(|initrd| initrd.read_file(path))
+}
@@ -0,0 +1,278 @@
+// USTAR archive reader, for the initial ramdisk. Works in place on the archive's bytes, nothing is
+// copied or allocated.
+//
+// An archive is a sequence of 512 byte headers, each followed by the entry's data padded to 512
+// bytes, and ends with two zeroed blocks. GNU and pax extensions (long names, extended headers)
+// are listed as entries of their own kind and otherwise ignored, so names are limited to the 155
+// byte prefix plus the 100 byte name of USTAR.
+
+pub const BLOCK_SIZE: usize = 512;
+
+const NAME: (usize, usize) = (0, 100);
+const MODE: (usize, usize) = (100, 108);
+const SIZE: (usize, usize) = (124, 136);
+const CHECKSUM: (usize, usize) = (148, 156);
+const TYPE_FLAG: usize = 156;
+const LINK_NAME: (usize, usize) = (157, 257);
+const MAGIC: (usize, usize) = (257, 263);
+const PREFIX: (usize, usize) = (345, 500);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TarError {
+    Truncated,
+    BadChecksum,
+    BadMagic,
+    BadNumber
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    HardLink,
+    SymbolicLink,
+    Directory,
+    Other(u8)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TarEntry<'a> {
+    // The full path is prefix/name when prefix isn't empty
+    pub prefix: &'a str,
+    pub name: &'a str,
+    pub link_name: &'a str,
+    pub mode: u32,
+    pub kind: EntryKind,
+    pub data: &'a [u8]
+}
+
+impl<'a> TarEntry<'a> {
+    // Compares the entry's path with path, ignoring leading "./" and "/" and trailing "/"
+    pub fn path_is(&self, path: &str) -> bool {
+        let path = normalise(path);
+        if self.prefix.is_empty() {
+            return normalise(self.name) == path;
+        }
+        let prefix = normalise(self.prefix);
+        path.len() > prefix.len()
+            && path.starts_with(prefix)
+            && path.as_bytes()[prefix.len()] == b'/'
+            && path[prefix.len() + 1..] == *normalise(self.name)
+    }
+}
+
+fn normalise(path: &str) -> &str {
+    let mut path = path;
+    loop {
+        if let Some(rest) = path.strip_prefix("./") {
+            path = rest;
+        } else if let Some(rest) = path.strip_prefix('/') {
+            path = rest;
+        } else {
+            break;
+        }
+    }
+    path.trim_end_matches('/')
+}
+
+fn field(header: &[u8], (start, end): (usize, usize)) -> &[u8] {
+    let field = &header[start..end];
+    let length = field.iter().position(|&byte| byte == 0).unwrap_or(field.len());
+    &field[..length]
+}
+
+fn string_field(header: &[u8], range: (usize, usize)) -> &str {
+    core::str::from_utf8(field(header, range)).unwrap_or("")
+}
+
+// Octal, padded with spaces or nul bytes
+fn octal_field(header: &[u8], range: (usize, usize)) -> Result<u64, TarError> {
+    let digits = field(header, range);
+    let digits = core::str::from_utf8(digits).map_err(|_| TarError::BadNumber)?.trim();
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8).map_err(|_| TarError::BadNumber)
+}
+
+// Unsigned sum of the header's bytes, the checksum field counting as spaces
+fn checksum(header: &[u8]) -> u64 {
+    header.iter().enumerate().map(|(index, &byte)| {
+        if index >= CHECKSUM.0 && index < CHECKSUM.1 { b' ' as u64 } else { byte as u64 }
+    }).sum()
+}
+
+#[derive(Clone, Copy)]
+pub struct TarArchive<'a> {
+    data: &'a [u8]
+}
+
+impl<'a> TarArchive<'a> {
+    pub fn new(data: &'a [u8]) -> TarArchive<'a> {
+        TarArchive { data }
+    }
+
+    // Entries in archive order. Iteration stops after the first error.
+    pub fn entries(&self) -> Entries<'a> {
+        Entries { data: self.data, offset: 0, done: false }
+    }
+
+    // Checks every header, so later lookups can't run into a broken one
+    pub fn validate(&self) -> Result<usize, TarError> {
+        let mut count = 0;
+        for entry in self.entries() {
+            entry?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    pub fn find(&self, path: &str) -> Option<TarEntry<'a>> {
+        self.entries().filter_map(Result::ok).find(|entry| entry.path_is(path))
+    }
+
+    // Contents of a regular file
+    pub fn read_file(&self, path: &str) -> Option<&'a [u8]> {
+        self.find(path).filter(|entry| entry.kind == EntryKind::File).map(|entry| entry.data)
+    }
+}
+
+pub struct Entries<'a> {
+    data: &'a [u8],
+    offset: usize,
+    done: bool
+}
+
+impl<'a> Entries<'a> {
+    fn parse_next(&mut self) -> Result<Option<TarEntry<'a>>, TarError> {
+        // A missing end of archive marker is tolerated
+        if self.offset == self.data.len() {
+            return Ok(None);
+        }
+        if self.offset + BLOCK_SIZE > self.data.len() {
+            return Err(TarError::Truncated);
+        }
+        let header = &self.data[self.offset..self.offset + BLOCK_SIZE];
+        if header.iter().all(|&byte| byte == 0) {
+            return Ok(None);
+        }
+
+        if octal_field(header, CHECKSUM)? != checksum(header) {
+            return Err(TarError::BadChecksum);
+        }
+        // "ustar\0" for POSIX archives, "ustar " for old GNU ones
+        if &header[MAGIC.0..MAGIC.0 + 5] != b"ustar" {
+            return Err(TarError::BadMagic);
+        }
+
+        let size = octal_field(header, SIZE)? as usize;
+        let data_start = self.offset + BLOCK_SIZE;
+        let data_end = data_start.checked_add(size).ok_or(TarError::Truncated)?;
+        if data_end > self.data.len() {
+            return Err(TarError::Truncated);
+        }
+
+        let kind = match header[TYPE_FLAG] {
+            b'0' | 0 => EntryKind::File,
+            b'1' => EntryKind::HardLink,
+            b'2' => EntryKind::SymbolicLink,
+            b'5' => EntryKind::Directory,
+            other => EntryKind::Other(other)
+        };
+
+        let padded_size = (size + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+        self.offset = (data_start + padded_size).min(self.data.len());
+
+        Ok(Some(TarEntry {
+            prefix: string_field(header, PREFIX),
+            name: string_field(header, NAME),
+            link_name: string_field(header, LINK_NAME),
+            mode: octal_field(header, MODE)? as u32,
+            kind,
+            data: &self.data[data_start..data_end]
+        }))
+    }
+}
+
+impl<'a> Iterator for Entries<'a> {
+    type Item = Result<TarEntry<'a>, TarError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let result = self.parse_next();
+        match result {
+            Ok(Some(entry)) => Some(Ok(entry)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(error) => {
+                self.done = true;
+                Some(Err(error))
+            }
+        }
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    fn header(name: &str, prefix: &str, kind: u8, size: usize) -> Vec<u8> {
+        let mut header = vec![0u8; BLOCK_SIZE];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[PREFIX.0..PREFIX.0 + prefix.len()].copy_from_slice(prefix.as_bytes());
+        header[MODE.0..MODE.0 + 7].copy_from_slice(b"0000644");
+        header[SIZE.0..SIZE.0 + 11].copy_from_slice(format!("{:011o}", size).as_bytes());
+        header[TYPE_FLAG] = kind;
+        header[MAGIC.0..MAGIC.0 + 6].copy_from_slice(b"ustar\0");
+        let sum = checksum(&header);
+        header[CHECKSUM.0..CHECKSUM.0 + 7].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+        header
+    }
+
+    fn archive(entries: &[(&str, &str, u8, &[u8])]) -> Vec<u8> {
+        let mut archive = Vec::new();
+        for &(name, prefix, kind, data) in entries {
+            archive.extend(header(name, prefix, kind, data.len()));
+            archive.extend_from_slice(data);
+            archive.resize((archive.len() + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE, 0);
+        }
+        archive.resize(archive.len() + 2 * BLOCK_SIZE, 0);
+        archive
+    }
+
+    #[test]
+    fn files_are_found() {
+        let data = archive(&[
+            ("./", "", b'5', b""),
+            ("./motd", "", b'0', b"Hello from the initrd\n"),
+            ("fonts/big.psf", "usr/share", b'0', &[7u8; 600]),
+            ("bin", "", b'5', b"")
+        ]);
+        let archive = TarArchive::new(&data);
+        assert_eq!(archive.validate(), Ok(4));
+        assert_eq!(archive.read_file("motd"), Some(&b"Hello from the initrd\n"[..]));
+        assert_eq!(archive.read_file("/motd"), Some(&b"Hello from the initrd\n"[..]));
+        assert_eq!(archive.read_file("usr/share/fonts/big.psf").map(|data| data.len()), Some(600));
+        assert_eq!(archive.find("bin/").map(|entry| entry.kind), Some(EntryKind::Directory));
+        assert!(archive.read_file("bin").is_none());
+        assert!(archive.find("share/fonts/big.psf").is_none());
+    }
+
+    #[test]
+    fn broken_archives_are_reported() {
+        let mut data = archive(&[("a", "", b'0', b"abc"), ("b", "", b'0', b"def")]);
+        data[BLOCK_SIZE * 2 + 10] ^= 1;
+        let entries: Vec<_> = TarArchive::new(&data).entries().collect();
+        assert_eq!(entries.len(), 2);
+        assert!(entries[0].is_ok());
+        assert_eq!(entries[1].err(), Some(TarError::BadChecksum));
+
+        let data = archive(&[("a", "", b'0', &[1u8; 1000])]);
+        assert_eq!(TarArchive::new(&data[..700]).validate(), Err(TarError::Truncated));
+        assert_eq!(TarArchive::new(&[]).validate(), Ok(0));
+    }
+}
@@ -0,0 +1,435 @@
+// Local APIC and IOAPIC interrupt controllers.
+//
+// The local APIC of the boot processor is used in xAPIC mode through its MMIO page, and every
+// IOAPIC listed in the MADT gets all its inputs masked at init. Inputs are then routed, in
+// physical destination mode to the boot processor, as handlers get registered (see irq). The
+// polarity and trigger mode of each GSI come from the MADT's interrupt source overrides.
+
+use crate::acpi::madt::{InterruptOverride, Madt, Polarity, TriggerMode, ALL_PROCESSORS};
+use crate::interrupts::irq::{InterruptController, ISA_IRQ_COUNT};
+use crate::interrupts::{set_handler, InterruptStackFrame};
+use crate::memory::layout::map_mmio;
+use crate::memory::frame_allocator::FRAME_SIZE;
+use crate::utils::msr::{read_msr, write_msr, IA32_APIC_BASE};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::{Mutex, Once};
+
+pub const ERROR_VECTOR: usize = 0xfe;
+// Has to end in 0xf on older processors
+pub const SPURIOUS_VECTOR: usize = 0xff;
+
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+// Local APIC registers
+pub const REGISTER_ID: usize = 0x20;
+pub const REGISTER_VERSION: usize = 0x30;
+pub const REGISTER_TASK_PRIORITY: usize = 0x80;
+pub const REGISTER_EOI: usize = 0xb0;
+pub const REGISTER_SPURIOUS: usize = 0xf0;
+pub const REGISTER_ERROR_STATUS: usize = 0x280;
+pub const REGISTER_ICR_LOW: usize = 0x300;
+pub const REGISTER_ICR_HIGH: usize = 0x310;
+pub const REGISTER_LVT_TIMER: usize = 0x320;
+pub const REGISTER_LVT_LINT0: usize = 0x350;
+pub const REGISTER_LVT_LINT1: usize = 0x360;
+pub const REGISTER_LVT_ERROR: usize = 0x370;
+pub const REGISTER_TIMER_INITIAL_COUNT: usize = 0x380;
+pub const REGISTER_TIMER_CURRENT_COUNT: usize = 0x390;
+pub const REGISTER_TIMER_DIVIDE: usize = 0x3e0;
+
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+pub const LVT_MASKED: u32 = 1 << 16;
+const LVT_DELIVERY_NMI: u32 = 0b100 << 8;
+const LVT_ACTIVE_LOW: u32 = 1 << 13;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+// The timer counts down at the bus clock divided by 16
+pub const TIMER_DIVIDER: u32 = 16;
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_DESTINATION_SELF: u32 = 0b01 << 18;
+
+// IOAPIC registers, accessed through a register select / data window pair
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+pub struct LocalApic {
+    base: usize
+}
+
+impl LocalApic {
+    pub unsafe fn read(&self, register: usize) -> u32 {
+        core::ptr::read_volatile((self.base + register) as *const u32)
+    }
+
+    pub unsafe fn write(&self, register: usize, value: u32) {
+        core::ptr::write_volatile((self.base + register) as *mut u32, value);
+    }
+
+    pub fn id(&self) -> u32 {
+        unsafe { self.read(REGISTER_ID) >> 24 }
+    }
+
+    pub fn end_of_interrupt(&self) {
+        unsafe { self.write(REGISTER_EOI, 0) };
+    }
+
+    // Counts down from initial_count, raising vector when reaching 0 and starting over in periodic
+    // mode. An initial count of 0 stops the timer.
+    pub fn start_timer(&self, vector: u8, initial_count: u32, periodic: bool) {
+        unsafe {
+            self.write(REGISTER_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+            self.write(REGISTER_LVT_TIMER, vector as u32 | if periodic { LVT_TIMER_PERIODIC } else { 0 });
+            self.write(REGISTER_TIMER_INITIAL_COUNT, initial_count);
+        }
+    }
+
+    pub fn stop_timer(&self) {
+        unsafe {
+            self.write(REGISTER_LVT_TIMER, LVT_MASKED);
+            self.write(REGISTER_TIMER_INITIAL_COUNT, 0);
+        }
+    }
+
+    pub fn timer_count(&self) -> u32 {
+        unsafe { self.read(REGISTER_TIMER_CURRENT_COUNT) }
+    }
+
+    // Raises vector on the current processor
+    pub fn send_self_ipi(&self, vector: u8) {
+        unsafe {
+            self.write(REGISTER_ICR_HIGH, 0);
+            self.write(REGISTER_ICR_LOW, ICR_DESTINATION_SELF | vector as u32);
+            while self.read(REGISTER_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
+
+pub struct IoApic {
+    pub id: u8,
+    pub gsi_base: u32,
+    pub input_count: u32,
+    base: usize,
+    // Serializes register select / data window accesses
+    lock: Mutex<()>
+}
+
+impl IoApic {
+    unsafe fn new(id: u8, physical_address: usize, gsi_base: u32) -> IoApic {
+        let mut io_apic = IoApic {
+            id,
+            gsi_base,
+            input_count: 0,
+            base: map_mmio(physical_address, FRAME_SIZE),
+            lock: Mutex::new(())
+        };
+        io_apic.input_count = ((io_apic.read(IOAPIC_VERSION) >> 16) & 0xff) + 1;
+        io_apic
+    }
+
+    unsafe fn read(&self, register: u32) -> u32 {
+        let _guard = self.lock.lock();
+        core::ptr::write_volatile((self.base + IOREGSEL) as *mut u32, register);
+        core::ptr::read_volatile((self.base + IOWIN) as *const u32)
+    }
+
+    unsafe fn write(&self, register: u32, value: u32) {
+        let _guard = self.lock.lock();
+        core::ptr::write_volatile((self.base + IOREGSEL) as *mut u32, register);
+        core::ptr::write_volatile((self.base + IOWIN) as *mut u32, value);
+    }
+
+    pub fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.input_count
+    }
+
+    pub unsafe fn read_redirection(&self, input: u32) -> u64 {
+        let register = IOAPIC_REDIRECTION_TABLE + input * 2;
+        (self.read(register + 1) as u64) << 32 | self.read(register) as u64
+    }
+
+    // The low half, holding the mask bit, is written last
+    pub unsafe fn write_redirection(&self, input: u32, entry: u64) {
+        let register = IOAPIC_REDIRECTION_TABLE + input * 2;
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+}
+
+// Fixed delivery, physical destination mode
+pub fn redirection_entry(vector: u8, polarity: Polarity, trigger: TriggerMode, destination: u8, masked: bool) -> u64 {
+    let mut entry = vector as u64 | (destination as u64) << 56;
+    if polarity == Polarity::ActiveLow {
+        entry |= REDIRECTION_ACTIVE_LOW;
+    }
+    if trigger == TriggerMode::Level {
+        entry |= REDIRECTION_LEVEL;
+    }
+    if masked {
+        entry |= REDIRECTION_MASKED;
+    }
+    entry
+}
+
+// How a GSI is signaled. ISA interrupts are edge triggered and active high unless overridden,
+// other GSIs are PCI interrupts, level triggered and active low.
+pub fn gsi_signaling(overrides: &[InterruptOverride], gsi: u32) -> (Polarity, TriggerMode) {
+    let (polarity, trigger, is_isa) = match overrides.iter().find(|interrupt_override| interrupt_override.gsi == gsi) {
+        Some(interrupt_override) => (interrupt_override.polarity, interrupt_override.trigger, true),
+        None => (Polarity::BusDefault, TriggerMode::BusDefault, gsi < ISA_IRQ_COUNT as u32)
+    };
+    let polarity = match polarity {
+        Polarity::BusDefault if is_isa => Polarity::ActiveHigh,
+        Polarity::BusDefault => Polarity::ActiveLow,
+        polarity => polarity
+    };
+    let trigger = match trigger {
+        TriggerMode::BusDefault if is_isa => TriggerMode::Edge,
+        TriggerMode::BusDefault => TriggerMode::Level,
+        trigger => trigger
+    };
+    (polarity, trigger)
+}
+
+// GSI an ISA IRQ is wired to, which is the IRQ number unless overridden
+pub fn isa_irq_gsi(overrides: &[InterruptOverride], irq: u8) -> u32 {
+    match overrides.iter().find(|interrupt_override| interrupt_override.bus == 0 && interrupt_override.source == irq) {
+        Some(interrupt_override) => interrupt_override.gsi,
+        None => irq as u32
+    }
+}
+
+pub struct ApicController {
+    pub local_apic: LocalApic,
+    pub io_apics: Vec<IoApic>,
+    overrides: Vec<InterruptOverride>
+}
+
+impl ApicController {
+    fn io_apic_for_gsi(&self, gsi: u32) -> Option<&IoApic> {
+        self.io_apics.iter().find(|io_apic| io_apic.handles(gsi))
+    }
+}
+
+impl InterruptController for ApicController {
+    fn name(&self) -> &'static str {
+        "APIC"
+    }
+
+    fn isa_irq_to_gsi(&self, irq: u8) -> u32 {
+        isa_irq_gsi(&self.overrides, irq)
+    }
+
+    fn has_gsi(&self, gsi: u32) -> bool {
+        self.io_apic_for_gsi(gsi).is_some()
+    }
+
+    unsafe fn route(&self, gsi: u32, vector: u8) {
+        if let Some(io_apic) = self.io_apic_for_gsi(gsi) {
+            let (polarity, trigger) = gsi_signaling(&self.overrides, gsi);
+            let entry = redirection_entry(vector, polarity, trigger, self.local_apic.id() as u8, true);
+            io_apic.write_redirection(gsi - io_apic.gsi_base, entry);
+        }
+    }
+
+    unsafe fn set_masked(&self, gsi: u32, masked: bool) {
+        if let Some(io_apic) = self.io_apic_for_gsi(gsi) {
+            let input = gsi - io_apic.gsi_base;
+            let entry = io_apic.read_redirection(input);
+            let entry = if masked { entry | REDIRECTION_MASKED } else { entry & !REDIRECTION_MASKED };
+            io_apic.write_redirection(input, entry);
+        }
+    }
+
+    unsafe fn end_of_interrupt(&self, _gsi: u32) {
+        self.local_apic.end_of_interrupt();
+    }
+}
+
+static APIC: Once<ApicController> = Once::new();
+static ERRORS: AtomicUsize = AtomicUsize::new(0);
+
+extern "x86-interrupt" fn spurious_handler(_stack_frame: InterruptStackFrame) {
+    // Spurious interrupts must not be acknowledged
+}
+
+extern "x86-interrupt" fn error_handler(_stack_frame: InterruptStackFrame) {
+    ERRORS.fetch_add(1, Ordering::Relaxed);
+    if let Some(apic) = APIC.r#try() {
+        unsafe { apic.local_apic.write(REGISTER_ERROR_STATUS, 0) };
+        apic.local_apic.end_of_interrupt();
+    }
+}
+
+unsafe fn init_local_apic(madt: &Madt) -> LocalApic {
+    let physical_address = madt.local_apic_address & APIC_BASE_ADDRESS_MASK;
+    write_msr(IA32_APIC_BASE, (read_msr(IA32_APIC_BASE) & !APIC_BASE_ADDRESS_MASK) | physical_address | APIC_BASE_ENABLE);
+    let local_apic = LocalApic {
+        base: map_mmio(physical_address as usize, FRAME_SIZE)
+    };
+
+    local_apic.write(REGISTER_TASK_PRIORITY, 0);
+    local_apic.write(REGISTER_LVT_TIMER, LVT_MASKED);
+    local_apic.write(REGISTER_LVT_LINT0, LVT_MASKED);
+    local_apic.write(REGISTER_LVT_LINT1, LVT_MASKED);
+    local_apic.write(REGISTER_LVT_ERROR, ERROR_VECTOR as u32);
+    // The error status register is updated by writing to it
+    local_apic.write(REGISTER_ERROR_STATUS, 0);
+    local_apic.write(REGISTER_ERROR_STATUS, 0);
+
+    // NMIs wired to the local interrupt pins, usually LINT1
+    let id = local_apic.id();
+    let processor_uid = madt.processors.iter().find(|processor| processor.apic_id == id).map(|processor| processor.processor_uid);
+    for nmi in madt.nmis.iter().filter(|nmi| nmi.processor_uid == ALL_PROCESSORS || Some(nmi.processor_uid) == processor_uid) {
+        let register = if nmi.lint == 0 { REGISTER_LVT_LINT0 } else { REGISTER_LVT_LINT1 };
+        let polarity = if nmi.polarity == Polarity::ActiveLow { LVT_ACTIVE_LOW } else { 0 };
+        local_apic.write(register, LVT_DELIVERY_NMI | polarity);
+    }
+
+    local_apic.write(REGISTER_SPURIOUS, SPURIOUS_VECTOR as u32 | SPURIOUS_APIC_ENABLE);
+    local_apic.end_of_interrupt();
+    local_apic
+}
+
+// Enables the boot processor's local APIC and masks every IOAPIC input. The 8259 PICs have to be
+// remapped and masked already.
+pub unsafe fn init(madt: &Madt) -> &'static ApicController {
+    APIC.call_once(|| {
+        set_handler(SPURIOUS_VECTOR, spurious_handler);
+        set_handler(ERROR_VECTOR, error_handler);
+        let local_apic = init_local_apic(madt);
+
+        let io_apics: Vec<IoApic> = madt.io_apics.iter().map(|io_apic| {
+            let io_apic = IoApic::new(io_apic.id, io_apic.address as usize, io_apic.gsi_base);
+            for input in 0..io_apic.input_count {
+                io_apic.write_redirection(input, REDIRECTION_MASKED);
+            }
+            io_apic
+        }).collect();
+
+        info!("Local APIC {} enabled (version 0x{:x}), {} IOAPICs",
+              local_apic.id(),
+              local_apic.read(REGISTER_VERSION) & 0xff,
+              io_apics.len()
+        );
+        for io_apic in io_apics.iter() {
+            debug!("  IOAPIC {} : GSIs {}-{}", io_apic.id, io_apic.gsi_base, io_apic.gsi_base + io_apic.input_count - 1);
+        }
+
+        ApicController {
+            local_apic,
+            io_apics,
+            overrides: madt.overrides.clone()
+        }
+    })
+}
+
+pub fn controller() -> Option<&'static ApicController> {
+    APIC.r#try()
+}
+
+// Errors reported by the local APIC since boot
+pub fn error_count() -> usize {
+    ERRORS.load(Ordering::Relaxed)
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redirection_entries() {
+        assert_eq!(redirection_entry(0x21, Polarity::ActiveHigh, TriggerMode::Edge, 0, false), 0x21);
+        assert_eq!(
+            redirection_entry(0x29, Polarity::ActiveLow, TriggerMode::Level, 3, true),
+            0x0300_0000_0000_0000 | 0x29 | 1 << 13 | 1 << 15 | 1 << 16
+        );
+
+        let overrides = [InterruptOverride {
+            bus: 0,
+            source: 9,
+            gsi: 9,
+            polarity: Polarity::ActiveLow,
+            trigger: TriggerMode::Level
+        }];
+        assert_eq!(gsi_signaling(&overrides, 9), (Polarity::ActiveLow, TriggerMode::Level));
+        assert_eq!(gsi_signaling(&overrides, 1), (Polarity::ActiveHigh, TriggerMode::Edge));
+        assert_eq!(gsi_signaling(&overrides, 20), (Polarity::ActiveLow, TriggerMode::Level));
+    }
+
+    #[test]
+    fn isa_irqs_follow_overrides() {
+        let overrides = [
+            InterruptOverride { bus: 0, source: 0, gsi: 2, polarity: Polarity::BusDefault, trigger: TriggerMode::BusDefault },
+            InterruptOverride { bus: 0, source: 9, gsi: 9, polarity: Polarity::ActiveLow, trigger: TriggerMode::Level }
+        ];
+        assert_eq!(isa_irq_gsi(&overrides, 0), 2);
+        assert_eq!(isa_irq_gsi(&overrides, 9), 9);
+        assert_eq!(isa_irq_gsi(&overrides, 1), 1);
+        assert_eq!(isa_irq_gsi(&[], 0), 0);
+    }
+
+    #[test]
+    fn io_apics_handle_their_gsi_range() {
+        let io_apic = IoApic { id: 2, gsi_base: 24, input_count: 24, base: 0, lock: Mutex::new(()) };
+        assert!(!io_apic.handles(23));
+        assert!(io_apic.handles(24) && io_apic.handles(47));
+        assert!(!io_apic.handles(48));
+    }
+}
+
+#[cfg(all(test, target_os = "none"))]
+mod kernel_tests {
+    use super::*;
+    use crate::interrupts::irq;
+
+    static RECEIVED: AtomicUsize = AtomicUsize::new(0);
+
+    fn count_interrupt(_gsi: u32) {
+        RECEIVED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[test_case]
+    fn registered_gsi_is_routed_to_its_handler() {
+        let apic = match controller() {
+            Some(apic) => apic,
+            None => return
+        };
+        // COM2's IRQ, no device is wired to it on the machines the tests run on (PCI interrupts
+        // use the inputs above the ISA ones)
+        let gsi = apic.isa_irq_to_gsi(3);
+        let io_apic = apic.io_apic_for_gsi(gsi).unwrap();
+        let input = gsi - io_apic.gsi_base;
+        let vector = irq::vector_for_gsi(gsi);
+        irq::register_handler(gsi, count_interrupt).unwrap();
+        assert_eq!(irq::register_handler(gsi, count_interrupt), Err(irq::IrqError::AlreadyRegistered(gsi)));
+
+        let entry = unsafe { io_apic.read_redirection(input) };
+        assert_eq!(entry & 0xff, vector as u64);
+        assert_eq!(entry >> 56, apic.local_apic.id() as u64);
+        assert_eq!(entry & REDIRECTION_MASKED, 0);
+
+        // Nothing can raise the input, so the handler is reached through its vector instead
+        irq::mask(gsi).unwrap();
+        assert_ne!(unsafe { io_apic.read_redirection(input) } & REDIRECTION_MASKED, 0);
+        apic.local_apic.send_self_ipi(vector);
+        for _ in 0..1_000_000 {
+            if RECEIVED.load(Ordering::Relaxed) != 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        irq::unregister_handler(gsi).unwrap();
+        assert_eq!(RECEIVED.load(Ordering::Relaxed), 1);
+        assert_ne!(unsafe { io_apic.read_redirection(input) } & REDIRECTION_MASKED, 0);
+    }
+}
@@ -0,0 +1,176 @@
+// Hardware interrupt registration.
+//
+// Drivers register a handler for a global system interrupt (GSI, an input of the interrupt
+// controllers as numbered by ACPI), or for an ISA IRQ which gets translated to its GSI. GSI n is
+// delivered on vector IRQ_BASE_VECTOR + n, whichever controller is in use, and the handler is
+// called from the interrupt with the GSI as argument. Handlers run with interrupts disabled and
+// must not take locks that can be held outside of interrupts (the logger, the heap...).
+
+use crate::interrupts::{set_handler, without_interrupts, InterruptStackFrame};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Once;
+
+pub const IRQ_BASE_VECTOR: usize = 32;
+// GSIs 0..IRQ_COUNT can have handlers
+pub const IRQ_COUNT: usize = 64;
+// ISA IRQs 0-15
+pub const ISA_IRQ_COUNT: u8 = 16;
+
+pub type IrqHandler = fn(gsi: u32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    NoController,
+    OutOfRange(u32),
+    AlreadyRegistered(u32)
+}
+
+// What the rest of the kernel needs from an interrupt controller. Routing and masking are called
+// with interrupts disabled.
+pub trait InterruptController: Sync {
+    fn name(&self) -> &'static str;
+    // GSI an ISA IRQ is wired to
+    fn isa_irq_to_gsi(&self, irq: u8) -> u32;
+    fn has_gsi(&self, gsi: u32) -> bool;
+    // Delivers gsi on vector to the current processor, leaving it masked
+    unsafe fn route(&self, gsi: u32, vector: u8);
+    unsafe fn set_masked(&self, gsi: u32, masked: bool);
+    unsafe fn end_of_interrupt(&self, gsi: u32);
+    // Whether an interrupt received on gsi wasn't really raised. Spurious interrupts aren't
+    // passed to handlers nor acknowledged, this takes care of anything they need.
+    unsafe fn is_spurious(&self, _gsi: u32) -> bool {
+        false
+    }
+}
+
+static CONTROLLER: Once<&'static dyn InterruptController> = Once::new();
+
+// Addresses of the registered handlers, 0 when there is none. The constant is only used to
+// initialise the array, each element is a distinct atomic.
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_HANDLER: AtomicUsize = AtomicUsize::new(0);
+static HANDLERS: [AtomicUsize; IRQ_COUNT] = [NO_HANDLER; IRQ_COUNT];
+static UNHANDLED: AtomicUsize = AtomicUsize::new(0);
+static SPURIOUS: AtomicUsize = AtomicUsize::new(0);
+
+fn dispatch(gsi: u32) {
+    let controller = match controller() {
+        Some(controller) => controller,
+        None => return
+    };
+    if unsafe { controller.is_spurious(gsi) } {
+        SPURIOUS.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    match handler(gsi) {
+        Some(handler) => handler(gsi),
+        // Can't log from here, see unhandled_count
+        None => { UNHANDLED.fetch_add(1, Ordering::Relaxed); }
+    }
+    unsafe { controller.end_of_interrupt(gsi) };
+}
+
+// One entry point per vector, since handlers aren't told which vector they were called for
+macro_rules! irq_stubs {
+    ($($gsi:literal),*) => {
+        const IRQ_STUBS: [extern "x86-interrupt" fn(InterruptStackFrame); IRQ_COUNT] = [$({
+            extern "x86-interrupt" fn stub(_stack_frame: InterruptStackFrame) {
+                dispatch($gsi);
+            }
+            stub
+        }),*];
+    };
+}
+
+irq_stubs!(
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+    16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28, 29, 30, 31,
+    32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45, 46, 47,
+    48, 49, 50, 51, 52, 53, 54, 55, 56, 57, 58, 59, 60, 61, 62, 63
+);
+
+// Selects the controller handlers get registered with and installs the IRQ vectors. Only the
+// first call has an effect.
+pub unsafe fn set_controller(controller: &'static dyn InterruptController) {
+    CONTROLLER.call_once(|| {
+        for (gsi, stub) in IRQ_STUBS.iter().enumerate() {
+            set_handler(IRQ_BASE_VECTOR + gsi, *stub);
+        }
+        controller
+    });
+}
+
+pub fn controller() -> Option<&'static dyn InterruptController> {
+    CONTROLLER.r#try().copied()
+}
+
+pub fn vector_for_gsi(gsi: u32) -> u8 {
+    (IRQ_BASE_VECTOR + gsi as usize) as u8
+}
+
+fn handler(gsi: u32) -> Option<IrqHandler> {
+    match HANDLERS.get(gsi as usize)?.load(Ordering::Acquire) {
+        0 => None,
+        address => Some(unsafe { core::mem::transmute::<usize, IrqHandler>(address) })
+    }
+}
+
+fn checked_controller(gsi: u32) -> Result<&'static dyn InterruptController, IrqError> {
+    let controller = controller().ok_or(IrqError::NoController)?;
+    if gsi as usize >= IRQ_COUNT || !controller.has_gsi(gsi) {
+        return Err(IrqError::OutOfRange(gsi));
+    }
+    Ok(controller)
+}
+
+// Routes gsi to its vector and unmasks it
+pub fn register_handler(gsi: u32, handler: IrqHandler) -> Result<(), IrqError> {
+    let controller = checked_controller(gsi)?;
+    HANDLERS[gsi as usize].compare_exchange(0, handler as usize, Ordering::AcqRel, Ordering::Acquire)
+        .map_err(|_| IrqError::AlreadyRegistered(gsi))?;
+    without_interrupts(|| unsafe {
+        controller.route(gsi, vector_for_gsi(gsi));
+        controller.set_masked(gsi, false);
+    });
+    Ok(())
+}
+
+// Same as register_handler for the GSI an ISA IRQ is wired to, which is returned
+pub fn register_isa_handler(irq: u8, handler: IrqHandler) -> Result<u32, IrqError> {
+    let controller = controller().ok_or(IrqError::NoController)?;
+    if irq >= ISA_IRQ_COUNT {
+        return Err(IrqError::OutOfRange(irq as u32));
+    }
+    let gsi = controller.isa_irq_to_gsi(irq);
+    register_handler(gsi, handler).map(|_| gsi)
+}
+
+// Masks gsi and forgets its handler
+pub fn unregister_handler(gsi: u32) -> Result<(), IrqError> {
+    let controller = checked_controller(gsi)?;
+    without_interrupts(|| unsafe { controller.set_masked(gsi, true) });
+    HANDLERS[gsi as usize].store(0, Ordering::Release);
+    Ok(())
+}
+
+pub fn mask(gsi: u32) -> Result<(), IrqError> {
+    let controller = checked_controller(gsi)?;
+    without_interrupts(|| unsafe { controller.set_masked(gsi, true) });
+    Ok(())
+}
+
+pub fn unmask(gsi: u32) -> Result<(), IrqError> {
+    let controller = checked_controller(gsi)?;
+    without_interrupts(|| unsafe { controller.set_masked(gsi, false) });
+    Ok(())
+}
+
+// Interrupts received on a GSI without a handler
+pub fn unhandled_count() -> usize {
+    UNHANDLED.load(Ordering::Relaxed)
+}
+
+// Spurious interrupts reported by the controller
+pub fn spurious_count() -> usize {
+    SPURIOUS.load(Ordering::Relaxed)
+}
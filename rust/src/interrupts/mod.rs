@@ -0,0 +1,239 @@
+pub mod apic;
+pub mod irq;
+pub mod pic;
+
+use crate::memory::paging::{EntryFlags, recursive_root_table};
+use crate::memory::user_access::{smap_enabled, smep_enabled};
+use crate::panic_screen::record_exception_frame;
+use crate::utils::cpuid;
+use crate::utils::reg_read::read_cr2;
+
+pub const IDT_ENTRIES: usize = 256;
+
+pub const DIVIDE_ERROR_VECTOR: usize = 0;
+pub const BREAKPOINT_VECTOR: usize = 3;
+pub const INVALID_OPCODE_VECTOR: usize = 6;
+pub const DOUBLE_FAULT_VECTOR: usize = 8;
+pub const GENERAL_PROTECTION_VECTOR: usize = 13;
+pub const PAGE_FAULT_VECTOR: usize = 14;
+
+// RFLAGS.IF
+pub const RFLAGS_IF: u64 = 1 << 9;
+// RFLAGS.AC, set by stac to allow supervisor accesses to user pages under SMAP
+pub const RFLAGS_AC: u64 = 1 << 18;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct InterruptStackFrame {
+    pub instruction_pointer: u64,
+    pub code_segment: u64,
+    pub cpu_flags: u64,
+    pub stack_pointer: u64,
+    pub stack_segment: u64
+}
+
+pub type HandlerFunc = extern "x86-interrupt" fn(InterruptStackFrame);
+pub type HandlerFuncWithErrorCode = extern "x86-interrupt" fn(InterruptStackFrame, u64);
+
+bitflags! {
+    pub struct PageFaultErrorCode: u64 {
+        const PROTECTION_VIOLATION = 1 << 0;
+        const CAUSED_BY_WRITE =      1 << 1;
+        const USER_MODE =            1 << 2;
+        const MALFORMED_TABLE =      1 << 3;
+        const INSTRUCTION_FETCH =    1 << 4;
+        const PROTECTION_KEY =       1 << 5;
+        const SHADOW_STACK =         1 << 6;
+    }
+}
+
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct IdtEntry {
+    offset_low: u16,
+    selector: u16,
+    ist: u8,
+    type_attributes: u8,
+    offset_middle: u16,
+    offset_high: u32,
+    reserved: u32
+}
+
+impl IdtEntry {
+    pub const fn missing() -> IdtEntry {
+        IdtEntry {
+            offset_low: 0,
+            selector: 0,
+            ist: 0,
+            type_attributes: 0,
+            offset_middle: 0,
+            offset_high: 0,
+            reserved: 0
+        }
+    }
+
+    // Present, DPL 0, 64 bit interrupt gate
+    pub fn new(handler_address: usize, selector: u16) -> IdtEntry {
+        IdtEntry {
+            offset_low: handler_address as u16,
+            selector,
+            ist: 0,
+            type_attributes: 0x8e,
+            offset_middle: (handler_address >> 16) as u16,
+            offset_high: (handler_address >> 32) as u32,
+            reserved: 0
+        }
+    }
+}
+
+#[repr(C, align(16))]
+pub struct Idt {
+    pub entries: [IdtEntry; IDT_ENTRIES]
+}
+
+#[repr(C, packed)]
+struct DescriptorTablePointer {
+    limit: u16,
+    base: u64
+}
+
+static mut IDT: Idt = Idt {
+    entries: [IdtEntry::missing(); IDT_ENTRIES]
+};
+
+fn current_code_segment() -> u16 {
+    let segment: u16;
+    unsafe { asm!("mov {:x}, cs", out(reg) segment, options(nomem, nostack)) };
+    segment
+}
+
+pub unsafe fn set_handler(vector: usize, handler: HandlerFunc) {
+    IDT.entries[vector] = IdtEntry::new(handler as usize, current_code_segment());
+}
+
+pub unsafe fn set_handler_with_error_code(vector: usize, handler: HandlerFuncWithErrorCode) {
+    IDT.entries[vector] = IdtEntry::new(handler as usize, current_code_segment());
+}
+
+// Installs the exception handlers and loads the IDT. The bootloader's GDT is kept, handlers use
+// whatever code segment the kernel is currently running in.
+pub unsafe fn init() {
+    set_handler(DIVIDE_ERROR_VECTOR, divide_error_handler);
+    set_handler(BREAKPOINT_VECTOR, breakpoint_handler);
+    set_handler(INVALID_OPCODE_VECTOR, invalid_opcode_handler);
+    set_handler_with_error_code(DOUBLE_FAULT_VECTOR, double_fault_handler);
+    set_handler_with_error_code(GENERAL_PROTECTION_VECTOR, general_protection_handler);
+    set_handler_with_error_code(PAGE_FAULT_VECTOR, page_fault_handler);
+
+    let pointer = DescriptorTablePointer {
+        limit: (core::mem::size_of::<Idt>() - 1) as u16,
+        base: &IDT as *const Idt as u64
+    };
+    asm!("lidt [{}]", in(reg) &pointer, options(readonly, nostack));
+}
+
+// Sets up the interrupt controllers and enables interrupts. The APICs described by the MADT are
+// used, so this needs acpi::init, and the 8259 PICs when there are none or with noapic.
+pub unsafe fn init_controllers() {
+    pic::remap(pic::IRQ_VECTOR_OFFSET);
+    let use_apic = crate::cmdline::options().apic;
+    match crate::acpi::info().and_then(|info| info.madt.as_ref()) {
+        Some(madt) if use_apic && cpuid::has_apic() && !madt.io_apics.is_empty() => {
+            irq::set_controller(apic::init(madt));
+        }
+        _ => {
+            if use_apic {
+                warn!("No usable APIC, falling back to the 8259 PICs");
+            }
+            irq::set_controller(pic::init());
+        }
+    }
+    enable();
+}
+
+pub fn enable() {
+    unsafe { asm!("sti", options(nomem, nostack)) };
+}
+
+pub fn disable() {
+    unsafe { asm!("cli", options(nomem, nostack)) };
+}
+
+pub fn are_enabled() -> bool {
+    let flags: u64;
+    unsafe { asm!("pushfq", "pop {}", out(reg) flags, options(nomem, preserves_flags)) };
+    flags & RFLAGS_IF != 0
+}
+
+// Runs f with interrupts disabled, restoring their previous state afterwards
+pub fn without_interrupts<T, F: FnOnce() -> T>(f: F) -> T {
+    let were_enabled = are_enabled();
+    if were_enabled {
+        disable();
+    }
+    let result = f();
+    if were_enabled {
+        enable();
+    }
+    result
+}
+
+extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    record_exception_frame(&stack_frame);
+    panic!("EXCEPTION : DIVIDE ERROR");
+}
+
+extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    println!("EXCEPTION : BREAKPOINT\n{:#x?}", stack_frame);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    record_exception_frame(&stack_frame);
+    panic!("EXCEPTION : INVALID OPCODE");
+}
+
+extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, _error_code: u64) {
+    record_exception_frame(&stack_frame);
+    panic!("EXCEPTION : DOUBLE FAULT");
+}
+
+extern "x86-interrupt" fn general_protection_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    record_exception_frame(&stack_frame);
+    panic!("EXCEPTION : GENERAL PROTECTION FAULT (error code 0x{:x})", error_code);
+}
+
+extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    record_exception_frame(&stack_frame);
+    let address = unsafe { read_cr2() };
+    let error_code = PageFaultErrorCode::from_bits_truncate(error_code);
+
+    // Supervisor access to a present user page : SMEP when fetching instructions, SMAP otherwise
+    // unless RFLAGS.AC was set by stac. The protections are only enabled once the kernel's own
+    // tables, and so the recursive mapping, are loaded, the page can't be looked up before.
+    let supervisor_on_user_page = (smep_enabled() || smap_enabled())
+        && !error_code.contains(PageFaultErrorCode::USER_MODE)
+        && error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+        && page_is_user_accessible(address);
+
+    if supervisor_on_user_page && error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        panic!("EXCEPTION : PAGE FAULT (SMEP violation, kernel executed user page at 0x{:x})", address);
+    }
+
+    if supervisor_on_user_page && smap_enabled() && stack_frame.cpu_flags & RFLAGS_AC == 0 {
+        panic!(
+            "EXCEPTION : PAGE FAULT (SMAP violation, kernel accessed user page at 0x{:x} outside of copy_from_user / copy_to_user)",
+            address
+        );
+    }
+
+    panic!("EXCEPTION : PAGE FAULT at 0x{:x} ({:?})", address, error_code);
+}
+
+fn page_is_user_accessible(address: usize) -> bool {
+    // The current P4 table is always reachable through the recursive mapping
+    let p4_table = unsafe { recursive_root_table() };
+    match unsafe { p4_table.p4_lookup_recursive(address) } {
+        Some(flags) => flags.contains(EntryFlags::USER_ACCESSIBLE),
+        None => false
+    }
+}
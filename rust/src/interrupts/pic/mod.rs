@@ -0,0 +1,166 @@
+// Legacy 8259 programmable interrupt controllers (a master and a slave chained on its IRQ 2).
+//
+// Firmware leaves them delivering IRQs 0-7 on vectors 8-15, which are exceptions in protected
+// mode, so they're always remapped before interrupts get enabled, even when the APICs are used
+// and the PICs stay masked. Without APICs they're the interrupt controller, GSIs 0-15 being the
+// ISA IRQs themselves.
+
+use crate::interrupts::irq::{InterruptController, IRQ_BASE_VECTOR, ISA_IRQ_COUNT};
+use crate::utils::port::{inb, outb};
+use spin::Mutex;
+
+pub const MASTER_COMMAND_PORT: u16 = 0x20;
+pub const MASTER_DATA_PORT: u16 = 0x21;
+pub const SLAVE_COMMAND_PORT: u16 = 0xa0;
+pub const SLAVE_DATA_PORT: u16 = 0xa1;
+
+// Unused port, writing to it gives the PICs time to handle the previous command
+const WAIT_PORT: u16 = 0x80;
+
+const ICW1_ICW4: u8 = 0x01;
+const ICW1_INIT: u8 = 0x10;
+const ICW4_8086: u8 = 0x01;
+const OCW2_EOI: u8 = 0x20;
+const OCW3_READ_ISR: u8 = 0x0b;
+
+// The slave's output is wired to this master input
+pub const CASCADE_IRQ: u8 = 2;
+
+// IRQs 0-7 are delivered on vectors offset..offset + 8, IRQs 8-15 on the next 8 vectors. Same as
+// the vectors of GSIs 0-15 (see irq).
+pub const IRQ_VECTOR_OFFSET: u8 = IRQ_BASE_VECTOR as u8;
+
+unsafe fn io_wait() {
+    outb(WAIT_PORT, 0);
+}
+
+// Reinitializes both PICs with every line masked
+pub unsafe fn remap(offset: u8) {
+    outb(MASTER_COMMAND_PORT, ICW1_INIT | ICW1_ICW4);
+    io_wait();
+    outb(SLAVE_COMMAND_PORT, ICW1_INIT | ICW1_ICW4);
+    io_wait();
+    outb(MASTER_DATA_PORT, offset);
+    io_wait();
+    outb(SLAVE_DATA_PORT, offset + 8);
+    io_wait();
+    outb(MASTER_DATA_PORT, 1 << CASCADE_IRQ);
+    io_wait();
+    outb(SLAVE_DATA_PORT, CASCADE_IRQ);
+    io_wait();
+    outb(MASTER_DATA_PORT, ICW4_8086);
+    io_wait();
+    outb(SLAVE_DATA_PORT, ICW4_8086);
+    io_wait();
+    disable();
+}
+
+// Masks every line
+pub unsafe fn disable() {
+    outb(MASTER_DATA_PORT, 0xff);
+    outb(SLAVE_DATA_PORT, 0xff);
+}
+
+// Masks of both PICs, the slave's in the high byte
+unsafe fn write_masks(masks: u16) {
+    outb(MASTER_DATA_PORT, masks as u8);
+    outb(SLAVE_DATA_PORT, (masks >> 8) as u8);
+}
+
+// In service registers of both PICs, the slave's in the high byte
+unsafe fn in_service() -> u16 {
+    outb(MASTER_COMMAND_PORT, OCW3_READ_ISR);
+    outb(SLAVE_COMMAND_PORT, OCW3_READ_ISR);
+    (inb(SLAVE_COMMAND_PORT) as u16) << 8 | inb(MASTER_COMMAND_PORT) as u16
+}
+
+// The cascade line is unmasked as long as a slave line is
+pub fn updated_masks(masks: u16, irq: u8, masked: bool) -> u16 {
+    let masks = if masked { masks | 1 << irq } else { masks & !(1 << irq) };
+    if masks & 0xff00 == 0xff00 {
+        masks | 1 << CASCADE_IRQ
+    } else {
+        masks & !(1 << CASCADE_IRQ)
+    }
+}
+
+pub struct Pic {
+    // Copy of the interrupt mask registers, written whole on every change
+    masks: Mutex<u16>
+}
+
+impl InterruptController for Pic {
+    fn name(&self) -> &'static str {
+        "8259 PIC"
+    }
+
+    fn isa_irq_to_gsi(&self, irq: u8) -> u32 {
+        irq as u32
+    }
+
+    fn has_gsi(&self, gsi: u32) -> bool {
+        gsi < ISA_IRQ_COUNT as u32 && gsi != CASCADE_IRQ as u32
+    }
+
+    // Vectors are fixed by remap, edge triggered
+    unsafe fn route(&self, gsi: u32, vector: u8) {
+        debug_assert_eq!(vector as u32, IRQ_VECTOR_OFFSET as u32 + gsi);
+    }
+
+    unsafe fn set_masked(&self, gsi: u32, masked: bool) {
+        let mut masks = self.masks.lock();
+        *masks = updated_masks(*masks, gsi as u8, masked);
+        write_masks(*masks);
+    }
+
+    unsafe fn end_of_interrupt(&self, gsi: u32) {
+        if gsi >= 8 {
+            outb(SLAVE_COMMAND_PORT, OCW2_EOI);
+        }
+        outb(MASTER_COMMAND_PORT, OCW2_EOI);
+    }
+
+    // IRQ 7 and 15 are raised when a line goes away before being acknowledged, in which case the
+    // in service bit isn't set. The master did see a real IRQ 2 for a spurious IRQ 15 though.
+    unsafe fn is_spurious(&self, gsi: u32) -> bool {
+        if gsi != 7 && gsi != 15 {
+            return false;
+        }
+        if in_service() & 1 << gsi != 0 {
+            return false;
+        }
+        if gsi == 15 {
+            outb(MASTER_COMMAND_PORT, OCW2_EOI);
+        }
+        true
+    }
+}
+
+static PIC: Pic = Pic {
+    masks: Mutex::new(0xffff)
+};
+
+// Remaps the PICs to IRQ_VECTOR_OFFSET with every line masked, lines get unmasked as handlers get
+// registered (see irq)
+pub unsafe fn init() -> &'static Pic {
+    remap(IRQ_VECTOR_OFFSET);
+    *PIC.masks.lock() = 0xffff;
+    info!("Using the 8259 PICs, IRQs 0-15 on vectors {}-{}", IRQ_VECTOR_OFFSET, IRQ_VECTOR_OFFSET + 15);
+    &PIC
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cascade_follows_slave_lines() {
+        let masks = updated_masks(0xffff, 1, false);
+        assert_eq!(masks, 0xfffd);
+        let masks = updated_masks(masks, 12, false);
+        assert_eq!(masks, 0xeff9);
+        let masks = updated_masks(masks, 12, true);
+        assert_eq!(masks, 0xfffd);
+        assert_eq!(updated_masks(masks, 1, true), 0xffff);
+    }
+}
@@ -5,84 +5,162 @@
 #![feature(llvm_asm)]
 #![feature(allocator_api)]
 #![feature(alloc_error_handler)]
-#![no_std]
-
-use crate::memory::frame_allocator::{BitMapFrameAllocator, FrameAllocator, FRAME_SIZE, FrameInfo};
-use crate::memory::paging::{EntryTable, EntryFlags};
+#![feature(abi_x86_interrupt)]
+#![feature(panic_info_message)]
+#![cfg_attr(any(not(test), target_os = "none"), no_std)]
+// In-kernel tests (see the testing module), host-side unit tests use the default harness
+#![cfg_attr(all(test, target_os = "none"), no_main)]
+#![cfg_attr(all(test, target_os = "none"), feature(custom_test_frameworks))]
+#![cfg_attr(all(test, target_os = "none"), test_runner(crate::testing::test_runner))]
+#![cfg_attr(all(test, target_os = "none"), reexport_test_harness_main = "test_main")]
+
+use crate::memory::frame_allocator::{BitMapFrameAllocator, FrameAllocator, FRAME_SIZE};
+use crate::memory::paging::{EntryTable, EntryFlags, init_paging_levels, recursive_root_table, RECURSIVE_INDEX};
 use crate::utils::reg_write::write_cr3;
 use crate::memory::heap::{LinkedListHeapAllocator, AllocOption};
+use crate::memory::KernelHeapAllocator;
+use crate::memory::address_space::init_pcid;
+use crate::memory::layout::KernelLayout;
+use crate::memory::user_access::init_protections;
 use core::alloc::{Layout};
 use alloc::boxed::Box;
 
+#[cfg(any(not(test), target_os = "none"))]
 extern crate rlibc;
 #[macro_use]
 extern crate bitflags;
 #[macro_use]
 extern crate alloc;
 
+pub mod debugcon;
+#[macro_use]
+pub mod serial;
+#[macro_use]
+pub mod log;
 #[macro_use]
 pub mod display;
 pub mod memory;
 pub mod utils;
-
+pub mod interrupts;
+pub mod stivale2;
+pub mod cmdline;
+pub mod initrd;
+pub mod acpi;
+pub mod clock;
+pub mod panic_screen;
+#[cfg(all(test, target_os = "none"))]
+pub mod testing;
+
+// Address the kernel is linked at, the bootloader may slide it (see memory::layout)
 pub const KERNEL_OFFSET: usize = 0xffffffff80000000;
 pub const MAX_HEAP: usize = 0x100000000; // 4GiB
-pub const HEAP_OFFSET: usize = KERNEL_OFFSET - MAX_HEAP;
 
-#[global_allocator]
-static mut ALLOCATOR: AllocOption<LinkedListHeapAllocator<BitMapFrameAllocator>> = AllocOption(None);
+// Host-side unit tests (cargo test) run on top of std, which provides its own allocator and
+// panic handler
+#[cfg_attr(any(not(test), target_os = "none"), global_allocator)]
+pub static mut ALLOCATOR: AllocOption<KernelHeapAllocator> = AllocOption(None);
 
 #[no_mangle]
 pub extern fn kernel_main(stivale_struct_ptr: usize) {
-    println!("SysControl64 V0.2, booting up...");
+    log::init();
+    info!("SysControl64 V0.2, booting up...");
+    stivale2::init(stivale_struct_ptr);
+    let options = cmdline::init();
+    log::apply_options(options);
 
-    let stivale_struct = unsafe { stivale::load(stivale_struct_ptr) };
+    unsafe { interrupts::init() };
+    debug!("Loaded IDT");
 
+    let stivale_struct = unsafe { stivale::load(stivale_struct_ptr) };
 
-    println!("Bootloader info : {} {}",
-             stivale_struct.bootloader_brand().expect("No bootloader brand provided."),
-             stivale_struct.bootloader_version().expect("No bootloader version provided.")
+    info!("Bootloader info : {} {}",
+          stivale_struct.bootloader_brand().expect("No bootloader brand provided."),
+          stivale_struct.bootloader_version().expect("No bootloader version provided.")
     );
 
     let memory_map =
         stivale_struct.memory_map().expect("No memory map provided.");
 
-    print!("Creating frame allocator... ");
-    let mut frame_allocator = BitMapFrameAllocator::new(memory_map.iter());
-    println!("Done !");
+    let mut frame_allocator = BitMapFrameAllocator::new(memory_map.iter(), 0);
+    debug!("Created frame allocator");
 
-    print!("Marking VGA framebuffer as allocated... ");
     frame_allocator.mark_frame(0xb8000, true);
-    println!("Done !");
+    debug!("Marked VGA framebuffer as allocated");
+
+    initrd::protect_modules(&mut frame_allocator);
+    debug!("Marked boot modules as allocated");
+
+    let paging_levels = unsafe { init_paging_levels() };
+    info!("Using {} level paging", paging_levels);
+
+    let layout = unsafe { KernelLayout::randomise(frame_allocator.memory_end) };
+    info!("Kernel at 0x{:x} (offset 0x{:x}), heap at 0x{:x}, direct map at 0x{:x}",
+          layout.kernel_start,
+          layout.kernel_offset,
+          layout.heap_base,
+          layout.direct_map_base
+    );
 
-    print!("Creating page tables... ");
     let p4_frame = frame_allocator.allocate_frame().expect("Out of memory (cannot create P4 page table).");
     // The frame allocator is guaranteed to return a valid frame
     let p4_table = unsafe {EntryTable::from_frame_unzeroed(p4_frame)};
     p4_table.zero();
-    print!("[Created P4 table] ");
-    p4_table.entries[511].write(p4_frame, EntryFlags::PRESENT | EntryFlags::WRITABLE);
-    print!("[Recursively mapped P4 table to last entry] ");
-    unsafe { p4_table.p4_kernel_remap(&stivale_struct, &mut frame_allocator); }
-    print!("[Remapped the kernel] ");
+    debug!("Created P4 table");
+    p4_table.entries[RECURSIVE_INDEX].write(p4_frame, EntryFlags::PRESENT | EntryFlags::WRITABLE);
+    debug!("Recursively mapped root table to entry {}", RECURSIVE_INDEX);
+    unsafe { p4_table.p4_kernel_remap(&stivale_struct, &layout, &mut frame_allocator); }
+    debug!("Remapped the kernel");
     unsafe { write_cr3(p4_frame.address) };
-    print!("[Switched to new page table] ");
+    debug!("Switched to new page table");
+    if unsafe { memory::pat::init() } {
+        debug!("Enabled write-combining");
+    }
+    if unsafe { init_pcid() } {
+        info!("Enabled PCIDs");
+    }
+    let protections = unsafe { init_protections() };
+    info!("SMEP {}, SMAP {}, UMIP {}",
+          if protections.smep { "on" } else { "off" },
+          if protections.smap { "on" } else { "off" },
+          if protections.umip { "on" } else { "off" }
+    );
     // p4 table is now accessed in a recursive way
-    let p4_table = unsafe {
-        EntryTable::from_frame_unzeroed(FrameInfo::from_address(0xffffffff_fffff000))
-    };
-    println!("Done !");
-    print!("Creating kernel heap allocator... ");
+    let p4_table = unsafe { recursive_root_table() };
+    let heap_size = options.heap_max.map_or(layout.heap_size, |max| max.min(layout.heap_size));
     unsafe {
         let heap_allocator = LinkedListHeapAllocator::new(
             frame_allocator,
             p4_table,
-            HEAP_OFFSET / FRAME_SIZE,
-            MAX_HEAP
+            layout.heap_base / FRAME_SIZE,
+            heap_size
         );
         ALLOCATOR = AllocOption(Some(heap_allocator));
     }
-    println!("Done !");
+    debug!("Created kernel heap allocator ({} MiB at most)", heap_size / (1024 * 1024));
+
+    display::vga::enable_scrollback(display::vga::scrollback::DEFAULT_SCROLLBACK_LINES);
+    if options.framebuffer_console && unsafe { display::init_framebuffer_console() } {
+        // Everything before went to the VGA text buffer, which isn't shown in graphics modes
+        if options.console != cmdline::ConsoleOutput::Serial {
+            log::replay(&log::CONSOLE_SINK);
+        }
+        info!("Switched to the framebuffer console");
+    }
+
+    initrd::init();
+    if let Some(motd) = initrd::read_file("motd") {
+        print!("{}", core::str::from_utf8(motd).unwrap_or(""));
+    }
+
+    acpi::init();
+    unsafe { interrupts::init_controllers() };
+    clock::init();
+
+    #[cfg(all(test, target_os = "none"))]
+    test_main();
+
+    #[cfg(feature = "heap_tracking")]
+    let live_bytes_before = memory::heap::tracking::stats().live_bytes;
 
     for i in 0..1000 {
         let b = Box::new(i);
@@ -104,20 +182,34 @@ pub extern fn kernel_main(stivale_struct_ptr: usize) {
         assert_eq!(b.as_ref(), &i);
     }
 
-    println!("Ran 3000 allocation / deallocation tests !");
+    info!("Ran 3000 allocation / deallocation tests !");
+
+    #[cfg(feature = "heap_tracking")]
+    {
+        memory::heap::tracking::report();
+        assert_eq!(memory::heap::tracking::stats().live_bytes, live_bytes_before,
+                   "Allocation tests leaked memory.");
+    }
 }
 
+#[cfg(any(not(test), target_os = "none"))]
 #[lang = "eh_personality"]
 #[no_mangle]
 pub extern fn eh_personality() {}
 
+#[cfg(not(test))]
 #[panic_handler]
 pub extern fn panic_handler(info: &core::panic::PanicInfo) -> ! {
-    println!("{}", info);
+    panic_screen::panic(info)
+}
 
-    loop{}
+#[cfg(all(test, target_os = "none"))]
+#[panic_handler]
+pub extern fn panic_handler(info: &core::panic::PanicInfo) -> ! {
+    testing::test_panic_handler(info)
 }
 
+#[cfg(any(not(test), target_os = "none"))]
 #[alloc_error_handler]
 pub fn alloc_error_handler(layout: Layout) -> ! {
     panic!("Failed to allocate the following layout : {:?}", layout);
@@ -0,0 +1,439 @@
+// Kernel logging.
+//
+// Records are written through the error! / warn! / info! / debug! / trace! macros. Each one is
+// prefixed with a timestamp, its level and the module it comes from, kept in a fixed size ring
+// buffer (usable before the heap exists) and written to every registered sink whose level lets it
+// through.
+
+use crate::utils::reg_read::read_tsc;
+use core::fmt;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicU8, Ordering};
+use spin::Mutex;
+
+pub const MAX_SINKS: usize = 8;
+pub const RING_BUFFER_SIZE: usize = 16 * 1024;
+// Longest line handed to for_each_recent_line callbacks, longer lines are split
+pub const MAX_LINE_LENGTH: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Error = 0,
+    Warn  = 1,
+    Info  = 2,
+    Debug = 3,
+    Trace = 4,
+}
+
+impl Level {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn  => "WARN",
+            Level::Info  => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+
+    // SGR sequence the level is printed with on the consoles
+    pub fn ansi_color(&self) -> &'static str {
+        match self {
+            Level::Error => "\x1b[1;31m",
+            Level::Warn  => "\x1b[33m",
+            Level::Info  => "\x1b[32m",
+            Level::Debug => "\x1b[36m",
+            Level::Trace => "\x1b[90m",
+        }
+    }
+
+    fn from_u8(level: u8) -> Level {
+        match level {
+            0 => Level::Error,
+            1 => Level::Warn,
+            2 => Level::Info,
+            3 => Level::Debug,
+            _ => Level::Trace,
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+// Something records are written to. Sinks are called with the logger locked, so they must not log.
+pub trait LogSink: Sync {
+    fn name(&self) -> &'static str;
+    fn write_str(&self, s: &str);
+}
+
+#[derive(Clone, Copy)]
+struct RegisteredSink {
+    sink: &'static dyn LogSink,
+    max_level: Level
+}
+
+// fmt::Write adapter for sinks
+pub struct SinkWriter<'a>(pub &'a dyn LogSink);
+
+impl<'a> fmt::Write for SinkWriter<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.0.write_str(s);
+        Ok(())
+    }
+}
+
+// Keeps the last RING_BUFFER_SIZE bytes of formatted records, the oldest ones being overwritten
+pub struct RingBuffer {
+    data: [u8; RING_BUFFER_SIZE],
+    start: usize,
+    length: usize,
+    wrapped: bool
+}
+
+impl RingBuffer {
+    pub const fn new() -> RingBuffer {
+        RingBuffer {
+            data: [0; RING_BUFFER_SIZE],
+            start: 0,
+            length: 0,
+            wrapped: false
+        }
+    }
+
+    pub fn push(&mut self, byte: u8) {
+        if self.length < RING_BUFFER_SIZE {
+            self.data[(self.start + self.length) % RING_BUFFER_SIZE] = byte;
+            self.length += 1;
+        }
+        else {
+            self.data[self.start] = byte;
+            self.start = (self.start + 1) % RING_BUFFER_SIZE;
+            self.wrapped = true;
+        }
+    }
+
+    fn bytes(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..self.length).map(move |i| self.data[(self.start + i) % RING_BUFFER_SIZE])
+    }
+
+    // Calls f with every complete line still in the buffer, oldest first, without the newline.
+    // Once the buffer has wrapped its first line is partial and skipped.
+    pub fn for_each_line<F: FnMut(&str)>(&self, mut f: F) {
+        let mut line = [0u8; MAX_LINE_LENGTH];
+        let mut line_length = 0;
+        let mut skipping = self.wrapped;
+
+        for byte in self.bytes() {
+            if skipping {
+                skipping = byte != b'\n';
+                continue;
+            }
+            if byte == b'\n' || line_length == MAX_LINE_LENGTH {
+                emit_line(&line[..line_length], &mut f);
+                line_length = 0;
+                if byte == b'\n' {
+                    continue;
+                }
+            }
+            line[line_length] = byte;
+            line_length += 1;
+        }
+
+        if line_length > 0 {
+            emit_line(&line[..line_length], &mut f);
+        }
+    }
+}
+
+impl Default for RingBuffer {
+    fn default() -> RingBuffer {
+        RingBuffer::new()
+    }
+}
+
+// A line split at MAX_LINE_LENGTH may end in the middle of a character, which is dropped
+fn emit_line<F: FnMut(&str)>(line: &[u8], f: &mut F) {
+    let valid = match core::str::from_utf8(line) {
+        Ok(line) => line,
+        Err(error) => unsafe { core::str::from_utf8_unchecked(&line[..error.valid_up_to()]) }
+    };
+    f(valid)
+}
+
+impl fmt::Write for RingBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.push(byte);
+        }
+        Ok(())
+    }
+}
+
+struct Logger {
+    sinks: [Option<RegisteredSink>; MAX_SINKS],
+    ring_buffer: RingBuffer
+}
+
+static LOGGER: Mutex<Logger> = Mutex::new(Logger {
+    sinks: [None; MAX_SINKS],
+    ring_buffer: RingBuffer::new()
+});
+
+// Records above this level are dropped before being formatted, even for the ring buffer
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Debug as u8);
+
+pub fn max_level() -> Level {
+    Level::from_u8(MAX_LEVEL.load(Ordering::Relaxed))
+}
+
+pub fn set_max_level(level: Level) {
+    MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level <= max_level()
+}
+
+pub fn add_sink(sink: &'static dyn LogSink, max_level: Level) {
+    let mut logger = LOGGER.lock();
+    let slot = logger.sinks.iter_mut()
+        .find(|slot| slot.is_none())
+        .expect("Too many log sinks.");
+    *slot = Some(RegisteredSink { sink, max_level });
+}
+
+pub fn remove_sink(name: &str) {
+    let mut logger = LOGGER.lock();
+    for slot in logger.sinks.iter_mut() {
+        if slot.map_or(false, |registered| registered.sink.name() == name) {
+            *slot = None;
+        }
+    }
+}
+
+pub fn set_sink_level(name: &str, max_level: Level) {
+    let mut logger = LOGGER.lock();
+    for registered in logger.sinks.iter_mut().flatten() {
+        if registered.sink.name() == name {
+            registered.max_level = max_level;
+        }
+    }
+}
+
+pub fn for_each_sink<F: FnMut(&'static dyn LogSink)>(mut f: F) {
+    for registered in LOGGER.lock().sinks.iter().flatten() {
+        f(registered.sink);
+    }
+}
+
+// For the panic handler, the panicking code may have been logging
+pub unsafe fn force_unlock() {
+    LOGGER.force_unlock();
+    crate::serial::SERIAL1.force_unlock();
+}
+
+// Sends what the ring buffer holds to a sink, e.g. one registered after the first records
+pub fn replay(sink: &dyn LogSink) {
+    let logger = LOGGER.lock();
+    logger.ring_buffer.for_each_line(|line| {
+        sink.write_str(line);
+        sink.write_str("\n");
+    });
+}
+
+pub fn for_each_recent_line<F: FnMut(&str)>(f: F) {
+    LOGGER.lock().ring_buffer.for_each_line(f)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timestamp {
+    // Raw TSC value, before the clock is calibrated
+    Ticks(u64),
+    Nanoseconds(u64)
+}
+
+// Both are 14 characters wide, seconds being printed with microseconds
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Timestamp::Ticks(ticks) => write!(f, "{:>14}", ticks),
+            Timestamp::Nanoseconds(nanoseconds) => {
+                write!(f, "{:>7}.{:06}", nanoseconds / 1_000_000_000, nanoseconds % 1_000_000_000 / 1000)
+            }
+        }
+    }
+}
+
+fn timestamp() -> Timestamp {
+    let tsc = unsafe { read_tsc() };
+    match crate::clock::tsc_frequency() {
+        Some(frequency) => Timestamp::Nanoseconds(crate::clock::ticks_to_ns(tsc, frequency)),
+        None => Timestamp::Ticks(tsc)
+    }
+}
+
+// Sinks get the level coloured with ANSI escapes, the ring buffer keeps plain text
+fn write_record<W: fmt::Write>(writer: &mut W, colored: bool, timestamp: Timestamp, level: Level, module: &str, args: fmt::Arguments) {
+    if colored {
+        let _ = write!(writer, "[{}] {}{:<5}\x1b[0m {}: ", timestamp, level.ansi_color(), level, module);
+    }
+    else {
+        let _ = write!(writer, "[{}] {:<5} {}: ", timestamp, level, module);
+    }
+    let _ = writer.write_fmt(args);
+    let _ = writer.write_str("\n");
+}
+
+pub fn log(level: Level, module: &str, args: fmt::Arguments) {
+    if !enabled(level) {
+        return;
+    }
+    let timestamp = timestamp();
+    // The crate name is the same for every record
+    let module = module.strip_prefix("syscontrol::").unwrap_or(module);
+
+    let mut logger = LOGGER.lock();
+    write_record(&mut logger.ring_buffer, false, timestamp, level, module, args);
+    for registered in logger.sinks.iter().flatten() {
+        if level <= registered.max_level {
+            write_record(&mut SinkWriter(registered.sink), true, timestamp, level, module, args);
+        }
+    }
+}
+
+// The framebuffer console, or the VGA text buffer without one
+pub struct ConsoleSink;
+
+impl LogSink for ConsoleSink {
+    fn name(&self) -> &'static str {
+        "console"
+    }
+
+    fn write_str(&self, s: &str) {
+        crate::display::write_str(s);
+    }
+}
+
+pub struct SerialSink;
+
+impl LogSink for SerialSink {
+    fn name(&self) -> &'static str {
+        "serial"
+    }
+
+    fn write_str(&self, s: &str) {
+        let _ = crate::serial::SERIAL1.lock().write_str(s);
+    }
+}
+
+pub static CONSOLE_SINK: ConsoleSink = ConsoleSink;
+pub static SERIAL_SINK: SerialSink = SerialSink;
+
+// Registers the default sinks, records logged before this are only in the ring buffer
+pub fn init() {
+    add_sink(&CONSOLE_SINK, Level::Info);
+    add_sink(&SERIAL_SINK, Level::Trace);
+    add_sink(&crate::debugcon::DEBUGCON_SINK, Level::Trace);
+}
+
+// Applies the loglevel= and console= command line options
+pub fn apply_options(options: &crate::cmdline::Options) {
+    use crate::cmdline::ConsoleOutput;
+
+    if let Some(level) = options.loglevel {
+        set_sink_level(CONSOLE_SINK.name(), level);
+        if level > max_level() {
+            set_max_level(level);
+        }
+    }
+    match options.console {
+        ConsoleOutput::Screen => remove_sink(SERIAL_SINK.name()),
+        ConsoleOutput::Serial => remove_sink(CONSOLE_SINK.name()),
+        ConsoleOutput::Both => {}
+    }
+}
+
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => ({
+        $crate::log::log($level, module_path!(), format_args!($($arg)*));
+    });
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => (log!($crate::log::Level::Error, $($arg)*));
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => (log!($crate::log::Level::Warn, $($arg)*));
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => (log!($crate::log::Level::Info, $($arg)*));
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => (log!($crate::log::Level::Debug, $($arg)*));
+}
+
+macro_rules! trace {
+    ($($arg:tt)*) => (log!($crate::log::Level::Trace, $($arg)*));
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use std::string::String;
+    use std::vec::Vec;
+
+    fn lines(ring_buffer: &RingBuffer) -> Vec<String> {
+        let mut lines = Vec::new();
+        ring_buffer.for_each_line(|line| lines.push(String::from(line)));
+        lines
+    }
+
+    #[test]
+    fn records_are_formatted_with_prefix() {
+        let mut ring_buffer = RingBuffer::new();
+        write_record(&mut ring_buffer, false, Timestamp::Ticks(42), Level::Warn, "memory::heap", format_args!("{} bytes", 16));
+        write_record(&mut ring_buffer, false, Timestamp::Nanoseconds(3_004_005_006), Level::Info, "clock", format_args!("tick"));
+
+        assert_eq!(lines(&ring_buffer), vec![
+            String::from("[            42] WARN  memory::heap: 16 bytes"),
+            String::from("[      3.004005] INFO  clock: tick")
+        ]);
+    }
+
+    #[test]
+    fn oldest_lines_are_dropped_when_full() {
+        let mut ring_buffer = RingBuffer::new();
+        let line_count = RING_BUFFER_SIZE / 10 + 5;
+        for i in 0..line_count {
+            // 10 bytes per line with the newline
+            let _ = write!(ring_buffer, "line {:04}\n", i);
+        }
+
+        let lines = lines(&ring_buffer);
+        // The buffer ends with 1638 whole lines, the part of a line before them is skipped
+        assert_eq!(lines.len(), RING_BUFFER_SIZE / 10);
+        assert_eq!(lines.last().unwrap(), &format!("line {:04}", line_count - 1));
+        assert!(lines.iter().all(|line| line.len() == 9));
+    }
+
+    #[test]
+    fn long_lines_are_split() {
+        let mut ring_buffer = RingBuffer::new();
+        for _ in 0..MAX_LINE_LENGTH + 10 {
+            ring_buffer.push(b'a');
+        }
+
+        let lines = lines(&ring_buffer);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].len(), MAX_LINE_LENGTH);
+        assert_eq!(lines[1].len(), 10);
+    }
+}
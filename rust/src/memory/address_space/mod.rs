@@ -0,0 +1,236 @@
+use crate::memory::frame_allocator::FrameInfo;
+use crate::memory::paging::{invpcid, InvpcidType};
+use crate::utils::cpuid;
+use crate::utils::reg_read::{read_cr3, read_cr4};
+use crate::utils::reg_write::{write_cr3, write_cr4};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+pub const CR4_PCIDE: usize = 1 << 17;
+pub const CR3_NO_FLUSH: usize = 1 << 63;
+pub const CR3_PCID_MASK: usize = 0xfff;
+pub const PCID_AMOUNT: usize = 4096;
+
+// PCID 0 is used by the address space the kernel boots with
+pub const KERNEL_PCID: u16 = 0;
+
+static PCID_ENABLED: AtomicBool = AtomicBool::new(false);
+static INVPCID_AVAILABLE: AtomicBool = AtomicBool::new(false);
+
+pub struct PcidAllocator {
+    // One bit per PCID, set when the PCID is owned by an address space
+    used: [u64; PCID_AMOUNT / 64],
+    // One bit per PCID, set when the TLB may still hold entries left by a previous owner
+    stale: [u64; PCID_AMOUNT / 64]
+}
+
+static PCID_ALLOCATOR: Mutex<PcidAllocator> = Mutex::new(PcidAllocator {
+    used: [0; PCID_AMOUNT / 64],
+    stale: [0; PCID_AMOUNT / 64]
+});
+
+impl PcidAllocator {
+    fn is_set(bitmap: &[u64; PCID_AMOUNT / 64], pcid: u16) -> bool {
+        bitmap[pcid as usize / 64] & (1 << (pcid as usize % 64)) != 0
+    }
+
+    fn set(bitmap: &mut [u64; PCID_AMOUNT / 64], pcid: u16, value: bool) {
+        if value {
+            bitmap[pcid as usize / 64] |= 1 << (pcid as usize % 64);
+        }
+        else {
+            bitmap[pcid as usize / 64] &= !(1 << (pcid as usize % 64));
+        }
+    }
+
+    fn allocate(&mut self) -> Option<u16> {
+        // Find a non-full word, skipping the kernel PCID
+        for word in 0..self.used.len() {
+            let bits = self.used[word] | if word == 0 { 1 } else { 0 };
+            if bits != u64::MAX {
+                let pcid = (word * 64 + bits.trailing_ones() as usize) as u16;
+                PcidAllocator::set(&mut self.used, pcid, true);
+                return Some(pcid);
+            }
+        }
+
+        None
+    }
+
+    fn deallocate(&mut self, pcid: u16) {
+        PcidAllocator::set(&mut self.used, pcid, false);
+        PcidAllocator::set(&mut self.stale, pcid, true);
+    }
+
+    // Returns whether the PCID was stale, clearing the stale bit
+    fn take_stale(&mut self, pcid: u16) -> bool {
+        let stale = PcidAllocator::is_set(&self.stale, pcid);
+        PcidAllocator::set(&mut self.stale, pcid, false);
+        stale
+    }
+
+    fn mark_stale(&mut self, pcid: u16) {
+        PcidAllocator::set(&mut self.stale, pcid, true);
+    }
+
+    fn mark_all_stale_except(&mut self, pcid: u16) {
+        for word in self.stale.iter_mut() {
+            *word = u64::MAX;
+        }
+        PcidAllocator::set(&mut self.stale, pcid, false);
+    }
+}
+
+pub fn pcid_enabled() -> bool {
+    PCID_ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn invpcid_available() -> bool {
+    INVPCID_AVAILABLE.load(Ordering::Relaxed)
+}
+
+pub fn current_pcid() -> u16 {
+    (unsafe { read_cr3() } & CR3_PCID_MASK) as u16
+}
+
+// Makes the next switch to pcid flush its translations, for when they can't be flushed from
+// another PCID (no INVPCID)
+pub fn mark_stale(pcid: u16) {
+    PCID_ALLOCATOR.lock().mark_stale(pcid);
+}
+
+// Same as mark_stale for every PCID but the given one
+pub fn mark_all_stale_except(pcid: u16) {
+    PCID_ALLOCATOR.lock().mark_all_stale_except(pcid);
+}
+
+// Sets CR4.PCIDE when the processor supports it. Must be called while CR3 holds PCID 0, which is
+// always the case right after the kernel switches to its own page table.
+// Returns whether PCIDs are in use.
+pub unsafe fn init_pcid() -> bool {
+    if !cpuid::has_pcid() {
+        return false;
+    }
+
+    // Setting CR4.PCIDE with a non-zero PCID in CR3 raises #GP
+    assert_eq!(read_cr3() & CR3_PCID_MASK, 0, "Cannot enable PCIDs while CR3 holds a PCID.");
+
+    write_cr4(read_cr4() | CR4_PCIDE);
+    PCID_ENABLED.store(true, Ordering::Relaxed);
+    INVPCID_AVAILABLE.store(cpuid::has_invpcid(), Ordering::Relaxed);
+    true
+}
+
+pub struct AddressSpace {
+    pub p4_frame: FrameInfo,
+    pub pcid: u16
+}
+
+impl AddressSpace {
+    // The address space currently loaded in CR3 at boot, tagged with the kernel PCID
+    pub fn kernel(p4_frame: FrameInfo) -> AddressSpace {
+        AddressSpace {
+            p4_frame,
+            pcid: KERNEL_PCID
+        }
+    }
+
+    pub fn new(p4_frame: FrameInfo) -> AddressSpace {
+        // When PCIDs are disabled or exhausted, address spaces share the kernel PCID and every
+        // switch flushes the TLB
+        let pcid = if pcid_enabled() {
+            PCID_ALLOCATOR.lock().allocate().unwrap_or(KERNEL_PCID)
+        }
+        else {
+            KERNEL_PCID
+        };
+
+        AddressSpace {
+            p4_frame,
+            pcid
+        }
+    }
+
+    pub fn cr3_value(&self, no_flush: bool) -> usize {
+        if !pcid_enabled() {
+            return self.p4_frame.address;
+        }
+
+        let mut value = self.p4_frame.address | self.pcid as usize;
+        if no_flush {
+            value |= CR3_NO_FLUSH;
+        }
+        value
+    }
+
+    pub unsafe fn switch_to(&self) {
+        // A shared PCID may hold translations of another address space, and a recycled one may
+        // hold translations of its previous owner
+        let no_flush = pcid_enabled()
+            && self.pcid != KERNEL_PCID
+            && !PCID_ALLOCATOR.lock().take_stale(self.pcid);
+
+        write_cr3(self.cr3_value(no_flush));
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        if !pcid_enabled() || self.pcid == KERNEL_PCID {
+            return;
+        }
+
+        let mut allocator = PCID_ALLOCATOR.lock();
+        if invpcid_available() {
+            // Flush the translations right away so the PCID can be reused without a full flush
+            unsafe { invpcid(InvpcidType::SingleContext, self.pcid, 0) };
+            allocator.deallocate(self.pcid);
+            allocator.take_stale(self.pcid);
+        }
+        else {
+            allocator.deallocate(self.pcid);
+        }
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    fn allocator() -> PcidAllocator {
+        PcidAllocator {
+            used: [0; PCID_AMOUNT / 64],
+            stale: [0; PCID_AMOUNT / 64]
+        }
+    }
+
+    #[test]
+    fn recycled_pcid_is_stale() {
+        let mut allocator = allocator();
+        let pcid = allocator.allocate().unwrap();
+        assert_ne!(pcid, KERNEL_PCID);
+        assert!(!allocator.take_stale(pcid));
+
+        allocator.deallocate(pcid);
+        assert_eq!(allocator.allocate(), Some(pcid));
+        assert!(allocator.take_stale(pcid));
+        assert!(!allocator.take_stale(pcid));
+    }
+
+    #[test]
+    fn stale_marking() {
+        let mut allocator = allocator();
+        let a = allocator.allocate().unwrap();
+        let b = allocator.allocate().unwrap();
+
+        allocator.mark_stale(b);
+        assert!(!allocator.take_stale(a));
+        assert!(allocator.take_stale(b));
+
+        allocator.mark_all_stale_except(a);
+        assert!(!allocator.take_stale(a));
+        assert!(allocator.take_stale(b));
+        assert!(allocator.take_stale(KERNEL_PCID));
+        assert!(allocator.take_stale(4095));
+    }
+}
@@ -1,7 +1,7 @@
 use crate::utils::{ceil_div_usize};
-use stivale::memory::MemoryMapIter;
+use stivale::memory::MemoryMapEntry;
 use stivale::memory::MemoryMapEntryType::Usable;
-use crate::memory::paging::{EntryTable, PageInfo, EntryFlags, TableAccess};
+use core::ops::Range;
 
 pub const FRAME_SIZE: usize = 4096;
 
@@ -27,15 +27,50 @@ impl FrameInfo {
     }
 }
 
+// What the frame allocator needs to know about a memory map entry, so it doesn't depend on the
+// bootloader's types
+pub trait MemoryRegion {
+    fn start_address(&self) -> usize;
+    fn end_address(&self) -> usize;
+    fn is_usable(&self) -> bool;
+}
+
+impl MemoryRegion for MemoryMapEntry {
+    fn start_address(&self) -> usize {
+        MemoryMapEntry::start_address(self) as usize
+    }
+
+    fn end_address(&self) -> usize {
+        MemoryMapEntry::end_address(self) as usize
+    }
+
+    fn is_usable(&self) -> bool {
+        match self.entry_type() {
+            Usable => true,
+            _ => false
+        }
+    }
+}
+
+impl<'a, R: MemoryRegion> MemoryRegion for &'a R {
+    fn start_address(&self) -> usize {
+        (*self).start_address()
+    }
+
+    fn end_address(&self) -> usize {
+        (*self).end_address()
+    }
+
+    fn is_usable(&self) -> bool {
+        (*self).is_usable()
+    }
+}
+
 pub trait FrameAllocator {
     fn allocate_frame(&mut self) -> Option<FrameInfo>;
     fn deallocate_frame(&mut self, frame_info: FrameInfo);
-    unsafe fn identity_map(
-        &mut self,
-        p4_table: &mut EntryTable,
-        invalidate_addresses: bool,
-        current_table_access: TableAccess
-    );
+    // Frames holding the allocator's own data, which have to stay identity mapped
+    fn metadata_frames(&self) -> Range<usize>;
 }
 
 #[derive(Debug)]
@@ -80,8 +115,11 @@ impl BitMapFrameAllocator {
         }
     }
 
-    pub fn new(areas: MemoryMapIter) -> BitMapFrameAllocator {
-        let memory_end = areas.clone().max_by(|entry_1, entry_2| entry_1.end_address().cmp(&entry_2.end_address())).unwrap().end_address() as usize;
+    // physical_memory_offset is the virtual address physical memory is mapped at, 0 when it is
+    // identity mapped
+    pub fn new<I, R>(areas: I, physical_memory_offset: usize) -> BitMapFrameAllocator
+        where I: Iterator<Item = R> + Clone, R: MemoryRegion {
+        let memory_end = areas.clone().max_by(|entry_1, entry_2| entry_1.end_address().cmp(&entry_2.end_address())).unwrap().end_address();
 
         let mut areas = areas.clone();
         let mut areas_2 = areas.clone();
@@ -93,31 +131,26 @@ impl BitMapFrameAllocator {
 
         let mut found = false;
         let mut tested_mem_area = areas.next().unwrap();
-        let mut tested_frame = ceil_div_usize(tested_mem_area.start_address() as usize, FRAME_SIZE);
+        let mut tested_frame = ceil_div_usize(tested_mem_area.start_address(), FRAME_SIZE);
 
         while !found {
             // Checking if we fit into the mem area
-            let usable = match tested_mem_area.entry_type() {
-                Usable => true,
-                _ => false
-            };
-
-            if !usable ||
-                (tested_frame + continuous_frames_amount + 1) * FRAME_SIZE > (tested_mem_area.start_address() + tested_mem_area.size()) as usize {
+            if !tested_mem_area.is_usable() ||
+                (tested_frame + continuous_frames_amount + 1) * FRAME_SIZE > tested_mem_area.end_address() {
                 tested_mem_area = if let Some(area) = areas.next() {
                     area
                 }
                 else {
                     panic!("Could not find sufficiently big memory region to allocate bitmap.");
                 };
-                tested_frame = ceil_div_usize(tested_mem_area.start_address() as usize, FRAME_SIZE);
+                tested_frame = ceil_div_usize(tested_mem_area.start_address(), FRAME_SIZE);
             }
             else {
                 found = true;
             }
         }
 
-        let bitmap_ptr = (tested_frame * FRAME_SIZE) as *mut u8;
+        let bitmap_ptr = (physical_memory_offset + tested_frame * FRAME_SIZE) as *mut u8;
         let slice: &mut[u8] = unsafe {core::slice::from_raw_parts_mut::<'static>(bitmap_ptr, bitmap_length_in_bytes)};
 
         let mut allocator = BitMapFrameAllocator {
@@ -138,19 +171,15 @@ impl BitMapFrameAllocator {
         // Mark unavailable memory regions as allocated
         let areas = areas_2.clone();
         for area in areas {
-            let usable = match area.entry_type() {
-                Usable => true,
-                _ => false
-            };
-            if !usable {
-                allocator.mark_region(area.start_address() as usize, area.end_address() as usize, true);
+            if !area.is_usable() {
+                allocator.mark_region(area.start_address(), area.end_address(), true);
             }
         }
 
         // Mark non-present memory regions as allocated
         let mut previous = areas_2.next().unwrap();
         while let Some(current) = areas_2.next() {
-            allocator.mark_region(previous.end_address() as usize, current.start_address() as usize, true);
+            allocator.mark_region(previous.end_address(), current.start_address(), true);
             previous = current;
         }
 
@@ -190,25 +219,91 @@ impl FrameAllocator for BitMapFrameAllocator {
         self.mark_frame(frame_info.number, false);
     }
 
-    unsafe fn identity_map(
-        &mut self,
-        p4_table: &mut EntryTable,
-        invalidate_addresses: bool,
-        current_table_access: TableAccess
-    ) {
-        let start_frame = self.bitmap_frame;
-        let end_frame = self.bitmap_frame + self.bitmap_size_in_frames;
-        let flags = EntryFlags::PRESENT | EntryFlags::WRITABLE;
-        for i in start_frame..end_frame {
-            p4_table.p4_map(
-                FrameInfo::from_number(i),
-                PageInfo::from_number(i),
-                flags,
-                false,
-                invalidate_addresses,
-                current_table_access,
-                self
-            );
+    fn metadata_frames(&self) -> Range<usize> {
+        self.bitmap_frame..self.bitmap_frame + self.bitmap_size_in_frames
+    }
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use crate::memory::test_utils::{SimulatedMemory, SimulatedRegion};
+    use std::vec::Vec;
+
+    // Frame 0 reserved, frames 1..16 usable, 16..32 missing, 32..48 usable, 48..64 reserved
+    fn memory_map() -> Vec<SimulatedRegion> {
+        vec![
+            SimulatedRegion::reserved(0x0, 0x1000),
+            SimulatedRegion::usable(0x1000, 0x10000),
+            SimulatedRegion::usable(0x20000, 0x30000),
+            SimulatedRegion::reserved(0x30000, 0x40000)
+        ]
+    }
+
+    fn frame_allocator(memory_map: &[SimulatedRegion], memory: &SimulatedMemory, bitmap_address: usize) -> BitMapFrameAllocator {
+        BitMapFrameAllocator::new(memory_map.iter(), memory.physical_memory_offset(bitmap_address))
+    }
+
+    #[test]
+    fn bitmap_is_placed_in_first_usable_region() {
+        let memory = SimulatedMemory::new(1);
+        let allocator = frame_allocator(&memory_map(), &memory, 0x1000);
+
+        assert_eq!(allocator.bitmap_frame, 1);
+        assert_eq!(allocator.frames_amount, 64);
+        assert_eq!(allocator.bitmap_size_in_bytes, 8);
+        assert_eq!(allocator.metadata_frames(), 1..2);
+        assert_eq!(allocator.slice.as_ptr() as usize, memory.start());
+    }
+
+    #[test]
+    fn bitmap_skips_regions_too_small_for_it() {
+        let memory_map = vec![
+            SimulatedRegion::usable(0x1000, 0x2000),
+            SimulatedRegion::usable(0x5000, 0x10000)
+        ];
+        let memory = SimulatedMemory::new(1);
+        let allocator = frame_allocator(&memory_map, &memory, 0x5000);
+
+        assert_eq!(allocator.bitmap_frame, 5);
+    }
+
+    #[test]
+    fn only_usable_frames_are_allocated() {
+        let memory = SimulatedMemory::new(1);
+        let mut allocator = frame_allocator(&memory_map(), &memory, 0x1000);
+
+        let mut frames = Vec::new();
+        while let Some(frame) = allocator.allocate_frame() {
+            assert_eq!(frame.address, frame.number * FRAME_SIZE);
+            frames.push(frame.number);
         }
+
+        // Frame 1 holds the bitmap
+        let expected: Vec<usize> = (2..16).chain(32..48).collect();
+        assert_eq!(frames, expected);
+    }
+
+    #[test]
+    fn deallocated_frames_are_reused() {
+        let memory = SimulatedMemory::new(1);
+        let mut allocator = frame_allocator(&memory_map(), &memory, 0x1000);
+
+        let first = allocator.allocate_frame().unwrap();
+        let second = allocator.allocate_frame().unwrap();
+        allocator.deallocate_frame(first);
+
+        assert_eq!(allocator.allocate_frame(), Some(first));
+        assert_ne!(allocator.allocate_frame(), Some(second));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn marked_frames_are_not_allocated() {
+        let memory = SimulatedMemory::new(1);
+        let mut allocator = frame_allocator(&memory_map(), &memory, 0x1000);
+
+        allocator.mark_region(0x2000, 0x4000, true);
+
+        assert_eq!(allocator.allocate_frame(), Some(FrameInfo::from_number(4)));
+    }
+}
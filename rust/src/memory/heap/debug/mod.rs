@@ -0,0 +1,217 @@
+// Heap debugging mode, enabled with the heap_debug feature.
+//
+// Every allocation is wrapped in a block laid out as follows :
+//
+// | reserved | state | size | front red zone | data | back red zone |
+//
+// The reserved bytes are left for the hole list node dealloc writes at the start of freed blocks,
+// so the state stays readable after a free and double frees can be detected. Red zones are filled
+// with a canary checked on free, and freed blocks are filled with a poison pattern.
+
+use crate::memory::frame_allocator::FrameAllocator;
+use crate::memory::paging::PageMapper;
+use crate::memory::heap::{LinkedListHeapAllocator, LIST_HEAP_NODE_SIZE};
+use core::alloc::Layout;
+
+pub const POISON_BYTE: u8 = 0x6b;
+pub const CANARY_BYTE: u8 = 0xa5;
+pub const RED_ZONE_SIZE: usize = 16;
+
+pub const ALLOCATED_MAGIC: u64 = 0xa110_ca7e_d0d0_a110;
+pub const FREED_MAGIC: u64 = 0xf4ee_d0d0_f4ee_d0d0;
+
+// Blocks start 8 bytes aligned as long as every block size is a multiple of 8 (see block_layout),
+// rounding the reserved bytes keeps the state, size and data aligned as well
+const RESERVED_SIZE: usize = (LIST_HEAP_NODE_SIZE + 7) / 8 * 8;
+const STATE_OFFSET: usize = RESERVED_SIZE;
+const SIZE_OFFSET: usize = STATE_OFFSET + 8;
+const FRONT_RED_ZONE_OFFSET: usize = SIZE_OFFSET + 8;
+pub const DATA_OFFSET: usize = FRONT_RED_ZONE_OFFSET + RED_ZONE_SIZE;
+pub const BLOCK_OVERHEAD: usize = DATA_OFFSET + RED_ZONE_SIZE;
+
+fn block_layout(layout: Layout) -> Layout {
+    let size = layout.size().checked_add(BLOCK_OVERHEAD + 7).expect("Allocation too large.") / 8 * 8;
+    unsafe { Layout::from_size_align_unchecked(size, layout.align()) }
+}
+
+unsafe fn fill(start: usize, length: usize, byte: u8) {
+    core::ptr::write_bytes(start as *mut u8, byte, length);
+}
+
+// Returns the address of the first byte that isn't the canary
+unsafe fn check_canary(start: usize, length: usize) -> Option<usize> {
+    (start..start + length).find(|&address| *(address as *const u8) != CANARY_BYTE)
+}
+
+pub unsafe fn alloc<T: FrameAllocator, M: PageMapper>(allocator: &LinkedListHeapAllocator<T, M>, layout: Layout) -> *mut u8 {
+    let block = allocator.raw_alloc(block_layout(layout)) as usize;
+    let data = block + DATA_OFFSET;
+
+    *((block + STATE_OFFSET) as *mut u64) = ALLOCATED_MAGIC;
+    *((block + SIZE_OFFSET) as *mut u64) = layout.size() as u64;
+    fill(block + FRONT_RED_ZONE_OFFSET, RED_ZONE_SIZE, CANARY_BYTE);
+    fill(data + layout.size(), RED_ZONE_SIZE, CANARY_BYTE);
+
+    data as *mut u8
+}
+
+pub unsafe fn dealloc<T: FrameAllocator, M: PageMapper>(allocator: &LinkedListHeapAllocator<T, M>, ptr: *mut u8, layout: Layout) {
+    let data = ptr as usize;
+    let size = layout.size();
+
+    // Only the used part of the heap is mapped, anything past it can't be read
+    let heap_start = allocator.heap_start();
+    let heap_end = allocator.used_end();
+    if data < heap_start + DATA_OFFSET || data.saturating_add(size + RED_ZONE_SIZE) > heap_end {
+        panic!(
+            "Heap debug : freed pointer 0x{:x} (size {}) is outside of the heap (0x{:x}..0x{:x}).",
+            data, size, heap_start, heap_end
+        );
+    }
+
+    // Every data pointer handed out is 8 bytes aligned, others can't even be checked
+    if data % 8 != 0 {
+        panic!("Heap debug : freed pointer 0x{:x} (size {}) was not allocated.", data, size);
+    }
+
+    let block = data - DATA_OFFSET;
+    match *((block + STATE_OFFSET) as *const u64) {
+        ALLOCATED_MAGIC => {}
+        FREED_MAGIC => panic!("Heap debug : double free of 0x{:x} (size {}).", data, size),
+        _ => panic!("Heap debug : freed pointer 0x{:x} (size {}) was not allocated, or its header was overwritten.", data, size)
+    }
+
+    let recorded_size = *((block + SIZE_OFFSET) as *const u64) as usize;
+    if recorded_size != size {
+        panic!(
+            "Heap debug : 0x{:x} was allocated with size {} but freed with size {}.",
+            data, recorded_size, size
+        );
+    }
+
+    if let Some(address) = check_canary(block + FRONT_RED_ZONE_OFFSET, RED_ZONE_SIZE) {
+        panic!(
+            "Heap debug : buffer underflow on 0x{:x} (size {}), front red zone overwritten at 0x{:x}.",
+            data, size, address
+        );
+    }
+    if let Some(address) = check_canary(data + size, RED_ZONE_SIZE) {
+        panic!(
+            "Heap debug : buffer overflow on 0x{:x} (size {}), back red zone overwritten at 0x{:x}.",
+            data, size, address
+        );
+    }
+
+    // Poison everything but the state, which has to survive for double free detection
+    let block_layout = block_layout(layout);
+    fill(block, STATE_OFFSET, POISON_BYTE);
+    fill(block + SIZE_OFFSET, block_layout.size() - SIZE_OFFSET, POISON_BYTE);
+    *((block + STATE_OFFSET) as *mut u64) = FREED_MAGIC;
+
+    allocator.raw_dealloc(block as *mut u8, block_layout);
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use crate::memory::test_utils::{MockFrameAllocator, MockPageMapper, SimulatedMemory};
+
+    type TestAllocator = LinkedListHeapAllocator<MockFrameAllocator, MockPageMapper>;
+
+    fn heap(memory: &SimulatedMemory) -> TestAllocator {
+        unsafe {
+            LinkedListHeapAllocator::new(
+                MockFrameAllocator::new(0x100),
+                MockPageMapper::default(),
+                memory.start_frame(),
+                memory.end() - memory.start()
+            )
+        }
+    }
+
+    fn layout(size: usize) -> Layout {
+        Layout::from_size_align(size, 1).unwrap()
+    }
+
+    #[test]
+    fn blocks_stay_aligned() {
+        let memory = SimulatedMemory::new(1);
+        let allocator = heap(&memory);
+
+        unsafe {
+            for size in 1..20 {
+                assert_eq!(alloc(&allocator, layout(size)) as usize % 8, 0);
+            }
+        }
+    }
+
+    #[test]
+    fn freed_memory_is_poisoned() {
+        let memory = SimulatedMemory::new(1);
+        let allocator = heap(&memory);
+
+        unsafe {
+            let data = alloc(&allocator, layout(40));
+            // Keeps the freed block from merging into the end of the heap
+            alloc(&allocator, layout(8));
+            core::ptr::write_bytes(data, 0, 40);
+            dealloc(&allocator, data, layout(40));
+
+            let freed = core::slice::from_raw_parts(data, 40);
+            assert!(freed.iter().all(|&byte| byte == POISON_BYTE));
+            assert_eq!(*(data.sub(DATA_OFFSET - STATE_OFFSET) as *const u64), FREED_MAGIC);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "double free")]
+    fn double_free_panics() {
+        let memory = SimulatedMemory::new(1);
+        let allocator = heap(&memory);
+
+        unsafe {
+            let data = alloc(&allocator, layout(32));
+            alloc(&allocator, layout(8));
+            dealloc(&allocator, data, layout(32));
+            dealloc(&allocator, data, layout(32));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "was allocated with size 32 but freed with size 16")]
+    fn size_mismatch_panics() {
+        let memory = SimulatedMemory::new(1);
+        let allocator = heap(&memory);
+
+        unsafe {
+            let data = alloc(&allocator, layout(32));
+            dealloc(&allocator, data, layout(16));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "buffer underflow")]
+    fn overwritten_front_red_zone_panics() {
+        let memory = SimulatedMemory::new(1);
+        let allocator = heap(&memory);
+
+        unsafe {
+            let data = alloc(&allocator, layout(32));
+            *data.sub(1) = 0;
+            dealloc(&allocator, data, layout(32));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "buffer overflow")]
+    fn overwritten_back_red_zone_panics() {
+        let memory = SimulatedMemory::new(1);
+        let allocator = heap(&memory);
+
+        unsafe {
+            let data = alloc(&allocator, layout(32));
+            *data.add(32) = 0;
+            dealloc(&allocator, data, layout(32));
+        }
+    }
+}
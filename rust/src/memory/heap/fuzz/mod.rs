@@ -0,0 +1,255 @@
+// Randomised differential harness for LinkedListHeapAllocator, run on the host with cargo test.
+//
+// Random alloc / free sequences are applied both to the allocator and to a model of the live
+// blocks. After every step the allocator's hole list is checked against the model :
+// holes are sorted, never adjacent, inside of the used part of the heap, and together with the live
+// blocks they exactly cover it. Live blocks are filled with a per block pattern checked on free, so
+// a hole list node written over live data is caught as well. Blocks go through raw_alloc and
+// raw_dealloc, so the heap_debug wrapping doesn't change what is checked.
+//
+// HEAP_FUZZ_SEED reruns a single seed, HEAP_FUZZ_STEPS changes the length of the sequences.
+
+use super::*;
+use crate::memory::test_utils::{MockFrameAllocator, MockPageMapper, SimulatedMemory};
+use std::collections::BTreeMap;
+use std::env;
+use std::format;
+use std::string::String;
+use std::vec::Vec;
+
+const DEFAULT_SEEDS: u64 = 16;
+const DEFAULT_STEPS: usize = 2000;
+const HEAP_FRAMES: usize = 1024;
+// Live bytes are kept under this so fragmentation never exhausts the simulated heap
+const MAX_LIVE_BYTES: usize = 256 * 1024;
+
+type FuzzAllocator = LinkedListHeapAllocator<MockFrameAllocator, MockPageMapper>;
+
+// xorshift64*, good enough to drive the harness without pulling in a dependency
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        Rng(seed.wrapping_mul(0x9e3779b97f4a7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next() % bound as u64) as usize
+    }
+
+    fn chance(&mut self, percent: usize) -> bool {
+        self.below(100) < percent
+    }
+}
+
+struct LiveBlock {
+    layout: Layout,
+    pattern: u8
+}
+
+struct Harness {
+    allocator: FuzzAllocator,
+    memory: SimulatedMemory,
+    live: BTreeMap<usize, LiveBlock>,
+    live_bytes: usize,
+    rng: Rng
+}
+
+// Bytes the allocator actually reserves for a request
+fn reserved_size(layout: Layout) -> usize {
+    layout.size().max(LIST_HEAP_NODE_SIZE)
+}
+
+impl Harness {
+    fn new(seed: u64) -> Harness {
+        let memory = SimulatedMemory::new(HEAP_FRAMES);
+        let allocator = unsafe {
+            LinkedListHeapAllocator::new(
+                MockFrameAllocator::new(0x100),
+                MockPageMapper::default(),
+                memory.start_frame(),
+                memory.end() - memory.start()
+            )
+        };
+        Harness {
+            allocator,
+            memory,
+            live: BTreeMap::new(),
+            live_bytes: 0,
+            rng: Rng::new(seed)
+        }
+    }
+
+    fn random_layout(&mut self) -> Layout {
+        // Mostly small blocks, sometimes large ones
+        let size = match self.rng.below(10) {
+            0 => 1 + self.rng.below(8192),
+            1..=3 => 1 + self.rng.below(512),
+            _ => 1 + self.rng.below(64)
+        };
+        // Alignment isn't honoured by the allocator yet, but it is part of the layout it receives
+        let align = 1 << self.rng.below(7);
+        Layout::from_size_align(size, align).unwrap()
+    }
+
+    fn alloc(&mut self) -> Result<(), String> {
+        let layout = self.random_layout();
+        let address = unsafe { self.allocator.raw_alloc(layout) } as usize;
+        let end = address + reserved_size(layout);
+
+        if address < self.memory.start() || end > self.memory.end() {
+            return Err(format!("block 0x{:x}..0x{:x} is outside of the heap", address, end));
+        }
+        if let Some((&previous, block)) = self.live.range(..address).next_back() {
+            if previous + reserved_size(block.layout) > address {
+                return Err(format!("block 0x{:x} overlaps live block 0x{:x}", address, previous));
+            }
+        }
+        if let Some((&next, _)) = self.live.range(address..).next() {
+            if next < end {
+                return Err(format!("block 0x{:x}..0x{:x} overlaps live block 0x{:x}", address, end, next));
+            }
+        }
+
+        let pattern = self.rng.next() as u8;
+        unsafe { core::ptr::write_bytes(address as *mut u8, pattern, layout.size()) };
+        self.live.insert(address, LiveBlock { layout, pattern });
+        self.live_bytes += reserved_size(layout);
+        Ok(())
+    }
+
+    fn free(&mut self) -> Result<(), String> {
+        let index = self.rng.below(self.live.len());
+        let address = *self.live.keys().nth(index).unwrap();
+        let block = self.live.remove(&address).unwrap();
+
+        let data = unsafe { core::slice::from_raw_parts(address as *const u8, block.layout.size()) };
+        if let Some(offset) = data.iter().position(|&byte| byte != block.pattern) {
+            return Err(format!("live block 0x{:x} was overwritten at offset {}", address, offset));
+        }
+
+        unsafe { self.allocator.raw_dealloc(address as *mut u8, block.layout) };
+        self.live_bytes -= reserved_size(block.layout);
+        Ok(())
+    }
+
+    fn check_invariants(&self) -> Result<(), String> {
+        let inner = self.allocator.inner.lock();
+        let used_start = self.memory.start();
+        let used_end = used_start + inner.max_currently_used;
+
+        let mut hole_bytes = 0;
+        let mut previous_end = None;
+        let mut current = &inner.holes as *const ListHeapNode;
+        unsafe {
+            while !(*current).is_last {
+                current = (*current).next_node as *const ListHeapNode;
+                let start = current as usize;
+                let size = (*current).hole_size;
+                let end = start + size;
+
+                if start < used_start || end > used_end {
+                    return Err(format!("hole 0x{:x}..0x{:x} is outside of the used heap", start, end));
+                }
+                if size < LIST_HEAP_NODE_SIZE {
+                    return Err(format!("hole 0x{:x} is too small to hold a node ({} bytes)", start, size));
+                }
+                if let Some(previous_end) = previous_end {
+                    if start < previous_end {
+                        return Err(format!("hole 0x{:x} is not sorted after the previous one", start));
+                    }
+                    if start == previous_end {
+                        return Err(format!("hole 0x{:x} is adjacent to the previous one", start));
+                    }
+                }
+                if let Some((&block, layout)) = self.live.range(..end).next_back().map(|(a, b)| (a, b.layout)) {
+                    if block + reserved_size(layout) > start {
+                        return Err(format!("hole 0x{:x}..0x{:x} overlaps live block 0x{:x}", start, end, block));
+                    }
+                }
+
+                hole_bytes += size;
+                previous_end = Some(end);
+            }
+        }
+
+        // Every used byte is either in a hole or in a live block
+        if hole_bytes + self.live_bytes != inner.max_currently_used {
+            return Err(format!(
+                "holes ({} bytes) and live blocks ({} bytes) don't cover the used heap ({} bytes)",
+                hole_bytes, self.live_bytes, inner.max_currently_used
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn step(&mut self) -> Result<(), String> {
+        let must_free = self.live_bytes > MAX_LIVE_BYTES;
+        if !self.live.is_empty() && (must_free || self.rng.chance(45)) {
+            self.free()?;
+        }
+        else {
+            self.alloc()?;
+        }
+        self.check_invariants()
+    }
+
+    // Frees everything left, the heap must end up as a single hole
+    fn drain(&mut self) -> Result<(), String> {
+        while !self.live.is_empty() {
+            self.free()?;
+            self.check_invariants()?;
+        }
+
+        let inner = self.allocator.inner.lock();
+        let mut hole_count = 0;
+        let mut current = &inner.holes as *const ListHeapNode;
+        unsafe {
+            while !(*current).is_last {
+                current = (*current).next_node as *const ListHeapNode;
+                hole_count += 1;
+            }
+        }
+        if inner.max_currently_used > 0 && hole_count != 1 {
+            return Err(format!("freeing everything left {} holes instead of one", hole_count));
+        }
+        Ok(())
+    }
+}
+
+fn run(seed: u64, steps: usize) {
+    let mut harness = Harness::new(seed);
+    for step in 0..steps {
+        if let Err(error) = harness.step() {
+            panic!("Heap fuzz seed {} failed at step {} : {}", seed, step, error);
+        }
+    }
+    if let Err(error) = harness.drain() {
+        panic!("Heap fuzz seed {} failed while draining : {}", seed, error);
+    }
+}
+
+fn env_number(name: &str) -> Option<u64> {
+    env::var(name).ok().map(|value| value.parse().expect("Expected a number."))
+}
+
+#[test]
+fn random_alloc_free_sequences_keep_heap_consistent() {
+    let steps = env_number("HEAP_FUZZ_STEPS").map(|steps| steps as usize).unwrap_or(DEFAULT_STEPS);
+    match env_number("HEAP_FUZZ_SEED") {
+        Some(seed) => run(seed, steps),
+        None => {
+            for seed in 0..DEFAULT_SEEDS {
+                run(seed, steps);
+            }
+        }
+    }
+}
@@ -1,23 +1,29 @@
-use crate::memory::frame_allocator::{FrameAllocator, FRAME_SIZE};
-use crate::memory::paging::{EntryTable, PageInfo, EntryFlags, TableAccess};
+use crate::memory::frame_allocator::{FrameAllocator, FrameInfo, FRAME_SIZE};
+use crate::memory::paging::{PageInfo, EntryFlags, PageMapper};
 use core::alloc::{Layout, GlobalAlloc};
 use crate::utils::ceil_div_usize;
 use spin::Mutex;
 use core::ops::DerefMut;
-use crate::HEAP_OFFSET;
+
+#[cfg(feature = "heap_debug")]
+pub mod debug;
+#[cfg(feature = "heap_tracking")]
+pub mod tracking;
+#[cfg(all(test, not(target_os = "none")))]
+mod fuzz;
 
 pub struct AllocOption<T> (pub Option<T>);
 
-pub struct LinkedListHeapAllocatorInner {
-    pub p4_table: &'static mut EntryTable,
+pub struct LinkedListHeapAllocatorInner<M: PageMapper> {
+    pub mapper: M,
     pub virtual_start_frame: usize,
     pub max_memory_amount: usize,
     pub max_currently_used: usize,
     pub holes: ListHeapNode
 }
 
-pub struct LinkedListHeapAllocator<T: FrameAllocator> {
-    inner: Mutex<LinkedListHeapAllocatorInner>,
+pub struct LinkedListHeapAllocator<T: FrameAllocator, M: PageMapper> {
+    inner: Mutex<LinkedListHeapAllocatorInner<M>>,
     frame_allocator: Mutex<T>
 }
 
@@ -31,18 +37,18 @@ pub struct ListHeapNode {
 
 pub const LIST_HEAP_NODE_SIZE: usize = core::mem::size_of::<ListHeapNode>();
 
-impl<T: FrameAllocator> LinkedListHeapAllocator<T> {
+impl<T: FrameAllocator, M: PageMapper> LinkedListHeapAllocator<T, M> {
     pub unsafe fn new(
         frame_allocator: T,
-        p4_table: &'static mut EntryTable,
+        mapper: M,
         virtual_start_frame: usize,
         max_memory_amount: usize
-    ) -> LinkedListHeapAllocator<T> {
+    ) -> LinkedListHeapAllocator<T, M> {
         let max_currently_used = 0;
 
         let allocator = LinkedListHeapAllocator {
             inner: Mutex::new(LinkedListHeapAllocatorInner {
-                p4_table,
+                mapper,
                 virtual_start_frame,
                 max_memory_amount,
                 max_currently_used,
@@ -53,11 +59,47 @@ impl<T: FrameAllocator> LinkedListHeapAllocator<T> {
 
         allocator
     }
+
+    // For panic handlers that carry on running, the panicking code may have been allocating
+    pub unsafe fn force_unlock(&self) {
+        self.inner.force_unlock();
+        self.frame_allocator.force_unlock();
+    }
+
+    pub unsafe fn map_frame(&self, frame: FrameInfo, page: PageInfo, flags: EntryFlags) {
+        let mut inner = self.inner.lock();
+        let mut frame_allocator = self.frame_allocator.lock();
+        inner.mapper.map(frame, page, flags, frame_allocator.deref_mut());
+    }
+
+    pub unsafe fn map_new_frame(&self, page: PageInfo, flags: EntryFlags) -> FrameInfo {
+        let frame = self.frame_allocator
+            .lock()
+            .allocate_frame()
+            .expect("Out of memory (cannot get frame to map).");
+        self.map_frame(frame, page, flags);
+        frame
+    }
 }
 
-unsafe impl<T: FrameAllocator> GlobalAlloc for LinkedListHeapAllocator<T> {
+impl<T: FrameAllocator, M: PageMapper> LinkedListHeapAllocator<T, M> {
+    pub fn heap_start(&self) -> usize {
+        self.inner.lock().virtual_start_frame * FRAME_SIZE
+    }
+
+    pub fn heap_end(&self) -> usize {
+        let inner = self.inner.lock();
+        inner.virtual_start_frame * FRAME_SIZE + inner.max_memory_amount
+    }
+
+    // End of the part of the heap handed out so far, which is mapped
+    pub fn used_end(&self) -> usize {
+        let inner = self.inner.lock();
+        inner.virtual_start_frame * FRAME_SIZE + inner.max_currently_used
+    }
+
     // TODO : take layout alignment in account
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+    pub unsafe fn raw_alloc(&self, layout: Layout) -> *mut u8 {
         let mut inner = self.inner.lock();
         let mut size = layout.size();
         // We don't want to leave micro holes when deallocating
@@ -124,25 +166,22 @@ unsafe impl<T: FrameAllocator> GlobalAlloc for LinkedListHeapAllocator<T> {
 
                 let page = PageInfo::from_number(inner.virtual_start_frame + i);
 
-                inner.p4_table.p4_map(
+                inner.mapper.map(
                     new_physical_frame,
                     page,
                     EntryFlags::PRESENT | EntryFlags::WRITABLE,
-                    false,
-                    true,
-                    TableAccess::Recursive,
                     frame_allocator
                 );
             }
         }
 
-        let ptr = (prev_max_currently_used + HEAP_OFFSET) as *mut u8;
+        let ptr = (prev_max_currently_used + inner.virtual_start_frame * FRAME_SIZE) as *mut u8;
         ptr
     }
 
     // TODO : free pages
     // TODO : take layout alignment in account
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    pub unsafe fn raw_dealloc(&self, ptr: *mut u8, layout: Layout) {
         let mut inner = self.inner.lock();
         let mut size = layout.size();
         // We didn't allow micro holes when allocating
@@ -150,88 +189,92 @@ unsafe impl<T: FrameAllocator> GlobalAlloc for LinkedListHeapAllocator<T> {
             size = LIST_HEAP_NODE_SIZE
         }
 
-        // Find the right spot for the node in the linked list
-        let mut current = &mut inner.holes;
-        let first = current as *const ListHeapNode;
-        loop {
-            if current.is_last {
-                let current_hole_address = (current as *const ListHeapNode) as usize;
-                if current.hole_size + current_hole_address == ptr as usize && !core::ptr::eq(current, first) {
-                    // We free a hole at the very end of the current one, so we can just extend
-                    // the current one
-                    current.hole_size += size;
-                    return;
-                }
-                else {
-                    // We append a hole to the hole list
-                    current.is_last = false;
-                    current.next_node = ptr as usize; // Hole will be placed on ptr
-                    let new_hole = &mut *(ptr as *mut ListHeapNode);
-                    *new_hole = ListHeapNode {
-                        hole_size: size,
-                        is_last: true,
-                        next_node: 0
-                    };
-                    return;
-                }
+        // Holes are sorted by address, find the last one placed before the freed block. The first
+        // node is a sentinel living outside of the heap, which is never merged with.
+        let first = &mut inner.holes as *mut ListHeapNode;
+        let mut current = first;
+        while !(*current).is_last && (*current).next_node < ptr as usize {
+            current = (*current).next_node as *mut ListHeapNode;
+        }
+        let current = &mut *current;
+
+        let current_hole_address = (current as *const ListHeapNode) as usize;
+        let new_hole_address = ptr as usize;
+        let next_hole_address = current.next_node;
+
+        let merge_with_current = !core::ptr::eq(current, first)
+            && current_hole_address + current.hole_size == new_hole_address;
+        let merge_with_next = !current.is_last && new_hole_address + size == next_hole_address;
+
+        if merge_with_current {
+            if merge_with_next {
+                // Merge current with new and next
+                let next = &*(next_hole_address as *const ListHeapNode);
+                current.hole_size += size + next.hole_size;
+                current.is_last = next.is_last;
+                current.next_node = next.next_node;
             }
             else {
-                let current_hole_address = (current as *const ListHeapNode) as usize;
-                if ptr as usize > current_hole_address {
-                    // We found the right spot for our hole
-
-                    let next_hole_address = current.next_node;
-                    let next = &mut *(next_hole_address as *mut ListHeapNode);
-                    let new_hole = &mut *(ptr as *mut ListHeapNode);
-                    let new_hole_address = ptr as usize;
-
-                    if current_hole_address + current.hole_size == ptr as usize && !core::ptr::eq(current, first) {
-                        if new_hole_address + size == next_hole_address {
-                            // Merge current with next
-                            current.hole_size += size + next.hole_size;
-                            current.next_node = next.next_node;
-                        }
-                        else {
-                            // Merge new with current
-                            current.hole_size += size;
-                        }
-                    }
-                    else {
-                        if new_hole_address + size == next_hole_address {
-                            // Merge new with next
-                            let is_last = next.is_last;
-                            let after_next_address = next.next_node;
-                            let size = size + next.hole_size;
-
-                            current.next_node = new_hole_address;
-                            *new_hole = ListHeapNode {
-                                next_node: after_next_address,
-                                is_last,
-                                hole_size: size
-                            };
-                        }
-                        else {
-                            // Insert new hole without merging
-                            current.next_node = new_hole_address;
-                            *new_hole = ListHeapNode {
-                                next_node: next_hole_address,
-                                is_last: false,
-                                hole_size: size
-                            };
-                        }
-                    }
-
-                    return;
-                }
+                // Merge new with current
+                current.hole_size += size;
             }
+        }
+        else {
+            let new_hole = &mut *(ptr as *mut ListHeapNode);
+            if merge_with_next {
+                // Merge new with next
+                let next = &*(next_hole_address as *const ListHeapNode);
+                let is_last = next.is_last;
+                let after_next_address = next.next_node;
+                let size = size + next.hole_size;
 
-            let next_node = current.next_node;
-            current = &mut *(next_node as *mut ListHeapNode);
+                *new_hole = ListHeapNode {
+                    next_node: after_next_address,
+                    is_last,
+                    hole_size: size
+                };
+            }
+            else {
+                // Insert new hole without merging
+                *new_hole = ListHeapNode {
+                    next_node: next_hole_address,
+                    is_last: current.is_last,
+                    hole_size: size
+                };
+            }
+            current.is_last = false;
+            current.next_node = new_hole_address;
         }
     }
 }
 
-unsafe impl<T: FrameAllocator> GlobalAlloc for AllocOption<LinkedListHeapAllocator<T>> {
+unsafe impl<T: FrameAllocator, M: PageMapper> GlobalAlloc for LinkedListHeapAllocator<T, M> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        #[cfg(feature = "heap_debug")]
+        let ptr = debug::alloc(self, layout);
+
+        #[cfg(not(feature = "heap_debug"))]
+        let ptr = self.raw_alloc(layout);
+
+        #[cfg(feature = "heap_tracking")]
+        tracking::record_alloc(ptr, layout);
+
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        #[cfg(feature = "heap_tracking")]
+        tracking::record_dealloc(ptr, layout);
+
+        #[cfg(feature = "heap_debug")]
+        debug::dealloc(self, ptr, layout);
+
+        #[cfg(not(feature = "heap_debug"))]
+        self.raw_dealloc(ptr, layout);
+    }
+}
+
+unsafe impl<T: FrameAllocator, M: PageMapper> GlobalAlloc for AllocOption<LinkedListHeapAllocator<T, M>> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         if let Some(alloc) = &self.0 {
             alloc.alloc(layout)
@@ -249,4 +292,196 @@ unsafe impl<T: FrameAllocator> GlobalAlloc for AllocOption<LinkedListHeapAllocat
             panic!("Tried using heap allocator before initializing it.");
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use crate::memory::test_utils::{MockFrameAllocator, MockPageMapper, SimulatedMemory};
+    use std::vec::Vec;
+
+    type TestAllocator = LinkedListHeapAllocator<MockFrameAllocator, MockPageMapper>;
+
+    fn heap(memory: &SimulatedMemory) -> (TestAllocator, MockPageMapper) {
+        let mapper = MockPageMapper::default();
+        let allocator = unsafe {
+            LinkedListHeapAllocator::new(
+                MockFrameAllocator::new(0x100),
+                mapper.clone(),
+                memory.start_frame(),
+                memory.end() - memory.start()
+            )
+        };
+        (allocator, mapper)
+    }
+
+    fn layout(size: usize) -> Layout {
+        Layout::from_size_align(size, 1).unwrap()
+    }
+
+    // (address, size) of every hole, the sentinel excluded
+    fn holes(allocator: &TestAllocator) -> Vec<(usize, usize)> {
+        let inner = allocator.inner.lock();
+        let mut holes = Vec::new();
+        let mut current = &inner.holes as *const ListHeapNode;
+        unsafe {
+            while !(*current).is_last {
+                current = (*current).next_node as *const ListHeapNode;
+                holes.push((current as usize, (*current).hole_size));
+            }
+        }
+        holes
+    }
+
+    #[test]
+    fn allocations_are_bump_allocated_and_mapped_on_demand() {
+        let memory = SimulatedMemory::new(4);
+        let (allocator, mapper) = heap(&memory);
+
+        unsafe {
+            assert_eq!(allocator.raw_alloc(layout(100)) as usize, memory.start());
+            assert_eq!(allocator.raw_alloc(layout(100)) as usize, memory.start() + 100);
+            assert_eq!(mapper.mapped_pages(), vec![memory.start_frame()]);
+
+            assert_eq!(allocator.raw_alloc(layout(5000)) as usize, memory.start() + 200);
+            assert_eq!(mapper.mapped_pages(), vec![memory.start_frame(), memory.start_frame() + 1]);
+        }
+    }
+
+    #[test]
+    fn small_allocations_reserve_room_for_a_node() {
+        let memory = SimulatedMemory::new(1);
+        let (allocator, _) = heap(&memory);
+
+        unsafe {
+            assert_eq!(allocator.raw_alloc(layout(1)) as usize, memory.start());
+            assert_eq!(allocator.raw_alloc(layout(1)) as usize, memory.start() + LIST_HEAP_NODE_SIZE);
+        }
+    }
+
+    #[test]
+    fn freed_hole_is_reused() {
+        let memory = SimulatedMemory::new(1);
+        let (allocator, _) = heap(&memory);
+
+        unsafe {
+            let a = allocator.raw_alloc(layout(64));
+            allocator.raw_alloc(layout(64));
+            allocator.raw_dealloc(a, layout(64));
+            assert_eq!(holes(&allocator), vec![(a as usize, 64)]);
+
+            assert_eq!(allocator.raw_alloc(layout(32)), a);
+            assert_eq!(holes(&allocator), vec![(a as usize + 32, 32)]);
+        }
+    }
+
+    #[test]
+    fn hole_merges_with_previous_hole() {
+        let memory = SimulatedMemory::new(1);
+        let (allocator, _) = heap(&memory);
+
+        unsafe {
+            let a = allocator.raw_alloc(layout(64));
+            let b = allocator.raw_alloc(layout(64));
+            allocator.raw_alloc(layout(64));
+            allocator.raw_dealloc(a, layout(64));
+            allocator.raw_dealloc(b, layout(64));
+
+            assert_eq!(holes(&allocator), vec![(a as usize, 128)]);
+            assert_eq!(allocator.raw_alloc(layout(100)), a);
+        }
+    }
+
+    #[test]
+    fn hole_merges_with_next_hole() {
+        let memory = SimulatedMemory::new(1);
+        let (allocator, _) = heap(&memory);
+
+        unsafe {
+            let a = allocator.raw_alloc(layout(64));
+            let b = allocator.raw_alloc(layout(64));
+            allocator.raw_alloc(layout(64));
+            allocator.raw_dealloc(b, layout(64));
+            allocator.raw_dealloc(a, layout(64));
+
+            assert_eq!(holes(&allocator), vec![(a as usize, 128)]);
+        }
+    }
+
+    #[test]
+    fn hole_merges_with_both_neighbours() {
+        let memory = SimulatedMemory::new(1);
+        let (allocator, _) = heap(&memory);
+
+        unsafe {
+            let a = allocator.raw_alloc(layout(64));
+            let b = allocator.raw_alloc(layout(64));
+            let c = allocator.raw_alloc(layout(64));
+            let d = allocator.raw_alloc(layout(64));
+            allocator.raw_alloc(layout(64));
+            allocator.raw_dealloc(a, layout(64));
+            allocator.raw_dealloc(c, layout(64));
+            allocator.raw_dealloc(b, layout(64));
+            assert_eq!(holes(&allocator), vec![(a as usize, 192)]);
+
+            // The merged hole was the last one, freeing d must still extend it
+            allocator.raw_dealloc(d, layout(64));
+            assert_eq!(holes(&allocator), vec![(a as usize, 256)]);
+        }
+    }
+
+    #[test]
+    fn out_of_order_frees_keep_holes_sorted() {
+        let memory = SimulatedMemory::new(1);
+        let (allocator, _) = heap(&memory);
+
+        unsafe {
+            let blocks: Vec<*mut u8> = (0..6).map(|_| allocator.raw_alloc(layout(64))).collect();
+            allocator.raw_dealloc(blocks[4], layout(64));
+            allocator.raw_dealloc(blocks[0], layout(64));
+            allocator.raw_dealloc(blocks[2], layout(64));
+
+            assert_eq!(holes(&allocator), vec![
+                (blocks[0] as usize, 64),
+                (blocks[2] as usize, 64),
+                (blocks[4] as usize, 64)
+            ]);
+        }
+    }
+}
+
+// Run on the kernel heap by the in-kernel test runner
+#[cfg(all(test, target_os = "none"))]
+mod kernel_tests {
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+
+    #[test_case]
+    fn box_allocations() {
+        for i in 0..1000 {
+            let b = Box::new(i);
+            assert_eq!(*b, i);
+        }
+    }
+
+    #[test_case]
+    fn interleaved_allocations() {
+        for i in 0..1000 {
+            let b = Box::new(i);
+            let b2 = Box::new(i * 2);
+            let b3 = Box::new(i * 3);
+            assert_eq!(*b, i);
+            assert_eq!(*b2, i * 2);
+            assert_eq!(*b3, i * 3);
+        }
+    }
+
+    #[test_case]
+    fn growing_vec() {
+        let mut v = Vec::new();
+        for i in 0..10000usize {
+            v.push(i);
+        }
+        assert_eq!(v.iter().sum::<usize>(), 10000 * 9999 / 2);
+    }
+}
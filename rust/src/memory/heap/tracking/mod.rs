@@ -0,0 +1,251 @@
+// Allocation tracking, enabled with the heap_tracking feature.
+//
+// Live allocations are recorded in a fixed size side table living in .bss, since the tracker
+// can't use the heap it is tracking. Allocations made while the table is full are only counted.
+
+use crate::utils::backtrace::{read_frame_pointer, walk_stack};
+use core::alloc::Layout;
+use spin::Mutex;
+
+pub const MAX_TRACKED_ALLOCATIONS: usize = 4096;
+pub const TRACKED_CALLERS: usize = 4;
+// Bucket i counts allocations of size 2^i to 2^(i + 1) - 1, the last one everything bigger
+pub const HISTOGRAM_BUCKETS: usize = 24;
+
+// Frames of the tracker and of the allocator itself, skipped when recording callers
+const SKIPPED_FRAMES: usize = 2;
+
+#[derive(Debug, Clone, Copy)]
+pub struct AllocationRecord {
+    pub address: usize,
+    pub size: usize,
+    pub align: usize,
+    // Return addresses of the first frames above the allocator, innermost first
+    pub callers: [usize; TRACKED_CALLERS]
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub live_bytes: usize,
+    pub live_allocations: usize,
+    pub peak_bytes: usize,
+    pub total_allocations: usize,
+    pub total_deallocations: usize,
+    // Live allocations that didn't fit in the side table
+    pub untracked_allocations: usize
+}
+
+pub struct AllocationTracker {
+    records: [Option<AllocationRecord>; MAX_TRACKED_ALLOCATIONS],
+    histogram: [usize; HISTOGRAM_BUCKETS],
+    stats: HeapStats
+}
+
+impl AllocationTracker {
+    pub const fn new() -> AllocationTracker {
+        AllocationTracker {
+            records: [None; MAX_TRACKED_ALLOCATIONS],
+            histogram: [0; HISTOGRAM_BUCKETS],
+            stats: HeapStats {
+                live_bytes: 0,
+                live_allocations: 0,
+                peak_bytes: 0,
+                total_allocations: 0,
+                total_deallocations: 0,
+                untracked_allocations: 0
+            }
+        }
+    }
+
+    pub fn record_alloc(&mut self, address: usize, layout: Layout, callers: [usize; TRACKED_CALLERS]) {
+        self.stats.live_bytes += layout.size();
+        self.stats.live_allocations += 1;
+        self.stats.total_allocations += 1;
+        self.stats.peak_bytes = self.stats.peak_bytes.max(self.stats.live_bytes);
+        self.histogram[histogram_bucket(layout.size())] += 1;
+
+        let record = AllocationRecord {
+            address,
+            size: layout.size(),
+            align: layout.align(),
+            callers
+        };
+        match self.records.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => *slot = Some(record),
+            None => self.stats.untracked_allocations += 1
+        }
+    }
+
+    pub fn record_dealloc(&mut self, address: usize, layout: Layout) {
+        self.stats.live_bytes -= layout.size();
+        self.stats.live_allocations -= 1;
+        self.stats.total_deallocations += 1;
+
+        let slot = self.records.iter_mut().find(|slot| match slot {
+            Some(record) => record.address == address,
+            None => false
+        });
+        match slot {
+            Some(slot) => *slot = None,
+            // Allocated while the table was full
+            None => self.stats.untracked_allocations -= 1
+        }
+    }
+
+    // First record in a slot at or after slot, with the slot it is in
+    fn next_record(&self, slot: usize) -> Option<(usize, AllocationRecord)> {
+        self.records.iter().enumerate().skip(slot)
+            .find_map(|(slot, record)| record.map(|record| (slot, record)))
+    }
+}
+
+impl Default for AllocationTracker {
+    fn default() -> AllocationTracker {
+        AllocationTracker::new()
+    }
+}
+
+static TRACKER: Mutex<AllocationTracker> = Mutex::new(AllocationTracker::new());
+
+fn histogram_bucket(size: usize) -> usize {
+    if size == 0 {
+        return 0;
+    }
+    let bucket = core::mem::size_of::<usize>() * 8 - 1 - size.leading_zeros() as usize;
+    bucket.min(HISTOGRAM_BUCKETS - 1)
+}
+
+pub unsafe fn record_alloc(ptr: *mut u8, layout: Layout) {
+    let mut callers = [0; TRACKED_CALLERS];
+    walk_stack(read_frame_pointer(), SKIPPED_FRAMES, &mut callers);
+    TRACKER.lock().record_alloc(ptr as usize, layout, callers);
+}
+
+pub unsafe fn record_dealloc(ptr: *mut u8, layout: Layout) {
+    TRACKER.lock().record_dealloc(ptr as usize, layout);
+}
+
+pub fn stats() -> HeapStats {
+    TRACKER.lock().stats
+}
+
+// The printing functions below copy what they need out of the tracker before printing, the
+// output path may allocate and so record into the tracker
+
+pub fn print_stats() {
+    let stats = stats();
+    println!("Heap : {} bytes live in {} allocations ({} untracked), peak {} bytes, {} allocations / {} deallocations",
+             stats.live_bytes,
+             stats.live_allocations,
+             stats.untracked_allocations,
+             stats.peak_bytes,
+             stats.total_allocations,
+             stats.total_deallocations
+    );
+}
+
+pub fn print_histogram() {
+    let histogram = TRACKER.lock().histogram;
+    println!("Allocation sizes :");
+    for (bucket, &count) in histogram.iter().enumerate() {
+        if count == 0 {
+            continue;
+        }
+        if bucket == HISTOGRAM_BUCKETS - 1 {
+            println!("  >= {} : {}", 1usize << bucket, count);
+        }
+        else {
+            println!("  {} - {} : {}", 1usize << bucket, (1usize << (bucket + 1)) - 1, count);
+        }
+    }
+}
+
+// Prints at most limit of the outstanding allocations
+pub fn print_outstanding(limit: usize) {
+    println!("Outstanding allocations :");
+    let mut slot = 0;
+    for _ in 0..limit {
+        // The table is too big to be copied, records are taken one at a time instead
+        let (record_slot, record) = match TRACKER.lock().next_record(slot) {
+            Some(found) => found,
+            None => break
+        };
+        slot = record_slot + 1;
+        print!("  0x{:x} size {} align {} from", record.address, record.size, record.align);
+        for &caller in record.callers.iter().take_while(|&&caller| caller != 0) {
+            print!(" 0x{:x}", caller);
+        }
+        println!("");
+    }
+}
+
+pub fn report() {
+    print_stats();
+    print_histogram();
+    print_outstanding(32);
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use std::boxed::Box;
+
+    fn layout(size: usize) -> Layout {
+        Layout::from_size_align(size, 8).unwrap()
+    }
+
+    #[test]
+    fn histogram_buckets() {
+        assert_eq!(histogram_bucket(0), 0);
+        assert_eq!(histogram_bucket(1), 0);
+        assert_eq!(histogram_bucket(2), 1);
+        assert_eq!(histogram_bucket(3), 1);
+        assert_eq!(histogram_bucket(4096), 12);
+        assert_eq!(histogram_bucket((1 << (HISTOGRAM_BUCKETS - 1)) - 1), HISTOGRAM_BUCKETS - 2);
+        assert_eq!(histogram_bucket(1 << (HISTOGRAM_BUCKETS - 1)), HISTOGRAM_BUCKETS - 1);
+        assert_eq!(histogram_bucket(usize::MAX), HISTOGRAM_BUCKETS - 1);
+    }
+
+    #[test]
+    fn live_and_peak_usage() {
+        let mut tracker = Box::new(AllocationTracker::new());
+        tracker.record_alloc(0x1000, layout(100), [0; TRACKED_CALLERS]);
+        tracker.record_alloc(0x2000, layout(50), [0; TRACKED_CALLERS]);
+        tracker.record_dealloc(0x1000, layout(100));
+        tracker.record_alloc(0x3000, layout(20), [0; TRACKED_CALLERS]);
+
+        let stats = tracker.stats;
+        assert_eq!((stats.live_bytes, stats.live_allocations), (70, 2));
+        assert_eq!(stats.peak_bytes, 150);
+        assert_eq!((stats.total_allocations, stats.total_deallocations), (3, 1));
+        assert_eq!(tracker.histogram[6], 1);
+        assert_eq!(tracker.histogram[5], 1);
+        assert_eq!(tracker.histogram[4], 1);
+
+        // The freed slot is reused
+        assert_eq!(tracker.next_record(0).map(|(slot, record)| (slot, record.address)), Some((0, 0x3000)));
+        assert_eq!(tracker.next_record(1).map(|(slot, record)| (slot, record.address)), Some((1, 0x2000)));
+        assert!(tracker.next_record(2).is_none());
+    }
+
+    #[test]
+    fn allocations_past_the_table_are_counted() {
+        let mut tracker = Box::new(AllocationTracker::new());
+        for index in 0..MAX_TRACKED_ALLOCATIONS + 2 {
+            tracker.record_alloc(0x1000 + index * 16, layout(16), [0; TRACKED_CALLERS]);
+        }
+        assert_eq!(tracker.stats.untracked_allocations, 2);
+        assert_eq!(tracker.stats.live_allocations, MAX_TRACKED_ALLOCATIONS + 2);
+
+        // Untracked allocations are found missing from the table when freed
+        tracker.record_dealloc(0x1000 + (MAX_TRACKED_ALLOCATIONS + 1) * 16, layout(16));
+        assert_eq!(tracker.stats.untracked_allocations, 1);
+        tracker.record_dealloc(0x1000, layout(16));
+        assert_eq!(tracker.stats.untracked_allocations, 1);
+        assert_eq!(tracker.stats.live_allocations, MAX_TRACKED_ALLOCATIONS);
+
+        // A slot is free again
+        tracker.record_alloc(0x100000, layout(16), [0; TRACKED_CALLERS]);
+        assert_eq!(tracker.stats.untracked_allocations, 1);
+    }
+}
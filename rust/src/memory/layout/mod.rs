@@ -0,0 +1,296 @@
+use crate::memory::frame_allocator::{FrameInfo, FRAME_SIZE};
+use crate::memory::paging::{
+    EntryTable, EntryFlags, PageInfo, RECURSIVE_INDEX, paging_levels, root_entry_span, sign_extend_for
+};
+use crate::utils::ceil_div_usize;
+use crate::utils::random::random_below;
+use crate::utils::reg_read::read_cr3;
+use crate::MAX_HEAP;
+use spin::{Mutex, Once};
+
+// Every randomised region gets its own root table entries (P4, or P5 with LA57) so that regions
+// never share page tables. The lower half is never randomised over, nor are the last two root
+// entries : the recursive mapping (510) and the kernel image (511, which 0xffffffff80200000 falls
+// in with either paging mode).
+pub const FIRST_RANDOM_ROOT_INDEX: usize = 256;
+pub const END_RANDOM_ROOT_INDEX: usize = RECURSIVE_INDEX;
+// Regions are placed at a random 2MiB aligned offset inside of their root entries
+pub const REGION_ALIGN: usize = 0x200000;
+
+pub const KERNEL_STACKS_SIZE: usize = 64 * 1024 * 1024 * 1024; // 64GiB
+pub const MMIO_WINDOW_SIZE: usize = 64 * 1024 * 1024 * 1024; // 64GiB
+
+extern "C" {
+    static __kernel_start: u8;
+    static __kernel_end: u8;
+}
+
+// Where link.ld places __kernel_start, before the bootloader slides the kernel
+pub const KERNEL_LINK_START: usize = 0xffffffff80200000;
+
+#[derive(Debug, Clone, Copy)]
+pub struct KernelLayout {
+    // Virtual address the bootloader loaded the kernel at, and the difference between the
+    // kernel's virtual and physical addresses
+    pub kernel_start: usize,
+    pub kernel_end: usize,
+    pub kernel_offset: usize,
+
+    pub heap_base: usize,
+    pub heap_size: usize,
+    pub kernel_stacks_base: usize,
+    pub kernel_stacks_size: usize,
+    pub mmio_base: usize,
+    pub mmio_size: usize,
+    pub direct_map_base: usize,
+    pub direct_map_size: usize
+}
+
+static LAYOUT: Once<KernelLayout> = Once::new();
+
+// Next free virtual address of the bump allocated windows
+static NEXT_KERNEL_STACK: Mutex<usize> = Mutex::new(0);
+static NEXT_MMIO: Mutex<usize> = Mutex::new(0);
+
+struct SlotPicker {
+    levels: usize,
+    used: [bool; END_RANDOM_ROOT_INDEX - FIRST_RANDOM_ROOT_INDEX]
+}
+
+impl SlotPicker {
+    fn new(levels: usize) -> SlotPicker {
+        SlotPicker {
+            levels,
+            used: [false; END_RANDOM_ROOT_INDEX - FIRST_RANDOM_ROOT_INDEX]
+        }
+    }
+
+    fn is_free(&self, first: usize, amount: usize) -> bool {
+        (first..first + amount).all(|i| !self.used[i])
+    }
+
+    // Returns the virtual address of a randomly placed region of the given size
+    fn pick(&mut self, size: usize) -> usize {
+        let span = root_entry_span(self.levels);
+        let slot_amount = ceil_div_usize(size, span);
+        let candidates = self.used.len() - slot_amount + 1;
+
+        // Start at a random slot and take the first free run after it
+        let start = random_below(candidates as u64) as usize;
+        let first = (0..candidates)
+            .map(|i| (start + i) % candidates)
+            .find(|&first| self.is_free(first, slot_amount))
+            .expect("Not enough virtual address space to lay out the kernel.");
+
+        for i in first..first + slot_amount {
+            self.used[i] = true;
+        }
+
+        let slack = (slot_amount * span - size) / REGION_ALIGN;
+        let offset = random_below(slack as u64 + 1) as usize * REGION_ALIGN;
+
+        // Root entries from 256 on are in the higher half, sign extend from the highest bit
+        sign_extend_for((FIRST_RANDOM_ROOT_INDEX + first) * span + offset, self.levels)
+    }
+}
+
+impl KernelLayout {
+    // Picks the randomised layout. Must be called while the bootloader's page tables are still
+    // loaded, since they are used to find out where the kernel was loaded in physical memory.
+    pub unsafe fn randomise(memory_end: usize) -> KernelLayout {
+        let (kernel_start, kernel_end) = kernel_image();
+
+        let bootloader_p4 = EntryTable::from_frame_unzeroed(
+            FrameInfo::from_address(read_cr3() & 0x000fffff_fffff000)
+        );
+        let kernel_physical_start = bootloader_p4.translate_identity(kernel_start)
+            .expect("Could not find the kernel's physical address.");
+
+        let layout = KernelLayout::place(
+            kernel_start,
+            kernel_end,
+            kernel_start - kernel_physical_start,
+            memory_end,
+            paging_levels()
+        );
+
+        *NEXT_KERNEL_STACK.lock() = layout.kernel_stacks_base;
+        *NEXT_MMIO.lock() = layout.mmio_base;
+        LAYOUT.call_once(|| layout);
+
+        layout
+    }
+
+    // Randomly places the regions around a kernel image for the given number of paging levels
+    pub fn place(
+        kernel_start: usize,
+        kernel_end: usize,
+        kernel_offset: usize,
+        memory_end: usize,
+        levels: usize
+    ) -> KernelLayout {
+        let mut picker = SlotPicker::new(levels);
+
+        let direct_map_size = ceil_div_usize(memory_end, FRAME_SIZE) * FRAME_SIZE;
+
+        KernelLayout {
+            kernel_start,
+            kernel_end,
+            kernel_offset,
+            heap_base: picker.pick(MAX_HEAP),
+            heap_size: MAX_HEAP,
+            kernel_stacks_base: picker.pick(KERNEL_STACKS_SIZE),
+            kernel_stacks_size: KERNEL_STACKS_SIZE,
+            mmio_base: picker.pick(MMIO_WINDOW_SIZE),
+            mmio_size: MMIO_WINDOW_SIZE,
+            direct_map_base: picker.pick(direct_map_size),
+            direct_map_size
+        }
+    }
+
+    pub fn physical_to_direct_map(&self, physical_address: usize) -> usize {
+        assert!(physical_address < self.direct_map_size, "Physical address 0x{:x} is not direct mapped.", physical_address);
+        self.direct_map_base + physical_address
+    }
+}
+
+// Virtual start and end of the loaded kernel image
+pub fn kernel_image() -> (usize, usize) {
+    unsafe { (&__kernel_start as *const u8 as usize, &__kernel_end as *const u8 as usize) }
+}
+
+// Address an address of the loaded kernel has in the ELF file, which is what addr2line and
+// objdump expect. None for addresses outside of the kernel image.
+pub fn to_link_address(address: usize) -> Option<usize> {
+    let (start, end) = kernel_image();
+    if address >= start && address < end {
+        Some(address - start + KERNEL_LINK_START)
+    } else {
+        None
+    }
+}
+
+pub fn layout() -> &'static KernelLayout {
+    LAYOUT.r#try().expect("Tried using the kernel layout before randomising it.")
+}
+
+// Maps a physical MMIO range into the MMIO window and returns the virtual address matching
+// physical_address. Only usable once the kernel heap allocator is set up.
+pub unsafe fn map_mmio(physical_address: usize, size: usize) -> usize {
+    map_physical(
+        physical_address,
+        size,
+        EntryFlags::PRESENT | EntryFlags::WRITABLE | EntryFlags::NO_CACHE | EntryFlags::WRITE_THROUGH
+    )
+}
+
+// Same as map_mmio with the given flags, e.g. to map firmware tables read only and cached
+pub unsafe fn map_physical(physical_address: usize, size: usize, flags: EntryFlags) -> usize {
+    let first_frame = physical_address / FRAME_SIZE;
+    let frame_amount = ceil_div_usize(physical_address + size, FRAME_SIZE) - first_frame;
+    let layout = layout();
+
+    let virtual_start = {
+        let mut next = NEXT_MMIO.lock();
+        let virtual_start = *next;
+        *next += frame_amount * FRAME_SIZE;
+        if *next > layout.mmio_base + layout.mmio_size {
+            panic!("MMIO window is full.");
+        }
+        virtual_start
+    };
+
+    let mapper = crate::memory::kernel_mapper();
+    for i in 0..frame_amount {
+        mapper.map_frame(
+            FrameInfo::from_number(first_frame + i),
+            PageInfo::from_address(virtual_start + i * FRAME_SIZE),
+            flags
+        );
+    }
+
+    virtual_start + physical_address % FRAME_SIZE
+}
+
+// Allocates and maps a kernel stack of the given amount of pages, preceded by an unmapped guard
+// page. Returns the top of the stack. Only usable once the kernel heap allocator is set up.
+pub unsafe fn allocate_kernel_stack(pages: usize) -> usize {
+    let layout = layout();
+
+    let stack_bottom = {
+        let mut next = NEXT_KERNEL_STACK.lock();
+        let stack_bottom = *next + FRAME_SIZE;
+        *next = stack_bottom + pages * FRAME_SIZE;
+        if *next > layout.kernel_stacks_base + layout.kernel_stacks_size {
+            panic!("Kernel stack window is full.");
+        }
+        stack_bottom
+    };
+
+    let mapper = crate::memory::kernel_mapper();
+    for i in 0..pages {
+        mapper.map_new_frame(
+            PageInfo::from_address(stack_bottom + i * FRAME_SIZE),
+            EntryFlags::PRESENT | EntryFlags::WRITABLE
+        );
+    }
+
+    stack_bottom + pages * FRAME_SIZE
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+
+    fn root_index(address: usize, levels: usize) -> usize {
+        (address >> (12 + 9 * (levels - 1))) & 0o777
+    }
+
+    fn check_layout(levels: usize) {
+        let layout = KernelLayout::place(
+            KERNEL_LINK_START,
+            KERNEL_LINK_START + 0x200000,
+            KERNEL_LINK_START - 0x200000,
+            8 * 1024 * 1024 * 1024,
+            levels
+        );
+        assert_eq!(root_index(layout.kernel_start, levels), 511);
+
+        let regions = [
+            (layout.heap_base, layout.heap_size),
+            (layout.kernel_stacks_base, layout.kernel_stacks_size),
+            (layout.mmio_base, layout.mmio_size),
+            (layout.direct_map_base, layout.direct_map_size)
+        ];
+        for (i, &(base, size)) in regions.iter().enumerate() {
+            let last = base + size - 1;
+            assert_eq!(sign_extend_for(base, levels), base, "0x{:x} is not canonical", base);
+            assert_eq!(base % REGION_ALIGN, 0);
+            for &address in &[base, last] {
+                let index = root_index(address, levels);
+                assert!((FIRST_RANDOM_ROOT_INDEX..END_RANDOM_ROOT_INDEX).contains(&index),
+                        "0x{:x} is in root entry {}", address, index);
+            }
+            for &(other_base, other_size) in &regions[i + 1..] {
+                assert!(last < other_base || other_base + other_size <= base);
+            }
+        }
+    }
+
+    #[test]
+    fn four_level_layout() {
+        for _ in 0..100 {
+            check_layout(4);
+        }
+    }
+
+    #[test]
+    fn five_level_layout() {
+        for _ in 0..100 {
+            check_layout(5);
+        }
+        let layout = KernelLayout::place(KERNEL_LINK_START, KERNEL_LINK_START, 0, 0x1000, 5);
+        // Above the 48 bit higher half, which only starts at bit 56
+        assert!(layout.heap_base >= 0xff00_0000_0000_0000 && layout.heap_base < 0xffff_0000_0000_0000);
+    }
+}
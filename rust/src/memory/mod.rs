@@ -1,3 +1,28 @@
 pub mod frame_allocator;
 pub mod paging;
-pub mod heap;
\ No newline at end of file
+pub mod heap;
+pub mod address_space;
+pub mod layout;
+pub mod pat;
+pub mod user_access;
+
+#[cfg(all(test, not(target_os = "none")))]
+pub mod test_utils;
+
+use crate::memory::heap::LinkedListHeapAllocator;
+use crate::memory::frame_allocator::BitMapFrameAllocator;
+use crate::memory::paging::EntryTable;
+
+pub type KernelHeapAllocator = LinkedListHeapAllocator<BitMapFrameAllocator, &'static mut EntryTable>;
+
+// Gives access to the frame allocator and page table, which the heap allocator owns once it is
+// created
+pub unsafe fn kernel_mapper() -> &'static KernelHeapAllocator {
+    crate::ALLOCATOR.0.as_ref().expect("Tried mapping memory before initializing the heap allocator.")
+}
+
+pub unsafe fn force_unlock_heap() {
+    if let Some(allocator) = crate::ALLOCATOR.0.as_ref() {
+        allocator.force_unlock();
+    }
+}
@@ -1,9 +1,71 @@
 use crate::memory::frame_allocator::{FrameInfo, FRAME_SIZE, FrameAllocator};
 use crate::utils::reg_write::write_cr3;
-use crate::utils::reg_read::read_cr3;
+use crate::utils::reg_read::{read_cr3, read_cr4};
 use stivale::StivaleStructure;
 use stivale::memory::MemoryMapEntryType;
 use crate::utils::ceil_div_usize;
+use crate::memory::address_space::{
+    pcid_enabled, invpcid_available, current_pcid, mark_stale, mark_all_stale_except
+};
+use crate::memory::layout::KernelLayout;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+pub const CR4_LA57: usize = 1 << 12;
+pub const ENTRIES_PER_TABLE: usize = 512;
+// Root table entry leading back to the root table. The kernel image is in the last entry
+// (0xffffffff80200000), so the recursive mapping takes the one before it.
+pub const RECURSIVE_INDEX: usize = 510;
+
+// 4 (48 bit virtual addresses) or 5 with LA57 (57 bit virtual addresses)
+static PAGING_LEVELS: AtomicUsize = AtomicUsize::new(4);
+
+pub fn paging_levels() -> usize {
+    PAGING_LEVELS.load(Ordering::Relaxed)
+}
+
+pub fn virtual_address_bits() -> usize {
+    virtual_address_bits_for(paging_levels())
+}
+
+pub fn virtual_address_bits_for(levels: usize) -> usize {
+    12 + 9 * levels
+}
+
+// Size of the address range covered by one root table entry (512GiB, or 256TiB with LA57)
+pub fn root_entry_span(levels: usize) -> usize {
+    FRAME_SIZE << (9 * (levels - 1))
+}
+
+// Copies the highest implemented bit of a virtual address into the bits above it
+pub fn sign_extend(virtual_address: usize) -> usize {
+    sign_extend_for(virtual_address, paging_levels())
+}
+
+pub fn sign_extend_for(virtual_address: usize, levels: usize) -> usize {
+    let unused_bits = 64 - virtual_address_bits_for(levels);
+    (((virtual_address << unused_bits) as isize) >> unused_bits) as usize
+}
+
+// Virtual address of the active root table, reached by following RECURSIVE_INDEX at every level
+pub fn recursive_root_address() -> usize {
+    let address = (0..paging_levels())
+        .fold(0, |address, level| address | RECURSIVE_INDEX << (12 + 9 * level));
+    sign_extend(address)
+}
+
+pub unsafe fn recursive_root_table() -> &'static mut EntryTable {
+    EntryTable::from_frame_unzeroed(FrameInfo::from_address(recursive_root_address()))
+}
+
+// The paging mode can't be changed while in long mode, the bootloader enables LA57 when the
+// kernel asks for it (see stivale_entry.c) and the processor supports it.
+// Returns the number of paging levels.
+pub unsafe fn init_paging_levels() -> usize {
+    let levels = if read_cr4() & CR4_LA57 != 0 { 5 } else { 4 };
+    PAGING_LEVELS.store(levels, Ordering::Relaxed);
+    levels
+}
+
 bitflags! {
     pub struct EntryFlags: u64 {
         const PRESENT =         1 << 0;
@@ -31,22 +93,25 @@ pub struct PageInfo {
 }
 
 impl PageInfo {
-    fn p4_index(&self) -> usize {
-        (self.number >> 27) & 0o777
-    }
-    fn p3_index(&self) -> usize {
-        (self.number >> 18) & 0o777
+    // Index into the table of the given level, 1 being the P1 table and paging_levels() the root
+    // table (P4, or P5 with LA57)
+    pub fn table_index(&self, level: usize) -> usize {
+        (self.number >> (9 * (level - 1))) & 0o777
     }
-    fn p2_index(&self) -> usize {
-        (self.number >> 9) & 0o777
+
+    pub fn is_canonical(virtual_address: usize) -> bool {
+        // Bits above the highest implemented one have to be copies of it
+        let high_bits = (virtual_address as isize) >> (virtual_address_bits() - 1);
+        high_bits == 0 || high_bits == -1
     }
-    fn p1_index(&self) -> usize {
-        (self.number >> 0) & 0o777
+
+    // Lower half of the address space, where user mode lives
+    pub fn is_user_address(virtual_address: usize) -> bool {
+        virtual_address < 1 << (virtual_address_bits() - 1)
     }
 
     pub fn from_address(virtual_address: usize) -> PageInfo {
-        assert!(virtual_address < 0x0000_8000_0000_0000 ||
-                    virtual_address >= 0xffff_8000_0000_0000,
+        assert!(PageInfo::is_canonical(virtual_address),
                 "invalid address: 0x{:x}", virtual_address);
         let number = virtual_address / FRAME_SIZE;
         let address = number * FRAME_SIZE;
@@ -93,6 +158,39 @@ impl Entry {
     }
 }
 
+// Maps pages on behalf of code that shouldn't depend on how page tables are accessed, such as the
+// heap allocator
+pub trait PageMapper {
+    unsafe fn map<T: FrameAllocator>(
+        &mut self,
+        frame: FrameInfo,
+        page: PageInfo,
+        flags: EntryFlags,
+        allocator: &mut T
+    );
+}
+
+// The active root table, accessed through the recursive mapping
+impl<'a> PageMapper for &'a mut EntryTable {
+    unsafe fn map<T: FrameAllocator>(
+        &mut self,
+        frame: FrameInfo,
+        page: PageInfo,
+        flags: EntryFlags,
+        allocator: &mut T
+    ) {
+        self.p4_map(
+            frame,
+            page,
+            flags,
+            false,
+            true,
+            TableAccess::Recursive,
+            allocator
+        );
+    }
+}
+
 #[repr(align(4096))]
 pub struct EntryTable {
     pub entries: [Entry; 512]
@@ -113,12 +211,60 @@ impl EntryTable {
         let entry_flags = self.entries[index].get_flags();
         if entry_flags.contains(EntryFlags::PRESENT) && !entry_flags.contains(EntryFlags::HUGE_PAGE) {
             let table_address = self as *const _ as usize;
-            Some((table_address << 9) | (index << 12))
+            Some(sign_extend((table_address << 9) | (index << 12)))
         } else {
             None
         }
     }
 
+    // Walks the tables starting from this root table, assuming they are all identity mapped, and
+    // returns the physical address virtual_address is mapped to
+    pub unsafe fn translate_identity(&self, virtual_address: usize) -> Option<usize> {
+        let page = PageInfo::from_address(virtual_address);
+
+        let mut table: &EntryTable = self;
+        for level in (1..=paging_levels()).rev() {
+            let entry = table.entries[page.table_index(level)];
+            let entry_frame = entry.pointed_frame()?;
+            if level == 1 || entry.get_flags().contains(EntryFlags::HUGE_PAGE) {
+                // 4KiB page, or 2MiB / 1GiB huge page when found in a P2 / P3 table
+                let page_size = FRAME_SIZE << (9 * (level - 1));
+                return Some(entry_frame.address + (virtual_address & (page_size - 1)));
+            }
+            table = EntryTable::from_frame_unzeroed(entry_frame);
+        }
+
+        None
+    }
+
+    // Walks the tables starting from this root table through the recursive mapping and returns the
+    // flags of the entry mapping virtual_address. USER_ACCESSIBLE and WRITABLE are only kept when
+    // every level allows them.
+    pub unsafe fn p4_lookup_recursive(&self, virtual_address: usize) -> Option<EntryFlags> {
+        let page = PageInfo::from_address(virtual_address);
+        let inherited = EntryFlags::USER_ACCESSIBLE | EntryFlags::WRITABLE;
+        let mut allowed = EntryFlags::all();
+
+        let mut table: &EntryTable = self;
+        for level in (1..=paging_levels()).rev() {
+            let index = page.table_index(level);
+            let flags = table.entries[index].get_flags();
+            if !flags.contains(EntryFlags::PRESENT) {
+                return None;
+            }
+            allowed &= flags | !inherited;
+            if level == 1 || flags.contains(EntryFlags::HUGE_PAGE) {
+                return Some(flags & allowed);
+            }
+            table = EntryTable::from_frame_unzeroed(FrameInfo::from_address(
+                table.next_entry_address_recursive(index)?
+            ));
+        }
+
+        None
+    }
+
+    // Maps page to frame, self being the root table (P4, or P5 with LA57)
     pub unsafe fn p4_map<T: FrameAllocator>(
         &mut self,
         frame: FrameInfo,
@@ -129,103 +275,42 @@ impl EntryTable {
         current_table_access: TableAccess,
         allocator: &mut T
     ) {
-        match current_table_access {
-            // In this case tables are identity mapped so their physical address, the one found with
-            // .pointed_frame() is their virtual address as well.
-            TableAccess::Identity => {
-                // P3 table
-                let table = EntryTable::from_frame_unzeroed(
-                    self.create_or_get_table_entry(
-                        page.p4_index(),
-                        TableAccess::Identity,
-                        allocator
-                    ).pointed_frame().unwrap()
-                );
-                // P2 table
-                let table = EntryTable::from_frame_unzeroed(
-                    table.create_or_get_table_entry(
-                        page.p3_index(),
-                        TableAccess::Identity,
-                        allocator
-                    ).pointed_frame().unwrap()
-                );
-                // P1 table
-                let table = EntryTable::from_frame_unzeroed(
-                    table.create_or_get_table_entry(
-                        page.p2_index(),
-                        TableAccess::Identity,
-                        allocator
-                    ).pointed_frame().unwrap()
-                );
-                // Setting the entry
-                let entry: &mut Entry = &mut table.entries[page.p1_index()];
-                if !entry.is_unused() {
-                    if !allow_overwrite {
-                        panic!("Tried to perform unauthorized entry overwrite.");
-                    }
-                    // We changed something
-                    if invalidate_addres {
-                        invalidate(page.address);
-                    }
-                }
-                entry.write(frame, flags | EntryFlags::PRESENT);
-            }
-
-            // In this case tables are mapped recursively, the last entry of the P4 table leads to
-            // itself
-            TableAccess::Recursive => {
-                // P3 table
-                let table = {
-                    self.create_or_get_table_entry(
-                        page.p4_index(),
-                        TableAccess::Recursive,
-                        allocator
-                    );
-
-                    EntryTable::from_frame_unzeroed(FrameInfo::from_address(self.next_entry_address_recursive(
-                        page.p4_index()
-                    ).expect("An error occurred while creating the page table")))
-                };
-
-                // P2 table
-                let table = {
-                    table.create_or_get_table_entry(
-                        page.p3_index(),
-                        TableAccess::Recursive,
-                        allocator
-                    );
-
-                    EntryTable::from_frame_unzeroed(FrameInfo::from_address(table.next_entry_address_recursive(
-                        page.p3_index()
-                    ).expect("An error occurred while creating the page table")))
-                };
-
-                // P1 table
-                let table = {
-                    table.create_or_get_table_entry(
-                        page.p2_index(),
-                        TableAccess::Recursive,
-                        allocator
-                    );
+        // Walk down to the P1 table, creating the missing tables on the way
+        let mut table: &mut EntryTable = self;
+        for level in (2..=paging_levels()).rev() {
+            let index = page.table_index(level);
+            let next_frame = table.create_or_get_table_entry(
+                index,
+                current_table_access,
+                allocator
+            ).pointed_frame().unwrap();
+
+            table = match current_table_access {
+                // In this case tables are identity mapped so their physical address, the one found
+                // with .pointed_frame() is their virtual address as well.
+                TableAccess::Identity => EntryTable::from_frame_unzeroed(next_frame),
+
+                // In this case tables are mapped recursively, RECURSIVE_INDEX in the root table
+                // leads to itself
+                TableAccess::Recursive => EntryTable::from_frame_unzeroed(FrameInfo::from_address(
+                    table.next_entry_address_recursive(index)
+                        .expect("An error occurred while creating the page table")
+                ))
+            };
+        }
 
-                    EntryTable::from_frame_unzeroed(FrameInfo::from_address(table.next_entry_address_recursive(
-                        page.p2_index()
-                    ).expect("An error occurred while creating the page table")))
-                };
-                // Setting the entry
-                let entry: &mut Entry = &mut table.entries[page.p1_index()];
-                if !entry.is_unused() {
-                    if !allow_overwrite {
-                        panic!("Tried to perform unauthorized entry overwrite.");
-                    }
-                    // We changed something
-                    if invalidate_addres {
-                        invalidate(page.address);
-                    }
-                }
-                entry.write(frame, flags | EntryFlags::PRESENT);
+        // Setting the entry
+        let entry: &mut Entry = &mut table.entries[page.table_index(1)];
+        if !entry.is_unused() {
+            if !allow_overwrite {
+                panic!("Tried to perform unauthorized entry overwrite.");
+            }
+            // We changed something
+            if invalidate_addres {
+                invalidate(page.address);
             }
         }
+        entry.write(frame, flags | EntryFlags::PRESENT);
     }
 
     // TODO : Optimize
@@ -264,18 +349,25 @@ impl EntryTable {
     pub unsafe fn p4_kernel_remap<T: FrameAllocator>(
         &mut self,
         stivale_structure: &StivaleStructure,
+        layout: &KernelLayout,
         allocator: &mut T
     ) {
         let memory_map = stivale_structure.memory_map().expect(
             "No memory map provided."
         );
 
-        // Making sure the frame allocator identity maps all of its data
-        allocator.identity_map(
-            self,
-            false,
-            TableAccess::Identity
-        );
+        // Making sure all of the frame allocator's data stays identity mapped
+        for frame in allocator.metadata_frames() {
+            self.p4_map(
+                FrameInfo::from_number(frame),
+                PageInfo::from_number(frame),
+                EntryFlags::PRESENT | EntryFlags::WRITABLE,
+                false,
+                false,
+                TableAccess::Identity,
+                allocator
+            );
+        }
 
         // Map the VGA framebuffer
         self.p4_map(
@@ -297,7 +389,7 @@ impl EntryTable {
                 MemoryMapEntryType::AcpiNvs => (true, 0usize),
                 MemoryMapEntryType::BadMemory => (false, 0usize),
                 MemoryMapEntryType::BootloaderReclaimable => (true, 0usize),
-                MemoryMapEntryType::Kernel => (true, 0xffffffff80000000usize)
+                MemoryMapEntryType::Kernel => (true, layout.kernel_offset)
             };
 
             if do_map {
@@ -322,6 +414,31 @@ impl EntryTable {
                 }
             }
         }
+
+        // Map all of physical memory at the randomised direct map base. Memory map entries are
+        // not always frame aligned so neighbouring entries may share a frame, hence the overwrite.
+        let direct_map_frame_offset = layout.direct_map_base / FRAME_SIZE;
+        for i in memory_map.iter() {
+            if let MemoryMapEntryType::BadMemory = i.entry_type() {
+                continue;
+            }
+
+            let frame_start = i.start_address() as usize / FRAME_SIZE;
+            let frame_end = ceil_div_usize(i.end_address() as usize, FRAME_SIZE)
+                .min(layout.direct_map_size / FRAME_SIZE);
+
+            for frame in frame_start..frame_end {
+                self.p4_map(
+                    FrameInfo::from_number(frame),
+                    PageInfo::from_number(direct_map_frame_offset + frame),
+                    EntryFlags::PRESENT | EntryFlags::WRITABLE,
+                    true,
+                    false,
+                    TableAccess::Identity,
+                    allocator
+                );
+            }
+        }
     }
 }
 
@@ -329,8 +446,61 @@ pub fn invalidate(virtual_address: usize) {
     unsafe { llvm_asm!("invlpg ($0)" :: "r" (virtual_address) : "memory") };
 }
 
+// Flushes all non-global translations of every PCID. Without INVPCID, reloading CR3 only flushes
+// the current PCID, so the other ones are marked stale and flushed when they are switched to.
 pub fn invalidate_all() {
+    if pcid_enabled() && invpcid_available() {
+        unsafe { invpcid(InvpcidType::AllContexts, 0, 0) };
+        return;
+    }
+
     unsafe {
+        // Bit 63 is never set when reading CR3, so this reload always flushes
         write_cr3(read_cr3());
     }
+    if pcid_enabled() {
+        mark_all_stale_except(current_pcid());
+    }
+}
+
+// Flushes a single page for the given PCID. Without INVPCID, invlpg only reaches the current
+// PCID, so another PCID is marked stale and fully flushed when it is switched to.
+pub fn invalidate_pcid_address(pcid: u16, virtual_address: usize) {
+    if pcid_enabled() && invpcid_available() {
+        unsafe { invpcid(InvpcidType::IndividualAddress, pcid, virtual_address) };
+    }
+    else if !pcid_enabled() || pcid == current_pcid() {
+        invalidate(virtual_address);
+    }
+    else {
+        mark_stale(pcid);
+    }
+}
+
+#[derive(Clone, Copy)]
+#[repr(u64)]
+pub enum InvpcidType {
+    IndividualAddress = 0,
+    SingleContext = 1,
+    AllContextsIncludingGlobal = 2,
+    AllContexts = 3
+}
+
+#[repr(C, align(16))]
+struct InvpcidDescriptor {
+    pcid: u64,
+    address: u64
+}
+
+pub unsafe fn invpcid(invalidation_type: InvpcidType, pcid: u16, virtual_address: usize) {
+    let descriptor = InvpcidDescriptor {
+        pcid: pcid as u64,
+        address: virtual_address as u64
+    };
+    asm!(
+        "invpcid {}, [{}]",
+        in(reg) invalidation_type as u64,
+        in(reg) &descriptor as *const InvpcidDescriptor,
+        options(nostack)
+    );
 }
\ No newline at end of file
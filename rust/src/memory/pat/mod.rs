@@ -0,0 +1,44 @@
+// Page attribute table.
+//
+// The memory type of a page is the PAT entry selected by its PAT (bit 7 of a P1 entry), NO_CACHE
+// and WRITE_THROUGH bits. Entry 1 (WRITE_THROUGH alone) is reprogrammed from write-through, which
+// nothing uses, to write-combining for framebuffers. The other entries keep their power-on value,
+// so NO_CACHE | WRITE_THROUGH is still uncached.
+
+use crate::memory::paging::EntryFlags;
+use crate::utils::cpuid;
+use crate::utils::msr::{read_msr, write_msr, IA32_PAT};
+use crate::utils::reg_read::read_cr3;
+use crate::utils::reg_write::write_cr3;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const MEMORY_TYPE_WRITE_COMBINING: u64 = 0x01;
+const WRITE_COMBINING_ENTRY: u64 = 1;
+
+static PAT_ENABLED: AtomicBool = AtomicBool::new(false);
+
+// Returns whether write-combining is available. Must be called before anything is mapped with
+// write_combining().
+pub unsafe fn init() -> bool {
+    if !cpuid::has_pat() {
+        return false;
+    }
+
+    let shift = WRITE_COMBINING_ENTRY * 8;
+    let pat = read_msr(IA32_PAT);
+    // The manual asks for caches and TLBs to be flushed when the PAT changes
+    asm!("wbinvd", options(nostack));
+    write_msr(IA32_PAT, (pat & !(0xff << shift)) | (MEMORY_TYPE_WRITE_COMBINING << shift));
+    write_cr3(read_cr3());
+    PAT_ENABLED.store(true, Ordering::Relaxed);
+    true
+}
+
+// Flags mapping a page write-combining, or uncached without a PAT
+pub fn write_combining() -> EntryFlags {
+    if PAT_ENABLED.load(Ordering::Relaxed) {
+        EntryFlags::WRITE_THROUGH
+    } else {
+        EntryFlags::NO_CACHE | EntryFlags::WRITE_THROUGH
+    }
+}
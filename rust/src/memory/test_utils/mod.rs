@@ -0,0 +1,138 @@
+// Simulated hardware for the host-side unit tests of the memory allocators
+
+use crate::memory::frame_allocator::{FrameAllocator, FrameInfo, MemoryRegion, FRAME_SIZE};
+use crate::memory::paging::{EntryFlags, PageInfo, PageMapper};
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::cell::RefCell;
+use std::ops::Range;
+use std::rc::Rc;
+use std::vec::Vec;
+
+#[derive(Debug, Clone, Copy)]
+pub struct SimulatedRegion {
+    pub start: usize,
+    pub end: usize,
+    pub usable: bool
+}
+
+impl SimulatedRegion {
+    pub fn usable(start: usize, end: usize) -> SimulatedRegion {
+        SimulatedRegion { start, end, usable: true }
+    }
+
+    pub fn reserved(start: usize, end: usize) -> SimulatedRegion {
+        SimulatedRegion { start, end, usable: false }
+    }
+}
+
+impl MemoryRegion for SimulatedRegion {
+    fn start_address(&self) -> usize {
+        self.start
+    }
+
+    fn end_address(&self) -> usize {
+        self.end
+    }
+
+    fn is_usable(&self) -> bool {
+        self.usable
+    }
+}
+
+// Frame aligned host memory standing in for a range of physical or virtual memory
+pub struct SimulatedMemory {
+    start: *mut u8,
+    layout: Layout
+}
+
+impl SimulatedMemory {
+    pub fn new(frames: usize) -> SimulatedMemory {
+        let layout = Layout::from_size_align(frames * FRAME_SIZE, FRAME_SIZE).unwrap();
+        let start = unsafe { alloc_zeroed(layout) };
+        assert!(!start.is_null());
+        SimulatedMemory { start, layout }
+    }
+
+    pub fn start(&self) -> usize {
+        self.start as usize
+    }
+
+    pub fn end(&self) -> usize {
+        self.start as usize + self.layout.size()
+    }
+
+    pub fn start_frame(&self) -> usize {
+        self.start() / FRAME_SIZE
+    }
+
+    // Offset making physical_address land on the first byte of this memory
+    pub fn physical_memory_offset(&self, physical_address: usize) -> usize {
+        self.start() - physical_address
+    }
+}
+
+impl Drop for SimulatedMemory {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.start, self.layout) };
+    }
+}
+
+// Hands out increasing frame numbers and never runs out
+pub struct MockFrameAllocator {
+    pub next_frame: usize,
+    pub deallocated: Vec<FrameInfo>
+}
+
+impl MockFrameAllocator {
+    pub fn new(first_frame: usize) -> MockFrameAllocator {
+        MockFrameAllocator {
+            next_frame: first_frame,
+            deallocated: Vec::new()
+        }
+    }
+}
+
+impl FrameAllocator for MockFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<FrameInfo> {
+        let frame = FrameInfo::from_number(self.next_frame);
+        self.next_frame += 1;
+        Some(frame)
+    }
+
+    fn deallocate_frame(&mut self, frame_info: FrameInfo) {
+        self.deallocated.push(frame_info);
+    }
+
+    fn metadata_frames(&self) -> Range<usize> {
+        0..0
+    }
+}
+
+// Records (page number, frame number) pairs instead of writing page tables
+#[derive(Clone, Default)]
+pub struct MockPageMapper {
+    pub mappings: Rc<RefCell<Vec<(usize, usize)>>>
+}
+
+impl MockPageMapper {
+    pub fn mapped_pages(&self) -> Vec<usize> {
+        self.mappings.borrow().iter().map(|&(page, _)| page).collect()
+    }
+}
+
+impl PageMapper for MockPageMapper {
+    unsafe fn map<T: FrameAllocator>(
+        &mut self,
+        frame: FrameInfo,
+        page: PageInfo,
+        _flags: EntryFlags,
+        _allocator: &mut T
+    ) {
+        let mut mappings = self.mappings.borrow_mut();
+        assert!(
+            mappings.iter().all(|&(mapped_page, _)| mapped_page != page.number),
+            "Page 0x{:x} mapped twice.", page.number
+        );
+        mappings.push((page.number, frame.number));
+    }
+}
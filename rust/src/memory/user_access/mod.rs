@@ -0,0 +1,140 @@
+use crate::memory::address_space::AddressSpace;
+use crate::memory::paging::PageInfo;
+use crate::utils::cpuid;
+use crate::utils::reg_read::{read_cr3, read_cr4};
+use crate::utils::reg_write::write_cr4;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+pub const CR4_UMIP: usize = 1 << 11;
+pub const CR4_SMEP: usize = 1 << 20;
+pub const CR4_SMAP: usize = 1 << 21;
+
+static SMEP_ENABLED: AtomicBool = AtomicBool::new(false);
+static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy)]
+pub struct ProtectionFeatures {
+    pub smep: bool,
+    pub smap: bool,
+    pub umip: bool
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserAccessError {
+    // The range wraps around or is not entirely canonical
+    InvalidRange,
+    // Part of the range lies in the kernel half
+    NotUserRange,
+    // The given address space is not the one loaded in CR3
+    NotCurrentAddressSpace
+}
+
+pub fn smep_enabled() -> bool {
+    SMEP_ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn smap_enabled() -> bool {
+    SMAP_ENABLED.load(Ordering::Relaxed)
+}
+
+// Enables every protection the processor supports :
+// SMEP (no kernel execution of user pages), SMAP (no kernel access to user pages outside of
+// stac / clac) and UMIP (no sgdt, sidt, sldt, smsw and str in user mode)
+pub unsafe fn init_protections() -> ProtectionFeatures {
+    let features = ProtectionFeatures {
+        smep: cpuid::has_smep(),
+        smap: cpuid::has_smap(),
+        umip: cpuid::has_umip()
+    };
+
+    let mut cr4 = read_cr4();
+    if features.smep {
+        cr4 |= CR4_SMEP;
+    }
+    if features.smap {
+        cr4 |= CR4_SMAP;
+    }
+    if features.umip {
+        cr4 |= CR4_UMIP;
+    }
+    write_cr4(cr4);
+
+    SMEP_ENABLED.store(features.smep, Ordering::Relaxed);
+    SMAP_ENABLED.store(features.smap, Ordering::Relaxed);
+    features
+}
+
+// Allows supervisor accesses to user pages for as long as it lives
+struct UserAccessGuard;
+
+impl UserAccessGuard {
+    fn new() -> UserAccessGuard {
+        // stac and clac are invalid opcodes on processors without SMAP
+        if smap_enabled() {
+            unsafe { asm!("stac", options(nostack)) };
+        }
+        UserAccessGuard
+    }
+}
+
+impl Drop for UserAccessGuard {
+    fn drop(&mut self) {
+        if smap_enabled() {
+            unsafe { asm!("clac", options(nostack)) };
+        }
+    }
+}
+
+pub fn validate_user_range(address: usize, length: usize) -> Result<(), UserAccessError> {
+    if length == 0 {
+        return Ok(());
+    }
+
+    let last = address.checked_add(length - 1).ok_or(UserAccessError::InvalidRange)?;
+    if !PageInfo::is_canonical(address) || !PageInfo::is_canonical(last) {
+        return Err(UserAccessError::InvalidRange);
+    }
+    // The user half is contiguous, so checking both ends is enough
+    if !PageInfo::is_user_address(address) || !PageInfo::is_user_address(last) {
+        return Err(UserAccessError::NotUserRange);
+    }
+
+    Ok(())
+}
+
+fn check_current(address_space: &AddressSpace) -> Result<(), UserAccessError> {
+    if unsafe { read_cr3() } & 0x000fffff_fffff000 != address_space.p4_frame.address {
+        return Err(UserAccessError::NotCurrentAddressSpace);
+    }
+    Ok(())
+}
+
+// Copies destination.len() bytes from the user address source of the current address space.
+// The pages still have to be mapped, a missing page faults like any other access.
+pub unsafe fn copy_from_user(
+    address_space: &AddressSpace,
+    destination: &mut [u8],
+    source: usize
+) -> Result<(), UserAccessError> {
+    check_current(address_space)?;
+    validate_user_range(source, destination.len())?;
+
+    let _guard = UserAccessGuard::new();
+    core::ptr::copy_nonoverlapping(source as *const u8, destination.as_mut_ptr(), destination.len());
+    Ok(())
+}
+
+// Copies source to the user address destination of the current address space.
+// The pages still have to be mapped, a missing page faults like any other access.
+pub unsafe fn copy_to_user(
+    address_space: &AddressSpace,
+    destination: usize,
+    source: &[u8]
+) -> Result<(), UserAccessError> {
+    check_current(address_space)?;
+    validate_user_range(destination, source.len())?;
+
+    let _guard = UserAccessGuard::new();
+    core::ptr::copy_nonoverlapping(source.as_ptr(), destination as *mut u8, source.len());
+    Ok(())
+}
@@ -0,0 +1,241 @@
+// Panic report, shown on a cleared screen instead of after whatever was printed before, and
+// written to every log sink.
+//
+// The report has a fixed layout fitting the 80x25 VGA text mode : the message and location, the
+// stack and control registers, then the return addresses of the stack frames. Addresses are
+// translated back to the ones in the ELF file (see layout::to_link_address), so they can be
+// looked up with addr2line even though the kernel was slid by KASLR.
+
+use crate::display::vga::Color;
+use crate::interrupts::InterruptStackFrame;
+use crate::log::{LogSink, SinkWriter};
+use crate::memory::layout::to_link_address;
+use crate::utils::backtrace::{read_frame_pointer, walk_stack};
+use crate::utils::reg_read::{read_cr2, read_cr3, read_cr4};
+use core::fmt;
+use core::fmt::Write;
+use core::panic::Location;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+pub const FOREGROUND: Color = Color::White;
+pub const BACKGROUND: Color = Color::Red;
+pub const BACKTRACE_FRAMES: usize = 15;
+const BACKTRACE_COLUMNS: usize = 3;
+const REGISTER_COLUMNS: usize = 3;
+
+static PANICKING: AtomicBool = AtomicBool::new(false);
+
+// Frame of the exception that is being turned into a panic, see record_exception_frame
+static EXCEPTION_FRAME: Mutex<Option<InterruptStackFrame>> = Mutex::new(None);
+
+// Called by exception handlers before panicking, so the report shows where the fault happened
+// rather than where the handler panicked
+pub fn record_exception_frame(frame: &InterruptStackFrame) {
+    *EXCEPTION_FRAME.lock() = Some(*frame);
+}
+
+// General purpose registers aren't captured : by the time the panic handler runs they hold
+// whatever the panic machinery left in them. The exception frame, when there is one, tells where
+// a fault happened.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Registers {
+    pub rsp: u64,
+    pub rbp: u64,
+    pub rflags: u64,
+    pub cr0: u64,
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64
+}
+
+impl Registers {
+    #[inline(always)]
+    pub fn capture() -> Registers {
+        let mut registers = Registers::default();
+        unsafe {
+            asm!(
+                "mov {rsp}, rsp",
+                "mov {rbp}, rbp",
+                "pushfq",
+                "pop {rflags}",
+                "mov {cr0}, cr0",
+                rsp = out(reg) registers.rsp,
+                rbp = out(reg) registers.rbp,
+                rflags = out(reg) registers.rflags,
+                cr0 = out(reg) registers.cr0,
+                options(preserves_flags)
+            );
+            registers.cr2 = read_cr2() as u64;
+            registers.cr3 = read_cr3() as u64;
+            registers.cr4 = read_cr4() as u64;
+        }
+        registers
+    }
+
+    fn named(&self) -> [(&'static str, u64); 7] {
+        [
+            ("RSP", self.rsp), ("RBP", self.rbp), ("RFL", self.rflags),
+            ("CR0", self.cr0), ("CR2", self.cr2), ("CR3", self.cr3),
+            ("CR4", self.cr4)
+        ]
+    }
+}
+
+// Writes name=value pairs, columns per line
+fn write_table(writer: &mut dyn Write, values: &[(&str, u64)], columns: usize) -> fmt::Result {
+    for row in values.chunks(columns) {
+        for (column, (name, value)) in row.iter().enumerate() {
+            if column > 0 {
+                writer.write_str("  ")?;
+            }
+            write!(writer, "{:>3}={:016x}", name, value)?;
+        }
+        writer.write_str("\n")?;
+    }
+    Ok(())
+}
+
+// to_link_address is a parameter so the report can be tested outside of the kernel
+pub fn write_report(
+    writer: &mut dyn Write,
+    message: &dyn fmt::Display,
+    location: Option<&Location>,
+    registers: &Registers,
+    exception_frame: Option<&InterruptStackFrame>,
+    backtrace: &[usize],
+    to_link_address: fn(usize) -> Option<usize>
+) -> fmt::Result {
+    writer.write_str("*** KERNEL PANIC ***\n\n")?;
+    writeln!(writer, "{}", message)?;
+    match location {
+        Some(location) => writeln!(writer, "at {}:{}:{}", location.file(), location.line(), location.column())?,
+        None => writer.write_str("at an unknown location\n")?
+    }
+
+    if let Some(frame) = exception_frame {
+        writer.write_str("\nException frame (RIP as a link address) :\n")?;
+        write_table(writer, &[
+            ("RIP", to_link_address(frame.instruction_pointer as usize).unwrap_or(frame.instruction_pointer as usize) as u64),
+            ("RSP", frame.stack_pointer),
+            ("RFL", frame.cpu_flags),
+            ("CS", frame.code_segment),
+            ("SS", frame.stack_segment)
+        ], REGISTER_COLUMNS)?;
+    }
+
+    writer.write_str("\nRegisters :\n")?;
+    write_table(writer, &registers.named(), REGISTER_COLUMNS)?;
+
+    writer.write_str("\nBacktrace (link addresses, ? outside of the kernel) :\n")?;
+    for (row, addresses) in backtrace.chunks(BACKTRACE_COLUMNS).enumerate() {
+        for (column, &address) in addresses.iter().enumerate() {
+            let index = row * BACKTRACE_COLUMNS + column;
+            match to_link_address(address) {
+                Some(link_address) => write!(writer, "#{:<2} {:016x}   ", index, link_address)?,
+                None => write!(writer, "#{:<2} {:016x} ? ", index, address)?
+            }
+        }
+        writer.write_str("\n")?;
+    }
+    Ok(())
+}
+
+struct ScreenWriter;
+
+impl Write for ScreenWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        crate::display::write_str(s);
+        Ok(())
+    }
+}
+
+fn halt() -> ! {
+    loop {
+        unsafe { asm!("cli", "hlt", options(nomem, nostack)) };
+    }
+}
+
+pub fn panic(info: &core::panic::PanicInfo) -> ! {
+    let registers = Registers::capture();
+
+    // A panic while reporting one : the screen or a sink is broken, only use the debug console
+    if PANICKING.swap(true, Ordering::SeqCst) {
+        crate::debugcon::print(format_args!("\nPanicked while panicking : {}\n", info));
+        halt();
+    }
+
+    let mut backtrace = [0; BACKTRACE_FRAMES];
+    let frames = unsafe { walk_stack(read_frame_pointer(), 0, &mut backtrace) };
+    let backtrace = &backtrace[..frames];
+    let exception_frame = EXCEPTION_FRAME.try_lock().and_then(|frame| *frame);
+
+    let message: &dyn fmt::Display = match info.message() {
+        Some(message) => message,
+        None => &"(no message)"
+    };
+    let report = |writer: &mut dyn Write| {
+        let _ = write_report(
+            writer,
+            message,
+            info.location(),
+            &registers,
+            exception_frame.as_ref(),
+            backtrace,
+            to_link_address
+        );
+    };
+
+    unsafe {
+        crate::display::begin_panic_screen(FOREGROUND, BACKGROUND);
+        crate::log::force_unlock();
+    }
+    report(&mut ScreenWriter);
+    // The console sink would print the report twice on the screen
+    crate::log::for_each_sink(|sink: &'static dyn LogSink| {
+        if sink.name() != crate::log::CONSOLE_SINK.name() {
+            sink.write_str("\n");
+            report(&mut SinkWriter(sink));
+        }
+    });
+
+    halt()
+}
+
+#[cfg(all(test, not(target_os = "none")))]
+mod tests {
+    use super::*;
+    use std::string::String;
+
+    #[test]
+    fn report_fits_the_vga_screen() {
+        let registers = Registers { rsp: 0x1234, cr3: 0x1000, ..Registers::default() };
+        let frame = InterruptStackFrame {
+            instruction_pointer: 0x1000,
+            code_segment: 0x28,
+            cpu_flags: 0x202,
+            stack_pointer: 0xfff0,
+            stack_segment: 0x30
+        };
+        let mut backtrace = [0xffffffff80201000; BACKTRACE_FRAMES];
+        backtrace[BACKTRACE_FRAMES - 1] = 0x2000;
+        let to_link_address = |address: usize| if address > 0x2000 { Some(address) } else { None };
+        let mut report = String::new();
+        write_report(
+            &mut report,
+            &"Something went wrong",
+            Some(Location::caller()),
+            &registers,
+            Some(&frame),
+            &backtrace,
+            to_link_address
+        ).unwrap();
+
+        assert!(report.lines().count() <= crate::display::vga::BUFFER_HEIGHT, "{}", report);
+        assert!(report.lines().all(|line| line.chars().count() <= crate::display::vga::BUFFER_WIDTH), "{}", report);
+        assert!(report.contains("RSP=0000000000001234  RBP=0000000000000000"));
+        assert!(!report.contains("RAX"));
+        assert!(report.contains("#0  ffffffff80201000"));
+        assert!(report.contains("#14 0000000000002000 ?"));
+    }
+}
@@ -0,0 +1,84 @@
+use crate::utils::port::{inb, outb};
+use core::fmt;
+use spin::Mutex;
+
+pub const COM1: u16 = 0x3f8;
+
+// Register offsets from the base port of a 16550 UART
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+
+const LINE_STATUS_TRANSMIT_EMPTY: u8 = 1 << 5;
+
+pub struct SerialPort {
+    base: u16,
+    initialized: bool
+}
+
+impl SerialPort {
+    pub const fn new(base: u16) -> SerialPort {
+        SerialPort {
+            base,
+            initialized: false
+        }
+    }
+
+    // 38400 baud, 8 data bits, no parity, one stop bit
+    pub fn init(&mut self) {
+        unsafe {
+            outb(self.base + INTERRUPT_ENABLE, 0x00);
+            // Set the baud rate divisor (115200 / 3) through the divisor latch
+            outb(self.base + LINE_CONTROL, 0x80);
+            outb(self.base + DATA, 0x03);
+            outb(self.base + INTERRUPT_ENABLE, 0x00);
+            outb(self.base + LINE_CONTROL, 0x03);
+            // Enable and clear the FIFOs, 14 bytes threshold
+            outb(self.base + FIFO_CONTROL, 0xc7);
+            // DTR, RTS and OUT2
+            outb(self.base + MODEM_CONTROL, 0x0b);
+        }
+        self.initialized = true;
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        if !self.initialized {
+            self.init();
+        }
+
+        unsafe {
+            while inb(self.base + LINE_STATUS) & LINE_STATUS_TRANSMIT_EMPTY == 0 {}
+            outb(self.base + DATA, byte);
+        }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.write_byte(byte)
+        }
+        Ok(())
+    }
+}
+
+pub static SERIAL1: Mutex<SerialPort> = Mutex::new(SerialPort::new(COM1));
+
+pub fn print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    SERIAL1.lock().write_fmt(args).unwrap();
+}
+
+macro_rules! serial_print {
+    ($($arg:tt)*) => ({
+        $crate::serial::print(format_args!($($arg)*));
+    });
+}
+
+macro_rules! serial_println {
+    ($fmt:expr) => (serial_print!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => (serial_print!(concat!($fmt, "\n"), $($arg)*));
+}
@@ -0,0 +1,138 @@
+// Direct access to the stivale2 structure's tags, for the ones the stivale crate doesn't expose.
+//
+// The structure and its tags are in bootloader reclaimable memory, which stays identity mapped
+// (see EntryTable::p4_kernel_remap), so tags can be read at any point after boot.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+pub const STRUCT_TAG_FRAMEBUFFER_ID: u64 = 0x506461d2950408fa;
+pub const STRUCT_TAG_MODULES_ID: u64 = 0x4b6fe466aade04ce;
+pub const STRUCT_TAG_CMDLINE_ID: u64 = 0xe5e76a1b4597a781;
+pub const STRUCT_TAG_RSDP_ID: u64 = 0x9e1786930a375e78;
+
+pub const FRAMEBUFFER_MEMORY_MODEL_RGB: u8 = 1;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct Tag {
+    pub identifier: u64,
+    pub next: u64
+}
+
+#[repr(C, packed)]
+pub struct StivaleStruct {
+    pub bootloader_brand: [u8; 64],
+    pub bootloader_version: [u8; 64],
+    pub tags: u64
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct FramebufferTag {
+    pub tag: Tag,
+    pub framebuffer_addr: u64,
+    pub framebuffer_width: u16,
+    pub framebuffer_height: u16,
+    pub framebuffer_pitch: u16,
+    pub framebuffer_bpp: u16,
+    pub memory_model: u8,
+    pub red_mask_size: u8,
+    pub red_mask_shift: u8,
+    pub green_mask_size: u8,
+    pub green_mask_shift: u8,
+    pub blue_mask_size: u8,
+    pub blue_mask_shift: u8
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct CmdlineTag {
+    pub tag: Tag,
+    // Address of a nul terminated string
+    pub cmdline: u64
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct RsdpTag {
+    pub tag: Tag,
+    // Physical address of the ACPI RSDP
+    pub rsdp: u64
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct ModulesTag {
+    pub tag: Tag,
+    pub module_count: u64
+}
+
+// Files loaded by the bootloader (MODULE_PATH in limine.cfg). begin and end are physical
+// addresses.
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+pub struct Module {
+    pub begin: u64,
+    pub end: u64,
+    pub string: [u8; 128]
+}
+
+impl Module {
+    // The module's MODULE_STRING, up to the first nul byte
+    pub fn string(&self) -> &str {
+        let string = &self.string;
+        let length = string.iter().position(|&byte| byte == 0).unwrap_or(string.len());
+        core::str::from_utf8(&string[..length]).unwrap_or("")
+    }
+
+    pub fn size(&self) -> usize {
+        (self.end - self.begin) as usize
+    }
+}
+
+static STRUCT_ADDRESS: AtomicUsize = AtomicUsize::new(0);
+
+// Called with the pointer the bootloader handed to _start
+pub fn init(struct_address: usize) {
+    STRUCT_ADDRESS.store(struct_address, Ordering::Relaxed);
+}
+
+// Returns the address of the first tag with the given identifier
+pub fn find_tag(identifier: u64) -> Option<usize> {
+    let struct_address = STRUCT_ADDRESS.load(Ordering::Relaxed);
+    if struct_address == 0 {
+        return None;
+    }
+
+    let mut tag_address = unsafe { (*(struct_address as *const StivaleStruct)).tags } as usize;
+    while tag_address != 0 {
+        let tag = unsafe { core::ptr::read_unaligned(tag_address as *const Tag) };
+        if tag.identifier == identifier {
+            return Some(tag_address);
+        }
+        tag_address = tag.next as usize;
+    }
+    None
+}
+
+// Copies a tag out of bootloader memory
+pub unsafe fn read_tag<T: Copy>(identifier: u64) -> Option<T> {
+    find_tag(identifier).map(|address| core::ptr::read_unaligned(address as *const T))
+}
+
+pub fn framebuffer() -> Option<FramebufferTag> {
+    unsafe { read_tag(STRUCT_TAG_FRAMEBUFFER_ID) }
+}
+
+pub fn modules() -> impl Iterator<Item = Module> {
+    let (address, count) = match find_tag(STRUCT_TAG_MODULES_ID) {
+        Some(address) => {
+            let tag = unsafe { core::ptr::read_unaligned(address as *const ModulesTag) };
+            (address + core::mem::size_of::<ModulesTag>(), tag.module_count as usize)
+        }
+        None => (0, 0)
+    };
+    (0..count).map(move |index| unsafe {
+        core::ptr::read_unaligned((address + index * core::mem::size_of::<Module>()) as *const Module)
+    })
+}
@@ -0,0 +1,189 @@
+// In-kernel test framework.
+//
+// Building the kernel with cargo test for the kernel target (see `make kerneltest` and
+// runtests.sh) collects every #[test_case] item and runs them from kernel_main once the heap is
+// set up. Results are reported over the serial port and QEMU is exited through its isa-debug-exit
+// device (iobase 0xf4), so its exit code tells whether the tests passed.
+
+use crate::interrupts;
+use crate::utils::port::outl;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+pub const ISA_DEBUG_EXIT_PORT: u16 = 0xf4;
+
+// QEMU exits with (code << 1) | 1, so 33 on success and 35 on failure
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x10,
+    Failed = 0x11
+}
+
+pub fn exit_qemu(exit_code: QemuExitCode) -> ! {
+    unsafe { outl(ISA_DEBUG_EXIT_PORT, exit_code as u32) };
+
+    // Only reached when the isa-debug-exit device is missing
+    loop {}
+}
+
+pub trait Testable {
+    fn name(&self) -> &'static str;
+    fn run(&self);
+    fn should_panic(&self) -> bool {
+        false
+    }
+}
+
+impl<T: Fn()> Testable for T {
+    fn name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
+
+    fn run(&self) {
+        self()
+    }
+}
+
+// A test passing only if it panics, declared as a const so the harness's test list stays 'static.
+// The name is built like the ones of the other tests, so test= filters match it the same way :
+//
+// #[test_case]
+// const OUT_OF_BOUNDS: ShouldPanic = ShouldPanic(concat!(module_path!(), "::out_of_bounds"), || { ... });
+pub struct ShouldPanic(pub &'static str, pub fn());
+
+impl Testable for ShouldPanic {
+    fn name(&self) -> &'static str {
+        self.0
+    }
+
+    fn run(&self) {
+        (self.1)()
+    }
+
+    fn should_panic(&self) -> bool {
+        true
+    }
+}
+
+static mut TESTS: &[&dyn Testable] = &[];
+static CURRENT_TEST: AtomicUsize = AtomicUsize::new(0);
+static FAILED_TESTS: AtomicUsize = AtomicUsize::new(0);
+// Stack pointer and interrupt state of test_runner, which tests are resumed from after a panic
+static RUNNER_STACK: AtomicUsize = AtomicUsize::new(0);
+static RUNNER_INTERRUPTS: AtomicBool = AtomicBool::new(false);
+
+pub fn test_runner(tests: &'static [&'static dyn Testable]) {
+    unsafe { TESTS = tests };
+    let stack_pointer: usize;
+    unsafe { asm!("mov {}, rsp", out(reg) stack_pointer, options(nomem, nostack, preserves_flags)) };
+    RUNNER_STACK.store(stack_pointer, Ordering::SeqCst);
+    RUNNER_INTERRUPTS.store(interrupts::are_enabled(), Ordering::SeqCst);
+
+    serial_println!("Running {} kernel tests", tests.len());
+    run_tests_from(0);
+}
+
+// There is no unwinding, so a test that panicked is never returned from. The panic handler goes
+// back to test_runner's stack and calls this again to carry on with the next test.
+fn run_tests_from(first: usize) -> ! {
+    let tests = unsafe { TESTS };
+    // test= on the command line
+    let filter = crate::cmdline::options().test_filter;
+    let selected = |test: &&dyn Testable| filter.map_or(true, |filter| test.name().contains(filter));
+
+    for (index, test) in tests.iter().enumerate().skip(first) {
+        if !selected(test) {
+            continue;
+        }
+        CURRENT_TEST.store(index, Ordering::SeqCst);
+        serial_print!("{}... ", test.name());
+        test.run();
+        if test.should_panic() {
+            serial_println!("[failed]");
+            serial_println!("  Expected a panic.");
+            FAILED_TESTS.fetch_add(1, Ordering::SeqCst);
+        }
+        else {
+            serial_println!("[ok]");
+        }
+    }
+
+    let failed = FAILED_TESTS.load(Ordering::SeqCst);
+    let ran = tests.iter().filter(|test| selected(*test)).count();
+    if ran < tests.len() {
+        serial_println!("{} kernel tests filtered out", tests.len() - ran);
+    }
+    if failed == 0 {
+        serial_println!("All {} kernel tests passed", ran);
+        exit_qemu(QemuExitCode::Success);
+    }
+    else {
+        serial_println!("{} of {} kernel tests failed", failed, ran);
+        exit_qemu(QemuExitCode::Failed);
+    }
+}
+
+pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    let tests = unsafe { TESTS };
+    let current = CURRENT_TEST.load(Ordering::SeqCst);
+
+    match tests.get(current) {
+        Some(test) if test.should_panic() => {
+            serial_println!("[ok]");
+        }
+        Some(_) => {
+            serial_println!("[failed]");
+            serial_println!("  {}", info);
+            FAILED_TESTS.fetch_add(1, Ordering::SeqCst);
+        }
+        None => {
+            // Panicked outside of a test, during boot or in the runner itself
+            serial_println!("Kernel panicked before tests could run : {}", info);
+            exit_qemu(QemuExitCode::Failed);
+        }
+    }
+
+    // The panicked test may have been holding any of these, and may have disabled interrupts
+    unsafe {
+        crate::log::force_unlock();
+        crate::display::force_unlock();
+        crate::memory::force_unlock_heap();
+    }
+    if RUNNER_INTERRUPTS.load(Ordering::SeqCst) {
+        interrupts::enable();
+    }
+    else {
+        interrupts::disable();
+    }
+
+    // Drop the panicked test's frames, which would otherwise pile up with every panicking test
+    unsafe {
+        asm!(
+            "mov rsp, {stack}",
+            "xor ebp, ebp",
+            "call {resume}",
+            stack = in(reg) RUNNER_STACK.load(Ordering::SeqCst) & !0xf,
+            resume = in(reg) resume_tests as extern "C" fn() -> !,
+            options(noreturn)
+        )
+    }
+}
+
+extern "C" fn resume_tests() -> ! {
+    run_tests_from(CURRENT_TEST.load(Ordering::SeqCst) + 1)
+}
+
+mod tests {
+    use super::ShouldPanic;
+
+    #[test_case]
+    fn trivial_assertion() {
+        assert_eq!(1 + 1, 2);
+    }
+
+    #[test_case]
+    const FAILED_ASSERTION_PANICS: ShouldPanic = ShouldPanic(concat!(module_path!(), "::failed_assertion_panics"), || {
+        assert_eq!(1 + 1, 3);
+    });
+}
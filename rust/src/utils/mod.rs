@@ -7,11 +7,30 @@ pub fn mem_regions_overlap(a_start: usize, a_end: usize, b_start: usize, b_end:
 }
 
 pub mod reg_read {
+    pub unsafe fn read_cr2() -> usize {
+        let result: u64;
+        asm!("mov {}, cr2", out(reg) result);
+        result as usize
+    }
+
     pub unsafe fn read_cr3() -> usize {
         let result: u64;
         asm!("mov {}, cr3", out(reg) result);
         result as usize
     }
+
+    pub unsafe fn read_cr4() -> usize {
+        let result: u64;
+        asm!("mov {}, cr4", out(reg) result);
+        result as usize
+    }
+
+    pub unsafe fn read_tsc() -> u64 {
+        let low: u32;
+        let high: u32;
+        asm!("rdtsc", out("eax") low, out("edx") high, options(nomem, nostack));
+        ((high as u64) << 32) | low as u64
+    }
 }
 
 pub mod reg_write {
@@ -19,6 +38,261 @@ pub mod reg_write {
         let to_write = to_write as u64;
         asm!("mov cr3, {}", in(reg) to_write);
     }
+
+    pub unsafe fn write_cr4(to_write: usize) {
+        let to_write = to_write as u64;
+        asm!("mov cr4, {}", in(reg) to_write);
+    }
+}
+
+pub mod msr {
+    pub const IA32_APIC_BASE: u32 = 0x1b;
+    pub const IA32_PAT: u32 = 0x277;
+
+    pub unsafe fn read_msr(msr: u32) -> u64 {
+        let low: u32;
+        let high: u32;
+        asm!("rdmsr", in("ecx") msr, out("eax") low, out("edx") high, options(nomem, nostack));
+        ((high as u64) << 32) | low as u64
+    }
+
+    pub unsafe fn write_msr(msr: u32, value: u64) {
+        asm!("wrmsr", in("ecx") msr, in("eax") value as u32, in("edx") (value >> 32) as u32, options(nostack));
+    }
+}
+
+pub mod port {
+    pub unsafe fn outb(port: u16, value: u8) {
+        asm!("out dx, al", in("dx") port, in("al") value, options(nomem, nostack, preserves_flags));
+    }
+
+    pub unsafe fn inb(port: u16) -> u8 {
+        let value: u8;
+        asm!("in al, dx", out("al") value, in("dx") port, options(nomem, nostack, preserves_flags));
+        value
+    }
+
+    pub unsafe fn outw(port: u16, value: u16) {
+        asm!("out dx, ax", in("dx") port, in("ax") value, options(nomem, nostack, preserves_flags));
+    }
+
+    pub unsafe fn inw(port: u16) -> u16 {
+        let value: u16;
+        asm!("in ax, dx", out("ax") value, in("dx") port, options(nomem, nostack, preserves_flags));
+        value
+    }
+
+    pub unsafe fn outl(port: u16, value: u32) {
+        asm!("out dx, eax", in("dx") port, in("eax") value, options(nomem, nostack, preserves_flags));
+    }
+
+    pub unsafe fn inl(port: u16) -> u32 {
+        let value: u32;
+        asm!("in eax, dx", out("eax") value, in("dx") port, options(nomem, nostack, preserves_flags));
+        value
+    }
+}
+
+pub mod cpuid {
+    use core::arch::x86_64::__cpuid_count;
+
+    #[derive(Debug, Clone, Copy)]
+    pub struct CpuidResult {
+        pub eax: u32,
+        pub ebx: u32,
+        pub ecx: u32,
+        pub edx: u32
+    }
+
+    pub fn cpuid(leaf: u32, sub_leaf: u32) -> CpuidResult {
+        let result = unsafe { __cpuid_count(leaf, sub_leaf) };
+        CpuidResult {
+            eax: result.eax,
+            ebx: result.ebx,
+            ecx: result.ecx,
+            edx: result.edx
+        }
+    }
+
+    pub fn max_leaf() -> u32 {
+        cpuid(0, 0).eax
+    }
+
+    pub fn max_extended_leaf() -> u32 {
+        cpuid(0x8000_0000, 0).eax
+    }
+
+    // CPUID.01H:ECX.PCID[bit 17]
+    pub fn has_pcid() -> bool {
+        cpuid(1, 0).ecx & (1 << 17) != 0
+    }
+
+    // CPUID.(EAX=07H,ECX=0H):EBX.INVPCID[bit 10]
+    pub fn has_invpcid() -> bool {
+        max_leaf() >= 7 && cpuid(7, 0).ebx & (1 << 10) != 0
+    }
+
+    // CPUID.01H:ECX.RDRAND[bit 30]
+    pub fn has_rdrand() -> bool {
+        cpuid(1, 0).ecx & (1 << 30) != 0
+    }
+
+    // CPUID.(EAX=07H,ECX=0H):EBX.RDSEED[bit 18]
+    pub fn has_rdseed() -> bool {
+        max_leaf() >= 7 && cpuid(7, 0).ebx & (1 << 18) != 0
+    }
+
+    // CPUID.01H:EDX.PAT[bit 16]
+    pub fn has_pat() -> bool {
+        cpuid(1, 0).edx & (1 << 16) != 0
+    }
+
+    // CPUID.01H:EDX.APIC[bit 9]
+    pub fn has_apic() -> bool {
+        cpuid(1, 0).edx & (1 << 9) != 0
+    }
+
+    // CPUID.80000007H:EDX.InvariantTSC[bit 8], the TSC runs at a constant rate in every state
+    pub fn has_invariant_tsc() -> bool {
+        max_extended_leaf() >= 0x8000_0007 && cpuid(0x8000_0007, 0).edx & (1 << 8) != 0
+    }
+
+    // CPUID.(EAX=07H,ECX=0H):EBX.SMEP[bit 7]
+    pub fn has_smep() -> bool {
+        max_leaf() >= 7 && cpuid(7, 0).ebx & (1 << 7) != 0
+    }
+
+    // CPUID.(EAX=07H,ECX=0H):EBX.SMAP[bit 20]
+    pub fn has_smap() -> bool {
+        max_leaf() >= 7 && cpuid(7, 0).ebx & (1 << 20) != 0
+    }
+
+    // CPUID.(EAX=07H,ECX=0H):ECX.UMIP[bit 2]
+    pub fn has_umip() -> bool {
+        max_leaf() >= 7 && cpuid(7, 0).ecx & (1 << 2) != 0
+    }
+}
+
+pub mod random {
+    use crate::utils::cpuid;
+    use crate::utils::reg_read::read_tsc;
+
+    // Both instructions may transiently fail when the entropy source is drained
+    const HARDWARE_RETRIES: usize = 16;
+
+    pub fn rdseed() -> Option<u64> {
+        if !cpuid::has_rdseed() {
+            return None;
+        }
+
+        for _ in 0..HARDWARE_RETRIES {
+            let value: u64;
+            let success: u8;
+            unsafe { asm!("rdseed {}", "setc {}", out(reg) value, out(reg_byte) success, options(nomem, nostack)) };
+            if success != 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    pub fn rdrand() -> Option<u64> {
+        if !cpuid::has_rdrand() {
+            return None;
+        }
+
+        for _ in 0..HARDWARE_RETRIES {
+            let value: u64;
+            let success: u8;
+            unsafe { asm!("rdrand {}", "setc {}", out(reg) value, out(reg_byte) success, options(nomem, nostack)) };
+            if success != 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    fn splitmix64(mut x: u64) -> u64 {
+        x = x.wrapping_add(0x9e3779b97f4a7c15);
+        x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+        x ^ (x >> 31)
+    }
+
+    // Collects the timing noise of a data-dependent busy loop. Weak, but better than a fixed
+    // layout on processors without RDRAND.
+    pub fn tsc_jitter() -> u64 {
+        let mut result = 0u64;
+        for _ in 0..64 {
+            let start = unsafe { read_tsc() };
+            let mut work = start;
+            for _ in 0..(start & 0xff) {
+                work = unsafe { core::ptr::read_volatile(&work) }.rotate_left(7) ^ 0x9e3779b97f4a7c15;
+            }
+            let delta = unsafe { read_tsc() }.wrapping_sub(start);
+            result = result.rotate_left(5) ^ delta ^ work;
+        }
+        splitmix64(result)
+    }
+
+    pub fn random_u64() -> u64 {
+        rdseed().or_else(rdrand).unwrap_or_else(tsc_jitter)
+    }
+
+    // Random number in 0..bound
+    pub fn random_below(bound: u64) -> u64 {
+        if bound == 0 {
+            return 0;
+        }
+        random_u64() % bound
+    }
+}
+
+pub mod backtrace {
+    use crate::memory::paging::PageInfo;
+
+    pub const MAX_FRAMES: usize = 64;
+
+    pub fn read_frame_pointer() -> usize {
+        let frame_pointer: u64;
+        unsafe { asm!("mov {}, rbp", out(reg) frame_pointer, options(nomem, nostack)) };
+        frame_pointer as usize
+    }
+
+    // Follows the saved frame pointer chain (the kernel is built with frame pointers) and fills
+    // return_addresses, skipping the first skip frames. Returns the amount of addresses written.
+    // The walk stops on anything that doesn't look like a kernel stack frame.
+    pub unsafe fn walk_stack(mut frame_pointer: usize, skip: usize, return_addresses: &mut [usize]) -> usize {
+        let mut written = 0;
+        let mut depth = 0;
+        while written < return_addresses.len() && depth < MAX_FRAMES {
+            if frame_pointer == 0
+                || frame_pointer % 8 != 0
+                || !PageInfo::is_canonical(frame_pointer)
+                || PageInfo::is_user_address(frame_pointer) {
+                break;
+            }
+
+            let saved_frame_pointer = *(frame_pointer as *const usize);
+            let return_address = *((frame_pointer + 8) as *const usize);
+            if return_address == 0 {
+                break;
+            }
+
+            if depth >= skip {
+                return_addresses[written] = return_address;
+                written += 1;
+            }
+            depth += 1;
+
+            // Stacks grow down, so callers always have higher frame pointers
+            if saved_frame_pointer <= frame_pointer {
+                break;
+            }
+            frame_pointer = saved_frame_pointer;
+        }
+        written
+    }
 }
 
 /*
@@ -4,11 +4,31 @@
 
 static uint8_t stack[65536];
 
+// Ask for five-level paging, the bootloader only enables it when the processor supports LA57
+static struct stivale2_tag la57_tag = {
+        .identifier = STIVALE2_HEADER_TAG_5LV_PAGING_ID,
+        .next = 0
+};
+
+// Ask for a linear framebuffer, zeroes let the bootloader pick the best mode. Without one the
+// kernel falls back to VGA text mode.
+static struct stivale2_header_tag_framebuffer framebuffer_tag = {
+        .tag = {
+                .identifier = STIVALE2_HEADER_TAG_FRAMEBUFFER_ID,
+                .next = (uintptr_t)&la57_tag
+        },
+        .framebuffer_width = 0,
+        .framebuffer_height = 0,
+        .framebuffer_bpp = 0
+};
+
 __attribute__((section(".stivale2hdr"), used))
 struct stivale2_header stivale_hdr = {
         .entry_point = 0,
         .stack = (uintptr_t)stack + sizeof(stack),
-        .flags = 0
+        // Bit 0 : let the bootloader randomise the kernel's load address
+        .flags = 1,
+        .tags = (uintptr_t)&framebuffer_tag
 };
 
 extern void kernel_main();